@@ -1,11 +1,12 @@
 #!/bin/bash
 
-# CouchDB should already be running (started in /etc/rc.local before this gets called).
+# CouchDB should already be running (started in /etc/rc.local before this gets called),
+# unless OPENSTAINER_STORAGE=file is set to keep data in a file instead (see the README).
 
 cd /home/pi/code/slide-stainer
 
 # Start Rust web server
-sudo ./target/debug/rmrm &
+sudo -E ./target/debug/rmrm &
 
 # Turn off power saving and the screen saver
 xset -dpms
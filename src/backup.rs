@@ -0,0 +1,377 @@
+pub use crate::structs_and_consts::*;
+pub use crate::couchdb::*;
+pub use crate::storage::*;
+
+use chrono::{DateTime, Utc};
+use juniper::FieldResult;
+use serde::*;
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+
+// A backup is every document in the database, one JSON document per line, after a first line holding the manifest.
+// The manifest says what the file is, how many documents it holds and the SHA-256 of the document lines, so a file that
+// was cut short or damaged on the way to or from a USB stick is refused rather than half restored.
+// Backups can be restored into any storage backend, whichever backend they were made from.
+
+pub const BACKUP_FORMAT: &str = "openstainer-backup";
+pub const BACKUP_FORMAT_VERSION: i32 = 1; // bump when the file layout changes, and keep reading the older layouts
+pub const BACKUP_FILE_EXTENSION: &str = "jsonl";
+pub const BACKUP_SIZE_LIMIT: u64 = 256 * 1024 * 1024; // Largest backup accepted by the restore endpoint, in bytes.
+pub const BACKUP_ROOT_ENV_VAR: &str = "OPENSTAINER_BACKUP_ROOT"; // the only directory backups are read from and written to
+pub const DEFAULT_BACKUP_ROOT: &str = "/media"; // where Raspbian mounts USB sticks
+
+// The server runs as root and the GraphQL API is open, so backup files are only ever read or written under the
+// backup root. Reads OPENSTAINER_BACKUP_ROOT, defaulting to /media.
+pub fn backup_root() -> PathBuf {
+    PathBuf::from(std::env::var(BACKUP_ROOT_ENV_VAR).unwrap_or_else(|_| DEFAULT_BACKUP_ROOT.to_string()))
+}
+
+// Resolves a path given by a client to one under root, following symlinks and "..", or refuses it.
+// Relative paths are taken to be relative to root. The path needn't exist yet, but its parent directory must.
+pub fn confine_to_root(root: &Path, path: &Path) -> FieldResult<PathBuf> {
+    let root = match root.canonicalize() {
+	Ok(root) => root,
+	Err(e) => return juniper_err(format!("The backup directory {} isn't available: {}", root.display(), e)),
+    };
+    let joined = root.join(path);
+    let resolved = match joined.canonicalize() {
+	Ok(resolved) => resolved,
+	Err(_) => {
+	    // a new file: resolve its directory instead
+	    let file_name = match joined.file_name() {
+		Some(name) if name != ".." => name.to_os_string(),
+		_ => return juniper_err(format!("{} isn't a file name.", path.display())),
+	    };
+	    match joined.parent().map(|parent| parent.canonicalize()) {
+		Some(Ok(parent)) => parent.join(file_name),
+		_ => return juniper_err(format!("{} doesn't exist.", path.display())),
+	    }
+	}
+    };
+    if !resolved.starts_with(&root) {
+	return juniper_err(format!("Backups can only be kept under {}.", root.display()));
+    }
+    Ok(resolved)
+}
+
+#[derive(juniper::GraphQLObject, Debug, Serialize, Deserialize, Clone)]
+#[graphql(description="The first line of a backup file, describing the rest of it.")]
+pub struct BackupManifest {
+    pub format: String,
+    pub format_version: i32,
+    pub created_at: DateTime<Utc>,
+
+    #[graphql(description="The version of the software that made the backup.")]
+    pub software_version: String,
+
+    #[graphql(description="Number of documents in the backup, one per line after the manifest.")]
+    pub document_count: i32,
+
+    pub procedure_count: i32,
+    pub run_count: i32,
+
+    #[graphql(description="Hex SHA-256 of the document lines, each followed by a newline.")]
+    pub sha256: String,
+}
+
+#[derive(juniper::GraphQLObject, Debug, Clone)]
+#[graphql(description="A backup file and what's in it.")]
+pub struct BackupFile {
+    pub path: String,
+    pub manifest: BackupManifest,
+}
+
+#[derive(juniper::GraphQLObject, Debug, Clone)]
+#[graphql(description="What restoring a backup did.")]
+pub struct RestoreSummary {
+    pub manifest: BackupManifest,
+
+    #[graphql(description="Documents that weren't in the database.")]
+    pub created: i32,
+
+    #[graphql(description="Documents that replaced one with the same _id.")]
+    pub overwritten: i32,
+
+    #[graphql(description="Documents that weren't restored, and why.")]
+    pub skipped: Vec<SkippedDocument>,
+}
+
+fn checksum(lines: &[String]) -> String {
+    let mut hasher = Sha256::new();
+    for line in lines {
+	hasher.input(line.as_bytes());
+	hasher.input(b"\n");
+    }
+    format!("{:x}", hasher.result())
+}
+
+fn count_type(docs: &[serde_json::Value], doc_type: &str) -> i32 {
+    docs.iter().filter(|doc| doc["type"] == doc_type).count() as i32
+}
+
+// Makes a backup of every document in the store.
+pub fn make_backup(store: &dyn BackupStore) -> FieldResult<String> {
+    let docs = store.all_docs()?;
+    let lines : Vec<String> = docs.iter().map(|doc| doc.to_string()).collect();
+    let manifest = BackupManifest {
+	format: BACKUP_FORMAT.to_string(),
+	format_version: BACKUP_FORMAT_VERSION,
+	created_at: Utc::now(),
+	software_version: env!("CARGO_PKG_VERSION").to_string(),
+	document_count: lines.len() as i32,
+	procedure_count: count_type(&docs, "procedure"),
+	run_count: count_type(&docs, "run"),
+	sha256: checksum(&lines),
+    };
+    let mut backup = serde_json::to_string(&manifest).unwrap();
+    backup.push('\n');
+    for line in lines {
+	backup.push_str(&line);
+	backup.push('\n');
+    }
+    Ok(backup)
+}
+
+fn parse_manifest(line: &str) -> FieldResult<BackupManifest> {
+    let value : serde_json::Value = match serde_json::from_str(line) {
+	Ok(value) => value,
+	Err(e) => return juniper_err(format!("The file isn't a backup; its first line isn't valid JSON: {}", e)),
+    };
+    if value["format"] != BACKUP_FORMAT {
+	return juniper_err(format!("The file isn't a backup; its format should be \"{}\".", BACKUP_FORMAT));
+    }
+    match value["format_version"].as_i64() {
+	Some(v) if v >= 1 && v <= BACKUP_FORMAT_VERSION as i64 => {}
+	Some(v) => return juniper_err(format!("The backup is format version {}, but only versions up to {} can be restored. Update this instrument's software to restore it.",
+					      v, BACKUP_FORMAT_VERSION)),
+	None => return juniper_err("The backup doesn't say which format version it is.".to_string()),
+    }
+    match serde_json::from_value::<BackupManifest>(value) {
+	Ok(manifest) => Ok(manifest),
+	Err(e) => juniper_err(format!("The backup's manifest is malformed: {}", e)),
+    }
+}
+
+// Checks a backup from end to end without changing anything: the manifest, the checksum, and that every document
+// can be read. Returns the manifest and the documents.
+pub fn parse_backup(backup: &str) -> FieldResult<(BackupManifest, Vec<serde_json::Value>)> {
+    let mut lines = backup.lines();
+    let manifest = parse_manifest(lines.next().unwrap_or(""))?;
+    let doc_lines : Vec<String> = lines.filter(|line| !line.trim().is_empty()).map(|line| line.to_string()).collect();
+    if doc_lines.len() as i32 != manifest.document_count {
+	return juniper_err(format!("The backup should hold {} documents but holds {}. It may have been cut short.",
+				   manifest.document_count, doc_lines.len()));
+    }
+    if checksum(&doc_lines) != manifest.sha256 {
+	return juniper_err("The backup's checksum doesn't match its contents. It may have been damaged.".to_string());
+    }
+
+    let mut docs : Vec<serde_json::Value> = Vec::new();
+    let mut ids : HashSet<String> = HashSet::new();
+    let mut problems : Vec<String> = Vec::new();
+    let mut readable = crate::memory_store::MemoryStoreData::default(); // only used to check the documents can be read
+    for (i, line) in doc_lines.iter().enumerate() {
+	let doc : serde_json::Value = match serde_json::from_str(line) {
+	    Ok(doc) => doc,
+	    Err(e) => {
+		problems.push(format!("line {} isn't valid JSON ({})", i + 2, e));
+		continue;
+	    }
+	};
+	let id = match doc["_id"].as_str() {
+	    Some(id) => id.to_string(),
+	    None => {
+		problems.push(format!("line {} has no _id", i + 2));
+		continue;
+	    }
+	};
+	if id.starts_with("_design/") {
+	    problems.push(format!("{} is a design doc; they're installed by the software, not restored", id));
+	} else if !ids.insert(id.clone()) {
+	    problems.push(format!("{} is in the backup more than once", id));
+	} else if let Err(e) = readable.load_doc(doc.clone()) {
+	    problems.push(format!("{} can't be read ({})", id, e));
+	}
+	docs.push(doc);
+    }
+    if !problems.is_empty() {
+	let shown : Vec<String> = problems.iter().take(5).cloned().collect();
+	return juniper_err(format!("The backup can't be restored: {}{}.", shown.join("; "),
+				   if problems.len() > shown.len() { format!(" and {} more", problems.len() - shown.len()) } else { String::new() }));
+    }
+    Ok((manifest, docs))
+}
+
+// Restores a backup over what's in the store. Documents in the backup replace those with the same _id; documents
+// that aren't in the backup are kept. Nothing is changed if the backup doesn't check out.
+pub fn restore_backup(store: &dyn BackupStore, backup: &str) -> FieldResult<RestoreSummary> {
+    let (manifest, docs) = parse_backup(backup)?;
+    let loaded = store.load_docs(docs)?;
+    Ok(RestoreSummary {
+	manifest,
+	created: loaded.created,
+	overwritten: loaded.overwritten,
+	skipped: loaded.skipped,
+    })
+}
+
+// Writes a backup to the given file, or to a new file named for the time if the path is a directory,
+// e.g. a USB stick's mount point. The path must be under root, and an existing file is never overwritten.
+pub fn write_backup_file(store: &dyn BackupStore, root: &Path, path: &Path) -> FieldResult<BackupFile> {
+    let path = confine_to_root(root, path)?;
+    let path = if path.is_dir() {
+	path.join(format!("openstainer-backup-{}.{}", Utc::now().format("%Y%m%d-%H%M%S"), BACKUP_FILE_EXTENSION))
+    } else {
+	path
+    };
+    let backup = make_backup(store)?;
+    let written = OpenOptions::new().write(true).create_new(true).open(&path).and_then(|mut file| {
+	file.write_all(backup.as_bytes())?;
+	file.sync_all() // so it's all there before the USB stick is pulled out
+    });
+    if let Err(e) = written {
+	return juniper_err(format!("Couldn't write {}: {}", path.display(), e));
+    }
+    Ok(BackupFile {
+	path: path.display().to_string(),
+	manifest: parse_manifest(backup.lines().next().unwrap_or(""))?,
+    })
+}
+
+pub fn restore_backup_file(store: &dyn BackupStore, root: &Path, path: &Path) -> FieldResult<RestoreSummary> {
+    let path = &confine_to_root(root, path)?;
+    match fs::metadata(path) {
+	Ok(metadata) if metadata.len() > BACKUP_SIZE_LIMIT => return juniper_err(format!("{} is too large to be a backup.", path.display())),
+	Ok(_) => {}
+	Err(e) => return juniper_err(format!("Couldn't read {}: {}", path.display(), e)),
+    }
+    match fs::read_to_string(path) {
+	Ok(backup) => restore_backup(store, &backup),
+	Err(e) => juniper_err(format!("Couldn't read {}: {}", path.display(), e)),
+    }
+}
+
+// The backup files in a directory, newest first. Only each file's manifest is read, so they aren't checked in full.
+pub fn backup_files(root: &Path, directory: &Path) -> FieldResult<Vec<BackupFile>> {
+    let directory = &confine_to_root(root, directory)?;
+    let entries = match fs::read_dir(directory) {
+	Ok(entries) => entries,
+	Err(e) => return juniper_err(format!("Couldn't list {}: {}", directory.display(), e)),
+    };
+    let mut files : Vec<BackupFile> = entries
+	.filter_map(|entry| entry.ok().map(|entry| entry.path()))
+	.filter(|path| path.extension().map_or(false, |ext| ext == BACKUP_FILE_EXTENSION))
+	.filter_map(|path: PathBuf| {
+	    let file = fs::File::open(&path).ok()?;
+	    let mut first_line = String::new();
+	    std::io::BufReader::new(file).read_line(&mut first_line).ok()?;
+	    let manifest = parse_manifest(first_line.trim_end()).ok()?;
+	    Some(BackupFile {
+		path: path.display().to_string(),
+		manifest,
+	    })
+	})
+	.collect();
+    files.sort_by(|a, b| b.manifest.created_at.cmp(&a.manifest.created_at));
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_store::MemoryStore;
+    use serde_json::json;
+
+    // A store holding one procedure that has been run once.
+    fn store_with_a_run() -> MemoryStore {
+	let store = MemoryStore::new();
+	store.load_docs(vec![
+	    json!({"_id": "giemsa", "_rev": "3-a", "type": "procedure", "name": "Giemsa", "version": 2,
+		   "jar_contents": ["Methanol", "Giemsa"],
+		   "procedure_steps": [{"time_in_seconds": 30, "jar_number": 1}, {"time_in_seconds": 1200, "jar_number": 2}]}),
+	    json!({"_id": "run-1", "_rev": "1-b", "type": "run", "procedure_id": "giemsa", "procedure_name": "Giemsa",
+		   "start_time": "2020-05-11T08:00:00Z", "end_time": "2020-05-11T08:21:00Z", "final_state": "Completed",
+		   "jar_usage": []}),
+	]).unwrap();
+	store
+    }
+
+    #[test]
+    fn backups_restore_into_an_empty_store() {
+	let backup = make_backup(&store_with_a_run()).unwrap();
+	let fresh = MemoryStore::new();
+	let summary = restore_backup(&fresh, &backup).unwrap();
+	assert_eq!((summary.manifest.procedure_count, summary.manifest.run_count), (1, 1));
+	assert_eq!((summary.created, summary.overwritten), (2, 3)); // the default settings, rack and reagent thresholds are replaced
+	assert!(summary.skipped.is_empty());
+
+	let giemsa = fresh.procedure_by_id("giemsa".to_string()).unwrap();
+	assert_eq!((giemsa.rev.as_str(), giemsa.version, giemsa.runs), ("3-a", Some(2), Some(1)));
+	assert_eq!(giemsa.procedure_steps[1].substance, "Giemsa");
+    }
+
+    #[test]
+    fn damaged_backups_are_refused_before_anything_changes() {
+	let backup = make_backup(&store_with_a_run()).unwrap();
+	let fresh = MemoryStore::new();
+
+	let cut_short : String = backup.lines().take(2).map(|line| format!("{}\n", line)).collect();
+	let message = restore_backup(&fresh, &cut_short).unwrap_err().message().to_string();
+	assert!(message.contains("cut short"), "{}", message);
+
+	let altered = backup.replace("\"time_in_seconds\":1200", "\"time_in_seconds\":12");
+	let message = restore_backup(&fresh, &altered).unwrap_err().message().to_string();
+	assert!(message.contains("checksum"), "{}", message);
+
+	assert!(fresh.procedures().unwrap().is_empty());
+    }
+
+    #[test]
+    fn newer_backup_formats_are_refused() {
+	let backup = make_backup(&MemoryStore::new()).unwrap();
+	let newer = backup.replacen(&format!("\"format_version\":{}", BACKUP_FORMAT_VERSION),
+				    &format!("\"format_version\":{}", BACKUP_FORMAT_VERSION + 1), 1);
+	let message = parse_backup(&newer).unwrap_err().message().to_string();
+	assert!(message.contains("Update this instrument's software"), "{}", message);
+	assert!(parse_backup("procedure,time\n").is_err());
+    }
+
+    // An empty backup root of its own for the test, standing in for a USB stick's mount point.
+    fn usb_stick(name: &str) -> PathBuf {
+	let root = std::env::temp_dir().join(format!("openstainer-backup-root-{}-{}", name, std::process::id()));
+	let _ = fs::remove_dir_all(&root);
+	fs::create_dir_all(root.join("lab")).unwrap();
+	root
+    }
+
+    #[test]
+    fn backup_paths_stay_under_the_root() {
+	let root = usb_stick("confine");
+	let canonical_root = root.canonicalize().unwrap();
+	assert_eq!(confine_to_root(&root, Path::new("lab/monday.jsonl")).unwrap(), canonical_root.join("lab/monday.jsonl"));
+	assert!(confine_to_root(&root, Path::new("../monday.jsonl")).is_err());
+	assert!(confine_to_root(&root, Path::new("lab/..")).is_ok());
+	assert!(confine_to_root(&root, Path::new("/etc/passwd")).is_err());
+	assert!(confine_to_root(&root, Path::new("missing/monday.jsonl")).is_err());
+	let _ = fs::remove_dir_all(&root);
+    }
+
+    #[test]
+    fn backup_files_are_never_overwritten() {
+	let root = usb_stick("overwrite");
+	let store = store_with_a_run();
+	let written = write_backup_file(&store, &root, Path::new("lab/friday.jsonl")).unwrap();
+	assert_eq!(written.manifest.procedure_count, 1);
+	assert!(write_backup_file(&store, &root, Path::new("lab/friday.jsonl")).is_err());
+
+	let listed = backup_files(&root, Path::new("lab")).unwrap();
+	assert_eq!(listed.iter().map(|file| file.path.clone()).collect::<Vec<String>>(), vec![written.path.clone()]);
+	let restored = restore_backup_file(&MemoryStore::new(), &root, Path::new(&written.path)).unwrap();
+	assert_eq!(restored.created, 2);
+	let _ = fs::remove_dir_all(&root);
+    }
+}
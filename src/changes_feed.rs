@@ -0,0 +1,264 @@
+pub use crate::structs_and_consts::*;
+pub use crate::storage::*;
+pub use crate::status_events::*;
+
+use serde::*;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader};
+use std::sync::{Arc, Mutex};
+use std::{thread, time};
+
+// Other instances of rmrm, or CouchDB's own web UI, can change procedures and settings while this one is running.
+// The CouchDB _changes feed is followed so those changes are published to event stream clients as they happen
+// (e.g. a "procedure_change" event; see status_events.rs), the current procedure is reloaded, and a run can't be
+// started on a procedure that changed after it was loaded. Only the CouchDB backend has a changes feed.
+
+// The newest _rev seen in the changes feed for each procedure, so run starts can tell a stale procedure.
+#[derive(Default)]
+pub struct ChangeTracker {
+    procedure_revs: Mutex<HashMap<String, String>>,
+}
+
+pub type SharedChangeTracker = Arc<ChangeTracker>;
+
+// The generation at the start of a CouchDB _rev, e.g. 3 for "3-917fa23".
+fn rev_generation(rev: &str) -> u64 {
+    rev.split('-').next().and_then(|generation| generation.parse::<u64>().ok()).unwrap_or(0)
+}
+
+impl ChangeTracker {
+    pub fn new() -> ChangeTracker {
+	ChangeTracker {
+	    procedure_revs: Mutex::new(HashMap::new()),
+	}
+    }
+
+    pub fn record_procedure_rev(&self, id: &str, rev: &str) {
+	self.procedure_revs.lock().unwrap().insert(id.to_string(), rev.to_string());
+    }
+
+    // Whether the feed has seen a newer revision of the procedure than rev. The feed can lag behind a read,
+    // so an older revision in the feed doesn't count.
+    pub fn procedure_changed_since(&self, id: &str, rev: &str) -> bool {
+	match self.procedure_revs.lock().unwrap().get(id) {
+	    Some(latest) => rev_generation(latest) > rev_generation(rev) || (rev_generation(latest) == rev_generation(rev) && latest != rev),
+	    None => false,
+	}
+    }
+}
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangedDocKind {
+    Procedure,
+    Settings,
+    Rack,
+    ReagentThresholds,
+}
+
+impl ChangedDocKind {
+    // The name of the event published for a change to this kind of doc.
+    pub fn event_name(&self) -> &'static str {
+	match self {
+	    ChangedDocKind::Procedure => "procedure_change",
+	    ChangedDocKind::Settings => "settings_change",
+	    ChangedDocKind::Rack => "rack_change",
+	    ChangedDocKind::ReagentThresholds => "reagent_thresholds_change",
+	}
+    }
+}
+
+// A change to a doc, as published to event stream clients.
+#[derive(Debug, Serialize, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct DocChangeEvent {
+    #[serde(rename = "_id")]
+    pub id: String,
+    #[serde(rename = "_rev")]
+    pub rev: String,
+    pub kind: ChangedDocKind,
+    pub deleted: bool, // for procedures, also true once the procedure is archived
+    pub affects_active_run: bool, // the running procedure was changed; the run carries on with the version it started with
+}
+
+// A line of the continuous changes feed, e.g. {"seq":"12-g1AAA","id":"abc","changes":[{"rev":"3-917fa23"}],"doc":{...}}
+#[derive(Debug, Deserialize)]
+struct ChangesFeedRow {
+    seq: serde_json::Value, // a string in CouchDB 2 and later, a number before
+    id: String,
+    changes: Vec<ChangedRev>,
+    #[serde(default)]
+    deleted: bool,
+    #[serde(default)]
+    doc: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChangedRev {
+    rev: String,
+}
+
+// Reads a line of the changes feed. Returns None for heartbeats (blank lines) and the feed's last_seq line.
+// The event is None for changes to docs that aren't published, such as runs and design docs; their seq still counts.
+fn parse_change_line(line: &str, active_procedure_id: Option<&str>) -> Option<(serde_json::Value, Option<DocChangeEvent>)> {
+    if line.trim().is_empty() {
+	return None;
+    }
+    let row = match serde_json::from_str::<ChangesFeedRow>(line) {
+	Ok(row) => row,
+	Err(_) => return None,
+    };
+    let doc = row.doc.unwrap_or(serde_json::Value::Null);
+    let kind = match (row.id.as_str(), doc["type"].as_str()) {
+	("settings", _) => ChangedDocKind::Settings,
+	("rack", _) => ChangedDocKind::Rack,
+	("reagent_thresholds", _) => ChangedDocKind::ReagentThresholds,
+	(_, Some("procedure")) => ChangedDocKind::Procedure,
+	// deleted docs only keep their _id, so a deleted doc that isn't one of the above is taken to be a procedure
+	(id, None) if row.deleted && !id.starts_with("_design/") => ChangedDocKind::Procedure,
+	_ => return Some((row.seq, None)),
+    };
+    let rev = match row.changes.into_iter().next() {
+	Some(change) => change.rev,
+	None => return Some((row.seq, None)),
+    };
+    let event = DocChangeEvent {
+	affects_active_run: kind == ChangedDocKind::Procedure && active_procedure_id == Some(row.id.as_str()),
+	deleted: row.deleted || !doc["archived_at"].is_null(),
+	id: row.id,
+	rev,
+	kind,
+    };
+    Some((row.seq, Some(event)))
+}
+
+fn changes_url(db_url: &str, since: &serde_json::Value) -> Result<reqwest::Url, String> {
+    let mut url = reqwest::Url::parse(db_url).map_err(|e| format!("{} isn't a valid URL: {}", db_url, e))?;
+    url.path_segments_mut()
+	.map_err(|_| format!("{} can't have a path.", db_url))?
+	.push("_changes");
+    let since = match since {
+	serde_json::Value::String(since) => since.clone(),
+	since => since.to_string(),
+    };
+    url.query_pairs_mut()
+	.append_pair("feed", "continuous")
+	.append_pair("include_docs", "true")
+	.append_pair("heartbeat", "10000") // a blank line every 10 s, so a dead connection is noticed
+	.append_pair("since", &since);
+    Ok(url)
+}
+
+// Acts on a change: remembers a procedure's new _rev, reloads the current procedure if it changed and no run is
+// using it, and tells event stream clients.
+fn handle_change(event: &DocChangeEvent, store: &dyn ProcedureStore, pes: &ProcedureExecutionState, tracker: &ChangeTracker, broadcaster: &EventBroadcaster) {
+    if event.kind == ChangedDocKind::Procedure {
+	tracker.record_procedure_rev(&event.id, &event.rev);
+	let is_current = pes.current_procedure.lock().unwrap().as_ref().map(|proc| &proc.id) == Some(&event.id);
+	if is_current && !event.affects_active_run {
+	    // the lock isn't held while the store is read, so check the procedure is still the current one before replacing it
+	    match store.find_procedure(event.id.clone()) {
+		Ok(proc) => {
+		    let current_procedure = &mut *pes.current_procedure.lock().unwrap();
+		    if current_procedure.as_ref().map(|proc| &proc.id) == Some(&event.id) {
+			*current_procedure = proc;
+		    }
+		}
+		Err(e) => println!("Couldn't reload procedure {}: {}", event.id, e.message()),
+	    }
+	}
+    }
+    broadcaster.publish(event.kind.event_name(), event);
+}
+
+// Follows the changes feed from now until the connection drops. Returns the last seq seen, to carry on from.
+fn follow_changes(db_url: &str, since: serde_json::Value, store: &dyn ProcedureStore, pes: &ProcedureExecutionState,
+		  tracker: &ChangeTracker, broadcaster: &EventBroadcaster) -> Result<serde_json::Value, (serde_json::Value, String)> {
+    let url = changes_url(db_url, &since).map_err(|e| (since.clone(), e))?;
+    // the feed never ends, so the client's default timeout would cut it off
+    let client = reqwest::blocking::Client::builder().timeout(None).build().map_err(|e| (since.clone(), e.to_string()))?;
+    let resp = client.get(url).send().map_err(|e| (since.clone(), format!("Unable to connect with CouchDB: {}", e)))?;
+    if !resp.status().is_success() {
+	return Err((since, format!("CouchDB returned {} for the changes feed.", resp.status())));
+    }
+
+    let mut last_seq = since;
+    for line in BufReader::new(resp).lines() {
+	let line = line.map_err(|e| (last_seq.clone(), e.to_string()))?;
+	let active_procedure_id = pes.active_run.lock().unwrap().as_ref().map(|run| run.procedure.id.clone());
+	if let Some((seq, event)) = parse_change_line(&line, active_procedure_id.as_deref()) {
+	    if let Some(event) = event {
+		handle_change(&event, store, pes, tracker, broadcaster);
+	    }
+	    last_seq = seq;
+	}
+    }
+    Ok(last_seq)
+}
+
+// Follows the changes feed of the database at db_url on a background thread, reconnecting whenever the connection
+// drops. Changes made while it was disconnected are caught up on when it reconnects.
+pub fn start_changes_listener(db_url: String, store: SharedProcedureStore, pes: SharedProcedureExecutionState,
+			      tracker: SharedChangeTracker, broadcaster: SharedEventBroadcaster) {
+    thread::spawn(move || {
+	let mut since = serde_json::Value::String("now".to_string());
+	let mut retry_seconds = 1;
+	loop {
+	    match follow_changes(&db_url, since.clone(), &*store, &pes, &tracker, &broadcaster) {
+		Ok(last_seq) => {
+		    println!("The CouchDB changes feed ended. Reconnecting.");
+		    since = last_seq;
+		    retry_seconds = 1;
+		}
+		Err((last_seq, e)) => {
+		    println!("Lost the CouchDB changes feed: {}. Retrying in {} s.", e, retry_seconds);
+		    since = last_seq;
+		}
+	    }
+	    thread::sleep(time::Duration::from_secs(retry_seconds));
+	    retry_seconds = std::cmp::min(retry_seconds * 2, 60);
+	}
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_newer_revs_count_as_changes() {
+	let changes = ChangeTracker::new();
+	assert!(!changes.procedure_changed_since("pap", "2-a"));
+	changes.record_procedure_rev("pap", "3-c");
+	assert!(changes.procedure_changed_since("pap", "2-a"));
+	assert!(changes.procedure_changed_since("pap", "3-b"));
+	assert!(!changes.procedure_changed_since("pap", "3-c"));
+	assert!(!changes.procedure_changed_since("pap", "4-d")); // the feed hasn't caught up with this read yet
+    }
+
+    #[test]
+    fn feed_lines_become_change_events() {
+	let line = r#"{"seq":"12-g1AAA","id":"pap","changes":[{"rev":"3-917fa23"}],"doc":{"_id":"pap","type":"procedure","archived_at":"2020-05-20T10:00:00Z"}}"#;
+	let (seq, event) = parse_change_line(line, Some("pap")).unwrap();
+	let event = event.unwrap();
+	assert_eq!(seq, serde_json::json!("12-g1AAA"));
+	assert_eq!((event.kind, event.rev.as_str(), event.deleted, event.affects_active_run), (ChangedDocKind::Procedure, "3-917fa23", true, true));
+
+	let line = r#"{"seq":13,"id":"settings","changes":[{"rev":"5-e"}],"doc":{"_id":"settings","developer":true}}"#;
+	let event = parse_change_line(line, Some("pap")).unwrap().1.unwrap();
+	assert_eq!((event.kind, event.deleted, event.affects_active_run), (ChangedDocKind::Settings, false, false));
+
+	let line = r#"{"seq":"14-x","id":"old-proc","changes":[{"rev":"7-f"}],"deleted":true}"#;
+	assert_eq!(parse_change_line(line, None).unwrap().1.unwrap().kind, ChangedDocKind::Procedure);
+    }
+
+    #[test]
+    fn runs_design_docs_and_heartbeats_are_not_published() {
+	assert!(parse_change_line("", None).is_none());
+	assert!(parse_change_line(r#"{"last_seq":"20-y","pending":0}"#, None).is_none());
+	// the feed still moves past them, so they aren't read again after a reconnect
+	let (seq, event) = parse_change_line(r#"{"seq":"15-x","id":"run-4","changes":[{"rev":"1-a"}],"doc":{"type":"run"}}"#, None).unwrap();
+	assert_eq!((seq, event), (serde_json::json!("15-x"), None));
+	assert!(parse_change_line(r#"{"seq":"16-x","id":"_design/procedures","changes":[{"rev":"2-a"}],"deleted":true}"#, None).unwrap().1.is_none());
+    }
+}
@@ -1,8 +1,12 @@
 pub use crate::structs_and_consts::*;
+pub use crate::jar_contents::*;
+pub use crate::storage::*;
 
+use chrono::{DateTime, Utc};
 use juniper::FieldResult;
 use juniper::graphql_value;
 use serde::*;
+use std::collections::{BTreeMap, HashMap};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct SingleViewResultWithIncludeDocs<T> {
@@ -18,6 +22,17 @@ pub struct ViewResult<T> {
     pub rows: Vec<SingleViewResultWithIncludeDocs<T>>,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReducedViewRow<K, V> {
+    pub key: K,
+    pub value: V,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReducedViewResult<K, V> {
+    pub rows: Vec<ReducedViewRow<K, V>>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CouchDBPOSTResponse {
     pub ok: bool,
@@ -30,146 +45,730 @@ pub fn juniper_err<T>(message: String) -> FieldResult<T>{
     Err(juniper::FieldError::new(message.clone(),graphql_value!({ "internal_error": message})))
 }
 
-pub fn get_doc<T: serde::de::DeserializeOwned>(id: String) -> FieldResult<T> {
-    let url : &str = &format!("{}/{}",COUCHDB_URL,id).to_string();
-    let resp = reqwest::blocking::get(url);
-    if resp.is_ok() {
-	let parse_result = resp.unwrap().json::<T>();
-	if parse_result.is_err() {
-	    return juniper_err::<T>(format!("Couldn't parse response from CouchDB: {:?}",parse_result.err()));
-	}
-	let proc = parse_result.unwrap();
-	return Ok(proc);
+// CouchDB's body for a failed request, e.g. {"error":"conflict","reason":"Document update conflict."}
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CouchDBErrorResponse {
+    pub error: String,
+    pub reason: String,
+}
+
+fn unavailable(e: reqwest::Error) -> StorageError {
+    StorageError::Unavailable(format!("Unable to connect with CouchDB: {}", e))
+}
+
+// Passes a successful response through, and turns any other into the matching StorageError.
+// what names the doc for the error message, e.g. "procedure with ID abc".
+fn check_response(resp: reqwest::blocking::Response, what: &str) -> StorageResult<reqwest::blocking::Response> {
+    let status = resp.status();
+    if status.is_success() {
+	return Ok(resp);
     }
-    return juniper_err::<T>("No procedure with that ID found.".to_string());
+    let reason = match resp.json::<CouchDBErrorResponse>() {
+	Ok(body) => format!("{}: {}", body.error, body.reason),
+	Err(_) => status.to_string(),
+    };
+    Err(match status.as_u16() {
+	404 => StorageError::NotFound(format!("No {} found.", what)),
+	409 => StorageError::conflict(format!("Document update conflict: the {} has been changed since it was loaded.", what)),
+	400 | 415 => StorageError::InvalidDocument(format!("CouchDB rejected the {} ({}).", what, reason)),
+	500..=599 => StorageError::Unavailable(format!("CouchDB failed while handling the {} ({}).", what, reason)),
+	_ => StorageError::Unexpected(format!("Recieved status {} from CouchDB for the {} ({}).", status, what, reason)),
+    })
 }
 
-pub fn procedure_by_id(id: String) -> FieldResult<Procedure> {
-    get_doc::<Procedure>(id)
+fn parse_response<T: serde::de::DeserializeOwned>(resp: reqwest::blocking::Response, what: &str) -> StorageResult<T> {
+    resp.json::<T>().map_err(|e| StorageError::InvalidDocument(format!("Couldn't parse the {} from CouchDB: {}", what, e)))
 }
 
-pub fn save_procedure(procedure: Procedure) -> FieldResult<Procedure> {
-    save_procedure_input_object( ProcedureInputObject::from(procedure) )
+// The URL of a path under the database, e.g. db_url(&[id]) for a doc. Each segment is percent-encoded, so an odd
+// _id can't make a bad URL or reach a different doc.
+fn db_url(segments: &[&str]) -> StorageResult<reqwest::Url> {
+    let mut url = reqwest::Url::parse(COUCHDB_URL)
+	.map_err(|e| StorageError::Unexpected(format!("COUCHDB_URL {} isn't a valid URL: {}", COUCHDB_URL, e)))?;
+    url.path_segments_mut()
+	.map_err(|_| StorageError::Unexpected(format!("COUCHDB_URL {} can't have a path.", COUCHDB_URL)))?
+	.extend(segments);
+    Ok(url)
+}
+
+// GETs one of the views in the procedures design doc. If the view is missing (a new database, or one set up before
+// the view was added) the views are installed and the request is tried again.
+fn get_view(view: &str, query: &[(&str, &str)]) -> StorageResult<reqwest::blocking::Response> {
+    let url = db_url(&["_design", "procedures", "_view", view])?;
+    let client = reqwest::blocking::Client::new();
+    let mut resp = client.get(url.clone()).query(query).send().map_err(unavailable)?;
+    if resp.status() == 404 {
+	println!("The {} view is missing, so the design docs are being installed.", view);
+	install_design_docs()?;
+	resp = client.get(url).query(query).send().map_err(unavailable)?;
+    }
+    check_response(resp, &format!("{} view", view))
 }
 
-pub fn save_procedure_input_object(procedure: ProcedureInputObject) -> FieldResult<Procedure> {
+// POSTs a doc, creating it or saving a new revision of it.
+fn post_doc<D: Serialize>(doc: &D, what: &str) -> StorageResult<CouchDBPOSTResponse> {
     let client = reqwest::blocking::Client::new();
     let resp = client.post(COUCHDB_URL)
-	.json(&procedure)
-	.send();
-    if resp.is_err() {
-	return juniper_err::<Procedure>("Unable to connect with CouchDB.".to_string());
+	.json(doc)
+	.send()
+	.map_err(unavailable)?;
+    parse_response(check_response(resp, what)?, &format!("response to saving the {}", what))
+}
+
+pub fn get_doc<T: serde::de::DeserializeOwned>(id: String) -> StorageResult<T> {
+    match get_optional_doc::<T>(id.clone())? {
+	Some(doc) => Ok(doc),
+	None => Err(StorageError::NotFound(format!("No doc with ID {} found.", id))),
     }
-    let unwrapped = resp.unwrap();
-    let parse_result = unwrapped.json::<CouchDBPOSTResponse>();
-    if parse_result.is_err() {
-	return juniper_err::<Procedure>(format!("Couldn't parse response from CouchDB: {:?}",parse_result.err()));
+}
+
+// Like get_doc, but returns None rather than an error if the document doesn't exist.
+pub fn get_optional_doc<T: serde::de::DeserializeOwned>(id: String) -> StorageResult<Option<T>> {
+    let resp = reqwest::blocking::get(db_url(&[&id])?).map_err(unavailable)?;
+    if resp.status() == 404 {
+	return Ok(None);
     }
+    let what = format!("doc with ID {}", id);
+    Ok(Some(parse_response(check_response(resp, &what)?, &what)?))
+}
 
-    procedure_by_id(parse_result.unwrap().id)
+// Number of run docs for each procedure, keyed by procedure _id. Procedures that have never been run aren't listed.
+pub fn run_counts() -> StorageResult<HashMap<String, i32>> {
+    let resp = match get_view("runs", &[("group", "true")]) {
+	Err(StorageError::NotFound(_)) => {
+	    println!("The runs view couldn't be installed, so run counts only include runs from before run history was kept.");
+	    return Ok(HashMap::new());
+	}
+	resp => resp?,
+    };
+    let view_result = parse_response::<ReducedViewResult<String, i32>>(resp, "runs view")?;
+    Ok(view_result.rows.into_iter().map(|row| (row.key, row.value)).collect())
+}
+
+fn fill_run_count(proc: &mut Procedure, run_counts: &HashMap<String, i32>) {
+    let recorded_runs = run_counts.get(&proc.id).cloned().unwrap_or(0);
+    proc.runs = Some(proc.runs_before_run_history.unwrap_or(0) + recorded_runs);
+}
+
+pub fn procedure_by_id(id: String) -> StorageResult<Procedure> {
+    match find_procedure_by_id(id.clone())? {
+	Some(proc) => Ok(proc),
+	None => Err(StorageError::NotFound(format!("No procedure with ID {} found.", id))),
+    }
 }
 
-pub fn increment_run_count( proc: &mut Procedure) -> FieldResult<Procedure> {
-    // increment the number of runs
-    let cur_runs = match proc.runs {
-	Some(v) => v,
-	None => 0
+// Like procedure_by_id, but returns None rather than an error if the procedure doesn't exist.
+// Other docs, e.g. the procedure_version snapshots, aren't procedures even if they'd parse as one.
+pub fn find_procedure_by_id(id: String) -> StorageResult<Option<Procedure>> {
+    match get_optional_doc::<serde_json::Value>(id.clone())? {
+	Some(doc) if doc["type"].as_str() == Some("procedure") => {
+	    let mut proc = serde_json::from_value::<Procedure>(doc)
+		.map_err(|e| StorageError::InvalidDocument(format!("Couldn't parse procedure {}: {}", id, e)))?;
+	    reconcile_procedure(&mut proc);
+	    fill_run_count(&mut proc, &run_counts()?);
+	    Ok(Some(proc))
+	}
+	_ => Ok(None),
+    }
+}
+
+pub fn save_procedure(procedure: Procedure) -> StorageResult<Procedure> {
+    save_procedure_input_object( ProcedureInputObject::from(procedure) )
+}
+
+pub fn save_procedure_input_object(mut procedure: ProcedureInputObject) -> StorageResult<Procedure> {
+    reconcile_procedure_input_object(&mut procedure);
+    let mut doc = serde_json::to_value(&procedure).unwrap();
+    // The input's run count is computed, so the old stored count (if any) is carried over from the saved doc instead.
+    // Whether it's archived isn't part of the input either.
+    if let Some(id) = &procedure.id {
+	if let Some(existing) = get_optional_doc::<serde_json::Value>(id.clone())? {
+	    if existing["type"].as_str() != Some("procedure") {
+		return Err(StorageError::NotAllowed(format!("{} isn't a procedure, so it can't be saved as one.", id)));
+	    }
+	    for field in ["runs", "archived_at"].iter() {
+		if let Some(value) = existing.get(*field) {
+		    doc[*field] = value.clone();
+		}
+	    }
+	}
+    }
+    let what = match &procedure.id {
+	Some(id) => format!("procedure with ID {}", id),
+	None => "new procedure".to_string(),
     };
-    proc.runs = Some(cur_runs+1);
+    let saved = post_doc(&doc, &what)?;
+    procedure_by_id(saved.id)
+}
+
+pub fn procedure_version_id(procedure_id: &str, version: i32) -> String {
+    format!("{}:v{}", procedure_id, version)
+}
+
+pub fn procedure_version(procedure_id: String, version: i32) -> StorageResult<ProcedureVersion> {
+    match get_optional_doc::<ProcedureVersion>(procedure_version_id(&procedure_id, version))? {
+	Some(mut proc_version) => {
+	    for step in proc_version.procedure_steps.iter_mut() {
+		step.substance = substance_in_jar(&proc_version.jar_contents, step.jar_number);
+	    }
+	    Ok(proc_version)
+	}
+	None => Err(StorageError::NotFound(format!("Procedure {} has no version {}.", procedure_id, version))),
+    }
+}
+
+// Lists the saved versions of a procedure, oldest first. The version docs are found by their _id prefix, so no view is needed.
+pub fn procedure_versions(procedure_id: String) -> StorageResult<Vec<ProcedureVersion>> {
+    let prefix = format!("{}:v", procedure_id);
+    let client = reqwest::blocking::Client::new();
+    let resp = client.get(db_url(&["_all_docs"])?)
+	.query(&[("include_docs", "true".to_string()),
+		 ("startkey", serde_json::to_string(&prefix).unwrap()),
+		 ("endkey", serde_json::to_string(&format!("{}\u{fff0}", prefix)).unwrap())])
+	.send()
+	.map_err(unavailable)?;
+    let what = format!("versions of procedure {}", procedure_id);
+    let view_result = parse_response::<ViewResult<ProcedureVersion>>(check_response(resp, &what)?, &what)?;
+    let mut versions : Vec<ProcedureVersion> = view_result.rows.into_iter().map(|row| {
+	let mut proc_version = row.doc;
+	for step in proc_version.procedure_steps.iter_mut() {
+	    step.substance = substance_in_jar(&proc_version.jar_contents, step.jar_number);
+	}
+	proc_version
+    }).collect();
+    versions.sort_by_key(|proc_version| proc_version.version); // _id order puts v10 before v2
+    Ok(versions)
+}
+
+// Every doc in the database except design docs, as raw JSON. Used to copy the database to another backend.
+pub fn all_docs() -> StorageResult<Vec<serde_json::Value>> {
+    let client = reqwest::blocking::Client::new();
+    let resp = client.get(db_url(&["_all_docs"])?)
+	.query(&[("include_docs", "true")])
+	.send()
+	.map_err(unavailable)?;
+    let view_result = parse_response::<ViewResult<serde_json::Value>>(check_response(resp, "list of all docs")?, "list of all docs")?;
+    Ok(view_result.rows.into_iter()
+       .filter(|row| !row.id.starts_with("_design/"))
+       .map(|row| row.doc)
+       .collect())
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct DocRev {
+    rev: String,
+}
 
-    // save the updated procedure
-    return save_procedure(proc.clone());
+#[derive(Debug, Serialize, Deserialize)]
+struct AllDocsRow {
+    id: String,
+    value: DocRev,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct AllDocsResult {
+    rows: Vec<AllDocsRow>,
+}
+
+// One entry of the response to a _bulk_docs request: a rev if the doc was saved, an error if it wasn't.
+#[derive(Debug, Serialize, Deserialize)]
+struct BulkDocsResult {
+    id: String,
+    #[serde(default)]
+    error: Option<String>,
+    #[serde(default)]
+    reason: Option<String>,
 }
 
-pub fn procedures() -> FieldResult<Vec<Procedure>> {
-    let resp = reqwest::blocking::get(reqwest::Url::parse(format!("{}/_design/procedures/_view/procedures?include_docs=true",COUCHDB_URL).as_str()).unwrap());
-    if resp.is_ok() {
-	let view_result = resp.unwrap().json::<ViewResult<Procedure>>().unwrap();
-	let v : Vec<Procedure> = view_result.rows.into_iter().map(|row| row.doc ).collect();
-	return Ok(v);
+// Saves docs from elsewhere (e.g. a backup), replacing any with the same _id whatever their _rev. The database is set up
+// first in case it's new. CouchDB saves each doc separately, so docs it rejects are reported in skipped and the rest are saved.
+pub fn load_docs(docs: Vec<serde_json::Value>) -> StorageResult<DocsLoaded> {
+    configure_database()?;
+    let resp = reqwest::blocking::get(db_url(&["_all_docs"])?).map_err(unavailable)?;
+    let current_revs : HashMap<String, String> = parse_response::<AllDocsResult>(check_response(resp, "list of all docs")?, "list of all docs")?
+	.rows.into_iter()
+	.map(|row| (row.id, row.value.rev))
+	.collect();
+
+    let (docs, skipped) = docs_to_restore(docs, &current_revs);
+    let client = reqwest::blocking::Client::new();
+    let resp = client.post(db_url(&["_bulk_docs"])?)
+	.json(&serde_json::json!({ "docs": docs }))
+	.send()
+	.map_err(unavailable)?;
+    let results = parse_response::<Vec<BulkDocsResult>>(check_response(resp, "restored docs")?, "restored docs")?;
+
+    let mut loaded = DocsLoaded {
+	created: 0,
+	overwritten: 0,
+	skipped,
+    };
+    for result in results {
+	match result.error {
+	    Some(error) => loaded.skipped.push(SkippedDocument {
+		id: result.id,
+		reason: format!("CouchDB rejected it ({}: {}).", error, result.reason.unwrap_or_default()),
+	    }),
+	    None if current_revs.contains_key(&result.id) => loaded.overwritten += 1,
+	    None => loaded.created += 1,
+	}
     }
-    return juniper_err::<Vec<Procedure>>("Unable to retrive list of procedures after delete.".to_string());
+    Ok(loaded)
 }
 
-pub fn delete_procedure(id: String, rev: String) -> FieldResult<Vec<Procedure>> {
+// Points each doc at the revision it replaces. Versions that already exist are left out, since versions are immutable.
+fn docs_to_restore(docs: Vec<serde_json::Value>, current_revs: &HashMap<String, String>) -> (Vec<serde_json::Value>, Vec<SkippedDocument>) {
+    let mut skipped = Vec::new();
+    let docs = docs.into_iter().filter_map(|mut doc| {
+	let current_rev = doc["_id"].as_str().and_then(|id| current_revs.get(id)).cloned();
+	if current_rev.is_some() && doc["type"] == "procedure_version" {
+	    skipped.push(SkippedDocument {
+		id: doc["_id"].as_str().unwrap_or("").to_string(),
+		reason: "This procedure version already exists.".to_string(),
+	    });
+	    return None;
+	}
+	match (current_rev, doc.as_object_mut()) {
+	    (Some(rev), Some(fields)) => { fields.insert("_rev".to_string(), serde_json::Value::String(rev)); }
+	    (None, Some(fields)) => { fields.remove("_rev"); }
+	    (_, None) => {}
+	}
+	Some(doc)
+    }).collect();
+    (docs, skipped)
+}
+
+// Writes a version doc if it doesn't already exist. Existing versions are left untouched, since versions are immutable.
+pub fn create_procedure_version(proc_version: &ProcedureVersion) -> StorageResult<()> {
     let client = reqwest::blocking::Client::new();
-    let url : &str = &format!("{}/{}?rev={}",COUCHDB_URL,id,rev).to_string();
-    let resp = client.delete(url).send();
+    let resp = client.put(db_url(&[&proc_version.id])?)
+	.json(proc_version)
+	.send()
+	.map_err(unavailable)?;
+    if resp.status() == 409 {
+	return Ok(());
+    }
+    check_response(resp, &format!("procedure version {}", proc_version.id))?;
+    Ok(())
+}
 
-    if resp.is_err() {
-	return juniper_err::<Vec<Procedure>>(format!("Unable to communicate with CouchDB server"));
+// Every procedure doc, parsed one at a time so that a malformed doc is reported in skipped rather than failing the list.
+pub fn procedure_list() -> StorageResult<ProcedureList> {
+    let resp = get_view("procedures", &[("include_docs", "true")])?;
+    let view_result = parse_response::<ViewResult<serde_json::Value>>(resp, "procedures view")?;
+    let run_counts = run_counts()?;
+    let mut list = ProcedureList {
+	procedures: Vec::new(),
+	skipped: Vec::new(),
+    };
+    for row in view_result.rows {
+	match serde_json::from_value::<Procedure>(row.doc) {
+	    Ok(mut proc) => {
+		reconcile_procedure(&mut proc);
+		fill_run_count(&mut proc, &run_counts);
+		list.procedures.push(proc);
+	    }
+	    Err(e) => list.skipped.push(SkippedDocument {
+		id: row.id,
+		reason: format!("Couldn't parse the procedure: {}", e),
+	    }),
+	}
     }
-    let resp = resp.unwrap();
-    if resp.status() != 200 {
-	return juniper_err::<Vec<Procedure>>(format!("Recieved status {} and text {:?} from CouchDB when attempting to delete.",resp.status(),resp.text()));
+    Ok(list)
+}
+
+pub fn procedures() -> StorageResult<Vec<Procedure>> {
+    Ok(procedure_list()?.procedures)
+}
+
+// Rewrites procedure documents that still store a substance on each step so that jar_contents is the only
+// place substances are stored. Blank jar contents are filled in from the steps first. Returns the rewritten procedures.
+pub fn migrate_step_substances() -> StorageResult<Vec<Procedure>> {
+    let resp = get_view("procedures", &[("include_docs", "true")])?;
+    // The raw JSON is needed here since a parsed ProcedureStep can't tell whether the substance was stored.
+    let view_result = parse_response::<ViewResult<serde_json::Value>>(resp, "procedures view")?;
+
+    let mut migrated : Vec<Procedure> = Vec::new();
+    for row in view_result.rows {
+	let stores_substances = match row.doc["procedure_steps"].as_array() {
+	    Some(steps) => steps.iter().any(|step| step.get("substance").is_some()),
+	    None => false,
+	};
+	if !stores_substances {
+	    continue;
+	}
+	let id = row.id;
+	let mut proc = serde_json::from_value::<Procedure>(row.doc)
+	    .map_err(|e| StorageError::InvalidDocument(format!("Couldn't parse procedure {}: {}", id, e)))?;
+	reconcile_procedure(&mut proc);
+	migrated.push(save_procedure(proc)?);
     }
-    
-    return procedures();
+    Ok(migrated)
 }
 
-pub fn settings() -> FieldResult<Settings> {
-    get_doc::<Settings>("settings".to_string())
+// Archives the procedure, or restores it if archived_at is None. The rest of the doc is left as it is.
+pub fn set_procedure_archived(id: String, rev: String, archived_at: Option<DateTime<Utc>>) -> StorageResult<Procedure> {
+    let what = format!("procedure with ID {}", id);
+    let mut doc = get_doc::<serde_json::Value>(id.clone())?;
+    doc["_rev"] = serde_json::Value::String(rev); // so CouchDB rejects the change if the doc was changed since it was loaded
+    match archived_at {
+	Some(archived_at) => doc["archived_at"] = serde_json::to_value(archived_at).unwrap(),
+	None => {
+	    if let Some(doc) = doc.as_object_mut() {
+		doc.remove("archived_at");
+	    }
+	}
+    }
+    post_doc(&doc, &what)?;
+    procedure_by_id(id)
 }
 
-pub fn save_settings(settings_input_object: SettingsInputObject) -> FieldResult<Settings> {
+// Removes the procedure doc for good. Its version docs are kept, since runs refer to them.
+pub fn delete_procedure(id: String, rev: String) -> StorageResult<()> {
     let client = reqwest::blocking::Client::new();
-    let resp = client.post(COUCHDB_URL)
-	.json(&settings_input_object)
-	.send();
-    if resp.is_err() {
-	return juniper_err::<Settings>("Unable to connect with CouchDB.".to_string());
+    let resp = client.delete(db_url(&[&id])?)
+	.query(&[("rev", &rev)])
+	.send()
+	.map_err(unavailable)?;
+    check_response(resp, &format!("procedure with ID {}", id))?;
+    Ok(())
+}
+
+pub fn settings() -> StorageResult<Settings> {
+    match get_optional_doc::<Settings>("settings".to_string())? {
+	Some(settings) => Ok(settings),
+	None => Err(StorageError::NotFound("No settings doc found.".to_string())),
+    }
+}
+
+pub fn save_settings_doc(settings: Settings) -> StorageResult<Settings> {
+    post_doc(&settings, "settings doc")?;
+    self::settings()
+}
+
+pub fn rack() -> StorageResult<Rack> {
+    let rack = get_optional_doc::<Rack>("rack".to_string())?;
+    Ok(rack.unwrap_or_else(Rack::empty))
+}
+
+pub fn save_rack_doc(rack: Rack) -> StorageResult<Rack> {
+    post_doc(&rack, "rack doc")?;
+    self::rack()
+}
+
+pub fn reagent_thresholds() -> StorageResult<ReagentThresholds> {
+    let thresholds = get_optional_doc::<ReagentThresholds>("reagent_thresholds".to_string())?;
+    Ok(thresholds.unwrap_or_else(ReagentThresholds::empty))
+}
+
+pub fn save_reagent_thresholds(thresholds_input_object: ReagentThresholdsInputObject) -> StorageResult<ReagentThresholds> {
+    post_doc(&thresholds_input_object, "reagent thresholds doc")?;
+    reagent_thresholds()
+}
+
+pub fn save_run_record(run_record: &RunRecord) -> StorageResult<RunRecord> {
+    let saved = post_doc(run_record, "run record")?;
+    get_doc::<RunRecord>(saved.id)
+}
+
+// The stores backed by the CouchDB database at COUCHDB_URL.
+pub struct CouchDBStore;
+
+impl ProcedureStore for CouchDBStore {
+    fn configure(&self) -> StorageResult<DatabaseSetup> {
+	configure_database()
+    }
+
+    fn procedure_list(&self) -> StorageResult<ProcedureList> {
+	procedure_list()
+    }
+
+    fn find_procedure(&self, id: String) -> StorageResult<Option<Procedure>> {
+	find_procedure_by_id(id)
+    }
+
+    fn save_procedure(&self, procedure: ProcedureInputObject) -> StorageResult<Procedure> {
+	save_procedure_input_object(procedure)
     }
-    let unwrapped = resp.unwrap();
-    let parse_result = unwrapped.json::<CouchDBPOSTResponse>();
-    if parse_result.is_err() {
-	return juniper_err::<Settings>(format!("Couldn't parse response from CouchDB: {:?}",parse_result.err()));
+
+    fn set_procedure_archived(&self, id: String, rev: String, archived_at: Option<DateTime<Utc>>) -> StorageResult<Procedure> {
+	set_procedure_archived(id, rev, archived_at)
+    }
+
+    fn delete_procedure(&self, id: String, rev: String) -> StorageResult<()> {
+	delete_procedure(id, rev)
     }
 
-    settings()
+    fn procedure_versions(&self, procedure_id: String) -> StorageResult<Vec<ProcedureVersion>> {
+	procedure_versions(procedure_id)
+    }
+
+    fn procedure_version(&self, procedure_id: String, version: i32) -> StorageResult<ProcedureVersion> {
+	procedure_version(procedure_id, version)
+    }
+
+    fn create_procedure_version(&self, proc_version: &ProcedureVersion) -> StorageResult<()> {
+	create_procedure_version(proc_version)
+    }
+
+    fn save_run_record(&self, run_record: &RunRecord) -> StorageResult<RunRecord> {
+	save_run_record(run_record)
+    }
+
+    fn migrate_step_substances(&self) -> StorageResult<Vec<Procedure>> {
+	migrate_step_substances()
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-struct ViewsProcedures {
-    map: String
+impl BackupStore for CouchDBStore {
+    fn all_docs(&self) -> StorageResult<Vec<serde_json::Value>> {
+	all_docs()
+    }
+
+    fn load_docs(&self, docs: Vec<serde_json::Value>) -> StorageResult<DocsLoaded> {
+	load_docs(docs)
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-struct DesignDocumentViews {
-    procedures: ViewsProcedures
+impl SettingsStore for CouchDBStore {
+    fn settings(&self) -> StorageResult<Settings> {
+	settings()
+    }
+
+    fn save_settings_doc(&self, settings: Settings) -> StorageResult<Settings> {
+	save_settings_doc(settings)
+    }
+}
+
+impl RackStore for CouchDBStore {
+    fn rack(&self) -> StorageResult<Rack> {
+	rack()
+    }
+
+    fn save_rack_doc(&self, rack: Rack) -> StorageResult<Rack> {
+	save_rack_doc(rack)
+    }
+
+    fn reagent_thresholds(&self) -> StorageResult<ReagentThresholds> {
+	reagent_thresholds()
+    }
+
+    fn save_reagent_thresholds(&self, thresholds: ReagentThresholdsInputObject) -> StorageResult<ReagentThresholds> {
+	save_reagent_thresholds(thresholds)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+struct View {
+    map: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reduce: Option<String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 struct DesignDocument {
     #[serde(rename="_id")]
-    id: Option<String>,
+    id: String,
     
-    #[serde(rename="_rev")]
+    #[serde(rename="_rev", skip_serializing_if = "Option::is_none")]
     rev: Option<String>,
     
-    views: DesignDocumentViews,
+    views: BTreeMap<String, View>,
     language: String,
 }
 
-pub fn install_views() -> String {
-    // TODO this is a one-time install. Once the document exists, this won't update the doc since it doesn't have
-    // the _id and _rev attributes from the existing doc on the db.
-    // However, this suffices for the present need of storing the view functions in source-controlled code.
-    let doc = DesignDocument {
-	id: None,
-	rev: None,
-	views: DesignDocumentViews {
-	    procedures: ViewsProcedures {
-		map: "function (doc) { if(doc.type == \'procedure\') { emit(doc._id, doc.name); } }".to_string(),
-	    }
+fn view(map: &str, reduce: Option<&str>) -> View {
+    View {
+	map: map.to_string(),
+	reduce: reduce.map(|reduce| reduce.to_string()),
+    }
+}
+
+// The design docs the app needs, as they should be. New views are added here; install_design_docs brings
+// the database up to date the next time it runs.
+fn design_docs() -> Vec<DesignDocument> {
+    let mut procedure_views = BTreeMap::new();
+    procedure_views.insert("procedures".to_string(),
+			   view("function (doc) { if(doc.type == \'procedure\') { emit(doc._id, doc.name); } }", None));
+    procedure_views.insert("runs".to_string(),
+			   view("function (doc) { if(doc.type == \'run\') { emit(doc.procedure_id, 1); } }", Some("_count")));
+    vec![
+	DesignDocument {
+	    id: "_design/procedures".to_string(),
+	    rev: None,
+	    views: procedure_views,
+	    language: "javascript".to_string(),
 	},
-	language: "javascript".to_string(),
-    };
-    
-    
+    ]
+}
+
+// Whether a design doc read back from CouchDB already has the views and language it should.
+fn up_to_date(existing: &serde_json::Value, doc: &DesignDocument) -> bool {
+    existing["views"] == serde_json::to_value(&doc.views).unwrap() && existing["language"] == doc.language.as_str()
+}
+
+// Creates each design doc that's missing and updates each one whose views differ from design_docs().
+// Design docs that are already up to date are left alone, so this is safe to run on every start.
+pub fn install_design_docs() -> StorageResult<Vec<DesignDocSetup>> {
     let client = reqwest::blocking::Client::new();
-    let resp = client.post(COUCHDB_URL)
-	.json(&doc)
-	.send();
-    return format!("{:?}",resp);
+    let mut results : Vec<DesignDocSetup> = Vec::new();
+    for mut doc in design_docs() {
+	let segments : Vec<&str> = doc.id.splitn(2, '/').collect();
+	let url = db_url(&segments)?;
+	let what = format!("{} design doc", doc.id);
+	let resp = client.get(url.clone()).send().map_err(unavailable)?;
+	// the existing doc may be from an older version with different views, so it's compared as raw JSON
+	let existing = if resp.status() == 404 {
+	    None
+	} else {
+	    Some(parse_response::<serde_json::Value>(check_response(resp, &what)?, &what)?)
+	};
+	let action = match &existing {
+	    None => DesignDocAction::Created,
+	    Some(existing) => {
+		if up_to_date(existing, &doc) {
+		    results.push(DesignDocSetup { id: doc.id.clone(), action: DesignDocAction::Unchanged });
+		    continue;
+		}
+		doc.rev = existing["_rev"].as_str().map(|rev| rev.to_string());
+		DesignDocAction::Updated
+	    }
+	};
+
+	let resp = client.put(url)
+	    .json(&doc)
+	    .send()
+	    .map_err(unavailable)?;
+	if resp.status() == 409 {
+	    // another request installed it in the meantime
+	    results.push(DesignDocSetup { id: doc.id.clone(), action: DesignDocAction::Unchanged });
+	    continue;
+	}
+	check_response(resp, &what)?;
+	results.push(DesignDocSetup { id: doc.id.clone(), action });
+    }
+    Ok(results)
 }
 
+// Creates the database if it doesn't exist. Returns whether it was created.
+pub fn ensure_database() -> StorageResult<bool> {
+    let url = db_url(&[])?;
+    let client = reqwest::blocking::Client::new();
+    let resp = client.get(url.clone()).send().map_err(unavailable)?;
+    if resp.status() != 404 {
+	check_response(resp, "database")?;
+	return Ok(false);
+    }
+    let resp = client.put(url).send().map_err(unavailable)?;
+    if resp.status() == 412 {
+	return Ok(false); // created by another request in the meantime
+    }
+    check_response(resp, "database")?;
+    Ok(true)
+}
+
+// Saves a default settings doc if there isn't one. Returns whether one was saved.
+pub fn seed_settings() -> StorageResult<bool> {
+    if get_optional_doc::<serde_json::Value>("settings".to_string())?.is_some() {
+	return Ok(false);
+    }
+    let client = reqwest::blocking::Client::new();
+    let resp = client.put(db_url(&["settings"])?)
+	.json(&Settings::default())
+	.send()
+	.map_err(unavailable)?;
+    if resp.status() == 409 {
+	return Ok(false); // saved by another request in the meantime
+    }
+    check_response(resp, "settings doc")?;
+    Ok(true)
+}
+
+// Brings the database up to date: creates it if needed, installs the design docs and seeds the settings.
+// Running it again on a database that's already set up changes nothing.
+pub fn configure_database() -> StorageResult<DatabaseSetup> {
+    let database_created = ensure_database()?;
+    let design_docs = install_design_docs()?;
+    let settings_created = seed_settings()?;
+    Ok(DatabaseSetup {
+	database_created,
+	design_docs,
+	settings_created,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn ids_are_encoded_as_single_path_segments() {
+	let url = db_url(&["H&E 2/3 #1?"]).unwrap();
+	assert_eq!(url.as_str(), format!("{}/H&E%202%2F3%20%231%3F", COUCHDB_URL));
+	assert_eq!(db_url(&["_design", "procedures"]).unwrap().as_str(), format!("{}/_design/procedures", COUCHDB_URL));
+    }
+
+    #[test]
+    fn design_docs_are_only_rewritten_when_their_views_change() {
+	let doc = design_docs().remove(0);
+	let mut installed = serde_json::to_value(&doc).unwrap();
+	installed["_rev"] = json!("3-abc");
+	assert!(up_to_date(&installed, &doc));
+
+	// a database set up before the runs view was added
+	installed["views"].as_object_mut().unwrap().remove("runs");
+	assert!(!up_to_date(&installed, &doc));
+    }
+
+    #[test]
+    fn run_counts_add_to_the_runs_counted_before_run_history() {
+	let grouped : ReducedViewResult<String, i32> = serde_json::from_value(json!({
+	    "rows": [{"key": "h-and-e", "value": 4}, {"key": "pap", "value": 1}],
+	})).unwrap();
+	let run_counts : HashMap<String, i32> = grouped.rows.into_iter().map(|row| (row.key, row.value)).collect();
+
+	let mut proc : Procedure = serde_json::from_value(json!({
+	    "_id": "h-and-e",
+	    "_rev": "7-b",
+	    "type": "procedure",
+	    "name": "H&E",
+	    "jar_contents": [],
+	    "procedure_steps": [],
+	    "runs": 10,
+	})).unwrap();
+	fill_run_count(&mut proc, &run_counts);
+	assert_eq!(proc.runs, Some(14));
+
+	// only the old count is written back, so saving doesn't count the run docs twice
+	let saved = serde_json::to_value(&proc).unwrap();
+	assert_eq!(saved["runs"], json!(10));
+
+	proc.id = "never-run".to_string();
+	proc.runs_before_run_history = None;
+	fill_run_count(&mut proc, &run_counts);
+	assert_eq!(proc.runs, Some(0));
+    }
+
+    #[test]
+    fn restored_docs_replace_current_revs_but_not_versions() {
+	let current_revs : HashMap<String, String> = vec![
+	    ("settings".to_string(), "4-c".to_string()),
+	    ("pap-v1".to_string(), "1-d".to_string()),
+	].into_iter().collect();
+	let (docs, skipped) = docs_to_restore(vec![
+	    json!({"_id": "settings", "_rev": "2-a"}),
+	    json!({"_id": "pap", "_rev": "9-e", "type": "procedure"}),
+	    json!({"_id": "pap-v1", "type": "procedure_version"}),
+	    json!({"_id": "pap-v2", "type": "procedure_version"}),
+	], &current_revs);
+
+	assert_eq!(docs, vec![
+	    json!({"_id": "settings", "_rev": "4-c"}),
+	    json!({"_id": "pap", "type": "procedure"}),
+	    json!({"_id": "pap-v2", "type": "procedure_version"}),
+	]);
+	assert_eq!(skipped.len(), 1);
+	assert_eq!(skipped[0].id, "pap-v1");
+    }
+}
@@ -0,0 +1,369 @@
+pub use crate::structs_and_consts::*;
+pub use crate::couchdb::*;
+pub use crate::procedure_versions::*;
+pub use crate::validation::*;
+
+use juniper::FieldResult;
+use serde::*;
+
+// Builds a procedure from a spreadsheet exported as CSV, with one row per step. Which column holds what is
+// configurable since every lab lays its spreadsheets out differently. Columns are matched by header, ignoring case.
+
+#[derive(juniper::GraphQLEnum, Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[graphql(description="How times are written in a CSV import. Times written as mm:ss (or h:mm:ss) are always read that way.")]
+pub enum CsvTimeUnit {
+    Seconds,
+    #[graphql(description="Decimal minutes, e.g. 2.5.")]
+    Minutes,
+    #[graphql(description="Minutes and seconds, e.g. 2:30.")]
+    MinutesSeconds,
+}
+
+#[derive(juniper::GraphQLInputObject, Debug, Clone, Default, Serialize, Deserialize)]
+#[graphql(description="Which CSV columns hold which parts of a step. Any column left out uses its default header.")]
+pub struct CsvColumnMappingInputObject {
+    #[graphql(description="Header of the step number column, used to order the steps. Defaults to step. Rows are taken in file order if there's no such column.")]
+    pub step_column: Option<String>,
+    #[graphql(description="Header of the reagent column. Defaults to reagent.")]
+    pub reagent_column: Option<String>,
+    #[graphql(description="Header of the jar number column. Defaults to jar. If there's no such column, each reagent is given the next free jar.")]
+    pub jar_column: Option<String>,
+    #[graphql(description="Header of the time column. Defaults to minutes.")]
+    pub time_column: Option<String>,
+    #[graphql(description="How the time column is written. Defaults to Minutes.")]
+    pub time_unit: Option<CsvTimeUnit>,
+    #[graphql(description="Header of the dips column, used for rows without a time. Defaults to dips.")]
+    pub dips_column: Option<String>,
+    #[graphql(description="Seconds of immersion a dip is counted as. Defaults to 2.")]
+    pub seconds_per_dip: Option<i32>,
+}
+
+#[derive(juniper::GraphQLObject, Debug, Clone, Serialize, Deserialize)]
+#[graphql(description="A CSV row that couldn't be turned into a step.")]
+pub struct CsvRowError {
+    #[graphql(description="Line number in the file, counting the header as line 1.")]
+    pub line: i32,
+    pub message: String,
+}
+
+#[derive(juniper::GraphQLObject, Debug, Clone, Serialize, Deserialize)]
+#[graphql(description="The procedure a CSV file would be imported as.")]
+pub struct CsvImportPreview {
+    pub name: String,
+    pub jar_contents: Vec<String>,
+    pub procedure_steps: Vec<ProcedureStep>,
+    pub repeat: Option<i32>,
+
+    #[graphql(description="Rows that couldn't be read. The procedure can't be saved while there are any.")]
+    pub row_errors: Vec<CsvRowError>,
+
+    #[graphql(description="Notes about how the file was read, e.g. which jars reagents were given.")]
+    pub notes: Vec<String>,
+
+    pub validation: ProcedureValidation,
+}
+
+struct Columns {
+    step: Option<usize>,
+    reagent: usize,
+    jar: Option<usize>,
+    time: Option<usize>,
+    dips: Option<usize>,
+}
+
+fn find_column(headers: &csv::StringRecord, name: &str) -> Option<usize> {
+    headers.iter().position(|header| header.trim().eq_ignore_ascii_case(name.trim()))
+}
+
+fn field(record: &csv::StringRecord, column: Option<usize>) -> &str {
+    column.and_then(|i| record.get(i)).unwrap_or("").trim()
+}
+
+// Reads a time in seconds. mm:ss and h:mm:ss are recognized whatever the unit.
+pub fn parse_time(value: &str, unit: CsvTimeUnit) -> Result<i32, String> {
+    if value.contains(':') {
+	let mut seconds = 0.0;
+	for part in value.split(':') {
+	    let n : f64 = part.trim().parse().map_err(|_| format!("{} isn't a time like 2:30.", value))?;
+	    seconds = seconds * 60.0 + n;
+	}
+	return Ok(seconds.round() as i32);
+    }
+    let n : f64 = value.parse().map_err(|_| format!("{} isn't a number.", value))?;
+    match unit {
+	CsvTimeUnit::Seconds => Ok(n.round() as i32),
+	// whole minutes written without a colon
+	CsvTimeUnit::Minutes | CsvTimeUnit::MinutesSeconds => Ok((n * 60.0).round() as i32),
+    }
+}
+
+struct CsvStep {
+    order: f64,
+    line: i32,
+    reagent: String,
+    jar_number: Option<i32>,
+    time_in_seconds: i32,
+}
+
+pub fn preview_csv_procedure(csv_text: &str, mapping: &CsvColumnMappingInputObject, name: String, repeat: Option<i32>) -> FieldResult<CsvImportPreview> {
+    let mut reader = csv::ReaderBuilder::new()
+	.flexible(true)
+	.trim(csv::Trim::All)
+	.from_reader(csv_text.as_bytes());
+    let headers = match reader.headers() {
+	Ok(headers) => headers.clone(),
+	Err(e) => return juniper_err(format!("Couldn't read the CSV header row: {}", e)),
+    };
+
+    let reagent_header = mapping.reagent_column.as_deref().unwrap_or("reagent");
+    let time_header = mapping.time_column.as_deref().unwrap_or("minutes");
+    let dips_header = mapping.dips_column.as_deref().unwrap_or("dips");
+    let columns = Columns {
+	step: find_column(&headers, mapping.step_column.as_deref().unwrap_or("step")),
+	reagent: match find_column(&headers, reagent_header) {
+	    Some(i) => i,
+	    None => return juniper_err(format!("The CSV has no {} column.", reagent_header)),
+	},
+	jar: find_column(&headers, mapping.jar_column.as_deref().unwrap_or("jar")),
+	time: find_column(&headers, time_header),
+	dips: find_column(&headers, dips_header),
+    };
+    if columns.time.is_none() && columns.dips.is_none() {
+	return juniper_err(format!("The CSV needs a {} or a {} column.", time_header, dips_header));
+    }
+    let time_unit = mapping.time_unit.unwrap_or(CsvTimeUnit::Minutes);
+    let seconds_per_dip = mapping.seconds_per_dip.unwrap_or(2);
+    if seconds_per_dip < 1 {
+	return juniper_err(format!("A dip has to count as at least 1 second, not {}.", seconds_per_dip));
+    }
+
+    let mut row_errors : Vec<CsvRowError> = Vec::new();
+    let mut notes : Vec<String> = Vec::new();
+    let mut steps : Vec<CsvStep> = Vec::new();
+    for (index, record) in reader.records().enumerate() {
+	let line = index as i32 + 2; // the header is line 1
+	let record = match record {
+	    Ok(record) => record,
+	    Err(e) => {
+		row_errors.push(CsvRowError { line, message: format!("Couldn't read the row: {}", e) });
+		continue;
+	    }
+	};
+	if record.iter().all(|value| value.trim().is_empty()) {
+	    continue;
+	}
+
+	let reagent = field(&record, Some(columns.reagent)).to_string();
+	if reagent.is_empty() {
+	    row_errors.push(CsvRowError { line, message: "The row has no reagent.".to_string() });
+	    continue;
+	}
+	let order = match field(&record, columns.step) {
+	    "" => index as f64,
+	    value => match value.parse::<f64>() {
+		Ok(order) => order,
+		Err(_) => {
+		    row_errors.push(CsvRowError { line, message: format!("Step {} isn't a number.", value) });
+		    continue;
+		}
+	    },
+	};
+	let jar_number = match field(&record, columns.jar) {
+	    "" => None,
+	    value => match value.parse::<i32>() {
+		Ok(jar_number) => Some(jar_number),
+		Err(_) => {
+		    row_errors.push(CsvRowError { line, message: format!("Jar {} isn't a whole number.", value) });
+		    continue;
+		}
+	    },
+	};
+	let time_in_seconds = match (field(&record, columns.time), field(&record, columns.dips)) {
+	    ("", "") => Err("The row has neither a time nor a number of dips.".to_string()),
+	    ("", dips) => match dips.parse::<i32>() {
+		Ok(n) => n.checked_mul(seconds_per_dip).ok_or_else(|| format!("{} dips is too many.", dips)),
+		Err(_) => Err(format!("{} dips isn't a whole number.", dips)),
+	    },
+	    (time, _) => parse_time(time, time_unit),
+	};
+	let time_in_seconds = match time_in_seconds {
+	    Ok(time_in_seconds) => time_in_seconds,
+	    Err(message) => {
+		row_errors.push(CsvRowError { line, message });
+		continue;
+	    }
+	};
+	if field(&record, columns.time).is_empty() {
+	    notes.push(format!("Line {}: {} dips counted as {} seconds.", line, field(&record, columns.dips), time_in_seconds));
+	}
+	steps.push(CsvStep { order, line, reagent, jar_number, time_in_seconds });
+    }
+    // stable, so rows with the same step number keep their file order
+    steps.sort_by(|a, b| a.order.partial_cmp(&b.order).unwrap_or(std::cmp::Ordering::Equal));
+
+    // Jars given in the file are filled in first, then reagents without a jar get the jar already holding them or the next free one.
+    let mut jar_contents : Vec<String> = vec!["".to_string(); NUMBER_OF_JARS as usize];
+    for step in steps.iter() {
+	if let Some(jar_number) = step.jar_number {
+	    if jar_number < 1 || jar_number > NUMBER_OF_JARS {
+		continue; // reported by validation
+	    }
+	    let contents = &mut jar_contents[(jar_number - 1) as usize];
+	    if contents.is_empty() {
+		*contents = step.reagent.clone();
+	    } else if !contents.eq_ignore_ascii_case(&step.reagent) {
+		row_errors.push(CsvRowError { line: step.line,
+					      message: format!("Jar {} already holds {}, so it can't hold {} too.", jar_number, contents, step.reagent) });
+	    }
+	}
+    }
+    for step in steps.iter_mut().filter(|step| step.jar_number.is_none()) {
+	let jar_index = match jar_contents.iter().position(|contents| contents.eq_ignore_ascii_case(&step.reagent)) {
+	    Some(jar_index) => jar_index,
+	    None => match jar_contents.iter().position(|contents| contents.is_empty()) {
+		Some(jar_index) => {
+		    jar_contents[jar_index] = step.reagent.clone();
+		    notes.push(format!("{} was put in jar {}.", step.reagent, jar_index + 1));
+		    jar_index
+		}
+		None => {
+		    row_errors.push(CsvRowError { line: step.line,
+						  message: format!("There's no free jar left for {}; the rack only has {} jars.", step.reagent, NUMBER_OF_JARS) });
+		    continue;
+		}
+	    },
+	};
+	step.jar_number = Some(jar_index as i32 + 1);
+    }
+    while jar_contents.last().map(|contents| contents.is_empty()).unwrap_or(false) {
+	jar_contents.pop();
+    }
+
+    let procedure_steps : Vec<ProcedureStep> = steps.iter()
+	.filter_map(|step| step.jar_number.map(|jar_number| ProcedureStep {
+	    substance: step.reagent.clone(),
+	    time_in_seconds: step.time_in_seconds,
+	    jar_number,
+	    step_type: StepType::Immerse,
+	    operator_message: None,
+	}))
+	.collect();
+    row_errors.sort_by_key(|error| error.line);
+
+    let mut preview = CsvImportPreview {
+	name,
+	jar_contents,
+	procedure_steps,
+	repeat,
+	row_errors,
+	notes,
+	validation: ProcedureValidation { valid: true, issues: Vec::new() },
+    };
+    preview.validation = validate_procedure(&preview_input_object(&preview));
+    Ok(preview)
+}
+
+pub fn preview_input_object(preview: &CsvImportPreview) -> ProcedureInputObject {
+    ProcedureInputObject {
+	id: None,
+	rev: None,
+	type_: "procedure".to_string(),
+	name: preview.name.clone(),
+	jar_contents: preview.jar_contents.clone(),
+	procedure_steps: preview.procedure_steps.iter().cloned().map(ProcedureStepInputObject::from).collect(),
+	step_groups: None,
+	repeat: preview.repeat,
+	runs: None,
+	version: None,
+    }
+}
+
+// Saves the procedure previewed from a CSV file, as long as every row could be read and it's valid.
+pub fn import_csv_procedure(store: &dyn ProcedureStore, csv_text: &str, mapping: &CsvColumnMappingInputObject, name: String, repeat: Option<i32>) -> FieldResult<Procedure> {
+    let preview = preview_csv_procedure(csv_text, mapping, name, repeat)?;
+    if !preview.row_errors.is_empty() {
+	let messages : Vec<String> = preview.row_errors.iter().map(|error| format!("Line {}: {}", error.line, error.message)).collect();
+	return juniper_err(format!("Some rows of the CSV couldn't be read. {}", messages.join(" ")));
+    }
+    if !preview.validation.valid {
+	return validation_err(&preview.validation);
+    }
+    Ok(save_procedure_version(store, preview_input_object(&preview))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_store::MemoryStore;
+
+    const HE_CSV : &str = "step,reagent,minutes,dips\n\
+			   1,Hematoxylin,2.5,\n\
+			   3,Eosin,1:30,\n\
+			   2,Water,,10\n\
+			   4,Water,0.5,\n";
+
+    #[test]
+    fn times_can_be_numbers_or_clock_times() {
+	assert_eq!(parse_time("2.5", CsvTimeUnit::Minutes), Ok(150));
+	assert_eq!(parse_time("90", CsvTimeUnit::Seconds), Ok(90));
+	assert_eq!(parse_time("1:30", CsvTimeUnit::Seconds), Ok(90));
+	assert_eq!(parse_time("1:02:03", CsvTimeUnit::Minutes), Ok(3723));
+	assert!(parse_time("1:xx", CsvTimeUnit::MinutesSeconds).is_err());
+	assert!(parse_time("", CsvTimeUnit::Minutes).is_err());
+    }
+
+    #[test]
+    fn preview_reads_times_dips_and_step_order() {
+	let preview = preview_csv_procedure(HE_CSV, &CsvColumnMappingInputObject::default(), "H&E".to_string(), None).unwrap();
+	assert!(preview.row_errors.is_empty(), "{:?}", preview.row_errors);
+	assert!(preview.validation.valid, "{:?}", preview.validation.issues);
+	let steps : Vec<(i32, i32)> = preview.procedure_steps.iter().map(|step| (step.jar_number, step.time_in_seconds)).collect();
+	// reagents without a jar get the next free one, or the one already holding them
+	assert_eq!(steps, vec![(1, 150), (2, 20), (3, 90), (2, 30)]);
+	assert_eq!(preview.jar_contents, vec!["Hematoxylin", "Water", "Eosin"]);
+    }
+
+    #[test]
+    fn preview_reports_bad_rows_by_line() {
+	let csv = "Reagent,Time,Jar\nHematoxylin,two,1\n,1:00,2\nEosin,1:00,first\nWater,0:20,2\n";
+	let mapping = CsvColumnMappingInputObject {
+	    time_column: Some("time".to_string()),
+	    time_unit: Some(CsvTimeUnit::MinutesSeconds),
+	    ..CsvColumnMappingInputObject::default()
+	};
+	let preview = preview_csv_procedure(csv, &mapping, "H&E".to_string(), None).unwrap();
+	let lines : Vec<i32> = preview.row_errors.iter().map(|error| error.line).collect();
+	assert_eq!(lines, vec![2, 3, 4]);
+	assert_eq!(preview.procedure_steps.len(), 1);
+	assert_eq!(preview.procedure_steps[0].jar_number, 2);
+    }
+
+    #[test]
+    fn dips_have_to_fit_in_a_step_time() {
+	let csv = "reagent,dips\nXylene,2000000000\nAlcohol,12\n";
+	let preview = preview_csv_procedure(csv, &CsvColumnMappingInputObject::default(), "Clearing".to_string(), None).unwrap();
+	assert_eq!(preview.row_errors.len(), 1);
+	assert_eq!(preview.row_errors[0].line, 2);
+	assert!(preview.row_errors[0].message.contains("too many"), "{}", preview.row_errors[0].message);
+	assert_eq!(preview.procedure_steps[0].time_in_seconds, 24);
+
+	let mapping = CsvColumnMappingInputObject { seconds_per_dip: Some(-1), ..CsvColumnMappingInputObject::default() };
+	assert!(preview_csv_procedure(csv, &mapping, "Clearing".to_string(), None).is_err());
+    }
+
+    #[test]
+    fn a_reagent_column_and_a_time_column_are_needed() {
+	assert!(preview_csv_procedure("step,minutes\n1,2\n", &CsvColumnMappingInputObject::default(), "A".to_string(), None).is_err());
+	assert!(preview_csv_procedure("reagent,jar\nWater,1\n", &CsvColumnMappingInputObject::default(), "A".to_string(), None).is_err());
+    }
+
+    #[test]
+    fn import_saves_the_previewed_procedure() {
+	let store = MemoryStore::new();
+	let saved = import_csv_procedure(&store, HE_CSV, &CsvColumnMappingInputObject::default(), "H&E".to_string(), Some(2)).unwrap();
+	assert_eq!((saved.version, saved.repeat), (Some(1), Some(2)));
+	let stored = store.procedure_by_id(saved.id).unwrap();
+	assert_eq!(stored.procedure_steps.len(), 4);
+	assert_eq!(stored.procedure_steps[3].substance, "Water");
+    }
+}
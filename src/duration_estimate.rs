@@ -0,0 +1,187 @@
+pub use crate::structs_and_consts::*;
+pub use crate::motion::*;
+pub use crate::step_groups::*;
+
+// Estimates of how long a procedure run takes. Travel times come from the same motion profile that move_steps uses,
+// with the rack assumed to follow the path move_to_jar takes: up, across to the jar, then down. Operator action steps
+// follow move_over_jar instead, which stops over the jar.
+
+// The travel between the up and down positions on the z-axis.
+fn estimate_raise_only_seconds(settings: &MotionSettings) -> f64 {
+    estimate_move_seconds(settings.up_position - settings.down_position, &AxisDirection::Z, settings)
+}
+
+// Where the rack is between steps. execute_procedure tracks this as it runs, so that the estimates follow the same path.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RackPosition {
+    Homed, // up at the left position
+    Over(i32), // up over a jar, after an operator action step
+    In(i32), // down in a jar
+}
+
+// Where the rack is left once the step's move is done. Operator action steps hold it up over their jar (see move_over_jar).
+pub fn rack_after_step(step: &ProcedureStep) -> RackPosition {
+    match step.step_type {
+	StepType::Immerse => RackPosition::In(step.jar_number),
+	StepType::OperatorAction => RackPosition::Over(step.jar_number),
+    }
+}
+
+// Predicts the travel time to get to a step's jar from where the rack is.
+pub fn estimate_travel_seconds(settings: &MotionSettings, from: RackPosition, step: &ProcedureStep) -> f64 {
+    let lower = if step.step_type == StepType::Immerse { estimate_raise_only_seconds(settings) } else { 0.0 };
+    let jar_number = step.jar_number;
+    match from {
+	// move_to_jar doesn't raise the rack if it's already at the right jar, and it's already down
+	RackPosition::In(prev) if prev == jar_number => {
+	    if step.step_type == StepType::Immerse { 0.0 } else { estimate_raise_only_seconds(settings) }
+	}
+	RackPosition::In(prev) => {
+	    estimate_raise_only_seconds(settings)
+		+ settings.drain_between_steps_seconds.max(0) as f64
+		+ estimate_move_seconds(jar_position(jar_number) - jar_position(prev), &AxisDirection::X, settings)
+		+ lower
+	}
+	RackPosition::Over(prev) => {
+	    estimate_move_seconds(jar_position(jar_number) - jar_position(prev), &AxisDirection::X, settings) + lower
+	}
+	RackPosition::Homed => {
+	    estimate_move_seconds(jar_position(jar_number) - LEFT_POSITION, &AxisDirection::X, settings) + lower
+	}
+    }
+}
+
+// Predicts the time to raise the rack out of the last jar at the end of a run and let it drain.
+pub fn estimate_raise_seconds(settings: &MotionSettings) -> f64 {
+    estimate_raise_only_seconds(settings) + settings.drain_seconds.max(0) as f64
+}
+
+// Predicts the end of a run from where the rack is. A rack that's already up only has to drain.
+fn estimate_finish_seconds(settings: &MotionSettings, rack: RackPosition) -> f64 {
+    match rack {
+	RackPosition::In(_) => estimate_raise_seconds(settings),
+	RackPosition::Over(_) => settings.drain_seconds.max(0) as f64,
+	RackPosition::Homed => 0.0,
+    }
+}
+
+pub fn number_of_cycles(proc: &Procedure) -> i32 {
+    match proc.repeat {
+	Some(v) => v,
+	None => 1
+    }
+}
+
+fn sequenced_rack(proc: &Procedure, sequenced: &SequencedStep) -> RackPosition {
+    rack_after_step(&proc.procedure_steps[sequenced.step_index])
+}
+
+// Where the rack is before the given (zero-indexed) position in the step sequence (see step_sequence)
+// of the given (zero-indexed) cycle runs.
+pub fn rack_before_step(proc: &Procedure, sequence: &[SequencedStep], cycle_index: i32, sequence_index: usize) -> RackPosition {
+    if sequence_index > 0 {
+	if let Some(sequenced) = sequence.get(sequence_index - 1) {
+	    return sequenced_rack(proc, sequenced);
+	}
+    }
+    if cycle_index > 0 {
+	if let Some(sequenced) = sequence.last() {
+	    return sequenced_rack(proc, sequenced);
+	}
+    }
+    RackPosition::Homed
+}
+
+// Sums the travel and immersion times through one cycle from the given position in the step sequence.
+// Returns the time and where the rack ends up.
+fn estimate_cycle_seconds(settings: &MotionSettings, proc: &Procedure, sequence: &[SequencedStep], sequence_index: usize,
+			  from: RackPosition) -> (f64, RackPosition) {
+    let mut seconds = 0.0;
+    let mut rack = from;
+    for sequenced in sequence.iter().skip(sequence_index) {
+	let step = &proc.procedure_steps[sequenced.step_index];
+	seconds += estimate_travel_seconds(settings, rack, step);
+	// how long an operator takes can't be predicted, so only the travel over their jar is counted
+	if step.step_type == StepType::Immerse {
+	    seconds += step.time_in_seconds.max(0) as f64;
+	}
+	rack = rack_after_step(step);
+    }
+    (seconds, rack)
+}
+
+// Sums the travel and immersion times from the given (zero-indexed) cycle and position in the step sequence to the
+// end of the run, including raising the rack out of the last jar. from is where the rack is before that step.
+pub fn estimate_seconds_from_step(settings: &MotionSettings, proc: &Procedure, sequence: &[SequencedStep],
+				  cycle_index: i32, sequence_index: usize, from: RackPosition) -> f64 {
+    let cycles_left = (number_of_cycles(proc) - cycle_index).max(0);
+    let (mut seconds, mut rack) = if cycles_left > 0 {
+	estimate_cycle_seconds(settings, proc, sequence, sequence_index, from)
+    } else {
+	(0.0, from)
+    };
+    if cycles_left > 1 {
+	// the first full cycle may start from a different jar, but every one after it starts from the sequence's last jar
+	let (first_cycle_seconds, rack_after_cycle) = estimate_cycle_seconds(settings, proc, sequence, 0, rack);
+	seconds += first_cycle_seconds;
+	rack = rack_after_cycle;
+	if cycles_left > 2 {
+	    let (cycle_seconds, _) = estimate_cycle_seconds(settings, proc, sequence, 0, rack);
+	    seconds += cycle_seconds * (cycles_left - 2) as f64;
+	}
+    }
+    seconds + estimate_finish_seconds(settings, rack)
+}
+
+// Predicts how long a whole run of the procedure takes, starting with the rack homed.
+pub fn estimate_procedure_duration_seconds(settings: &MotionSettings, proc: &Procedure) -> f64 {
+    estimate_seconds_from_step(settings, proc, &step_sequence(proc), 0, 0, RackPosition::Homed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn operator_steps_are_estimated_along_the_path_the_runner_takes() {
+	let proc : Procedure = serde_json::from_value(json!({
+	    "_id": "trichrome",
+	    "_rev": "1-a",
+	    "type": "procedure",
+	    "name": "Masson's trichrome",
+	    "jar_contents": ["Bouin's", "Weigert's hematoxylin", "Biebrich scarlet"],
+	    "procedure_steps": [
+		{"time_in_seconds": 60, "jar_number": 1},
+		{"time_in_seconds": 0, "jar_number": 1, "step_type": "OperatorAction", "operator_message": "Top up jar 1"},
+		{"time_in_seconds": 30, "jar_number": 2},
+		{"time_in_seconds": 0, "jar_number": 3, "step_type": "OperatorAction", "operator_message": "Swap in fresh scarlet"},
+	    ],
+	    "repeat": 2,
+	})).unwrap();
+	let settings = MotionSettings { drain_seconds: 20, drain_between_steps_seconds: 5, ..MotionSettings::default() };
+	let z = estimate_move_seconds(settings.up_position - settings.down_position, &AxisDirection::Z, &settings);
+	let x = |from: Length, to: i32| estimate_move_seconds(jar_position(to) - from, &AxisDirection::X, &settings);
+
+	// execute_procedure's moves: move_to_jar lowers the rack, move_over_jar leaves it up, and it only drains
+	// between jars when leaving one the slides were down in
+	let first_cycle = x(LEFT_POSITION, 1) + z + 60.0
+	    + z
+	    + x(jar_position(1), 2) + z + 30.0
+	    + z + 5.0 + x(jar_position(2), 3);
+	let second_cycle = x(jar_position(3), 1) + z + 60.0
+	    + z
+	    + x(jar_position(1), 2) + z + 30.0
+	    + z + 5.0 + x(jar_position(2), 3);
+	// the rack is already up over jar 3, so the run ends with the drain alone
+	let runner = first_cycle + second_cycle + 20.0;
+	let estimate = estimate_procedure_duration_seconds(&settings, &proc);
+	assert!((estimate - runner).abs() < 1e-9, "estimated {} s, the runner takes {} s", estimate, runner);
+
+	let sequence = step_sequence(&proc);
+	assert_eq!(rack_before_step(&proc, &sequence, 0, 0), RackPosition::Homed);
+	assert_eq!(rack_before_step(&proc, &sequence, 0, 2), RackPosition::Over(1));
+	assert_eq!(rack_before_step(&proc, &sequence, 1, 0), RackPosition::Over(3));
+	assert_eq!(rack_before_step(&proc, &sequence, 0, 3), RackPosition::In(2));
+    }
+}
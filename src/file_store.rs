@@ -0,0 +1,291 @@
+pub use crate::structs_and_consts::*;
+pub use crate::storage::*;
+pub use crate::memory_store::*;
+
+use chrono::{DateTime, Utc};
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+// Keeps procedures, settings and the rack in a single JSON file, so the instrument can run without a CouchDB service.
+// Everything is held in a MemoryStore and the whole file is rewritten after every change. The new file is written
+// alongside the old one and then renamed over it, so losing power mid-write leaves the old file intact.
+
+pub struct FileStore {
+    path: PathBuf,
+    memory: MemoryStore,
+    write_lock: Mutex<()>, // held from a change until it's on disk, so an older snapshot can't overwrite a newer one
+}
+
+impl FileStore {
+    // Opens the data file, starting with no procedures if it doesn't exist yet.
+    pub fn open(path: PathBuf) -> Result<FileStore, String> {
+	let data = if path.exists() {
+	    let json = fs::read_to_string(&path).map_err(|e| format!("Couldn't read {}: {}", path.display(), e))?;
+	    let mut data = serde_json::from_str::<MemoryStoreData>(&json).map_err(|e| format!("Couldn't parse {}: {}", path.display(), e))?;
+	    // substances aren't written out, so fill them in from the jar contents like CouchDB loads do
+	    data.procedures.values_mut().for_each(reconcile_procedure);
+	    data
+	} else {
+	    MemoryStoreData::default()
+	};
+	Ok(FileStore::with_data(path, data))
+    }
+
+    pub fn with_data(path: PathBuf, data: MemoryStoreData) -> FileStore {
+	FileStore {
+	    path,
+	    memory: MemoryStore::from_data(data),
+	    write_lock: Mutex::new(()),
+	}
+    }
+
+    // Writes everything out to the data file.
+    pub fn save(&self) -> StorageResult<()> {
+	let _guard = self.write_lock.lock().unwrap();
+	self.write_file(&self.memory)
+    }
+
+    // Writes the given store's data out to the data file. Must be called with write_lock held.
+    fn write_file(&self, memory: &MemoryStore) -> StorageResult<()> {
+	let json = match memory.to_json() {
+	    Ok(json) => json,
+	    Err(e) => return Err(StorageError::Unexpected(format!("Couldn't serialize the data file: {}", e))),
+	};
+	let mut tmp_path = self.path.clone().into_os_string();
+	tmp_path.push(".tmp");
+	let tmp_path = PathBuf::from(tmp_path);
+	let written = fs::File::create(&tmp_path)
+	    .and_then(|mut file| {
+		file.write_all(json.as_bytes())?;
+		file.sync_all()
+	    })
+	    .and_then(|_| fs::rename(&tmp_path, &self.path));
+	if let Err(e) = written {
+	    return Err(StorageError::Unavailable(format!("Couldn't write {}: {}", self.path.display(), e)));
+	}
+	Ok(())
+    }
+
+    // Makes a change to a copy of the data and writes the copy to the file. Only once it's on disk does the copy
+    // replace what's in memory, so a failed write leaves memory matching the file.
+    fn change<T>(&self, change: impl FnOnce(&MemoryStore) -> StorageResult<T>) -> StorageResult<T> {
+	let _guard = self.write_lock.lock().unwrap();
+	let staged = MemoryStore::from_data(self.memory.data());
+	let result = change(&staged)?;
+	self.write_file(&staged)?;
+	self.memory.replace_data(staged.into_data());
+	Ok(result)
+    }
+}
+
+impl ProcedureStore for FileStore {
+    // Writes the data file if it hasn't been written yet, so a bad path is found at startup rather than on the first save.
+    fn configure(&self) -> StorageResult<DatabaseSetup> {
+	let _guard = self.write_lock.lock().unwrap();
+	let database_created = !self.path.exists();
+	if database_created {
+	    self.write_file(&self.memory)?;
+	}
+	Ok(DatabaseSetup {
+	    database_created,
+	    design_docs: Vec::new(),
+	    settings_created: database_created,
+	})
+    }
+
+    fn procedure_list(&self) -> StorageResult<ProcedureList> {
+	self.memory.procedure_list()
+    }
+
+    fn find_procedure(&self, id: String) -> StorageResult<Option<Procedure>> {
+	self.memory.find_procedure(id)
+    }
+
+    fn save_procedure(&self, procedure: ProcedureInputObject) -> StorageResult<Procedure> {
+	self.change(|memory| memory.save_procedure(procedure))
+    }
+
+    fn set_procedure_archived(&self, id: String, rev: String, archived_at: Option<DateTime<Utc>>) -> StorageResult<Procedure> {
+	self.change(|memory| memory.set_procedure_archived(id, rev, archived_at))
+    }
+
+    fn delete_procedure(&self, id: String, rev: String) -> StorageResult<()> {
+	self.change(|memory| memory.delete_procedure(id, rev))
+    }
+
+    fn procedure_versions(&self, procedure_id: String) -> StorageResult<Vec<ProcedureVersion>> {
+	self.memory.procedure_versions(procedure_id)
+    }
+
+    fn procedure_version(&self, procedure_id: String, version: i32) -> StorageResult<ProcedureVersion> {
+	self.memory.procedure_version(procedure_id, version)
+    }
+
+    fn create_procedure_version(&self, proc_version: &ProcedureVersion) -> StorageResult<()> {
+	self.change(|memory| memory.create_procedure_version(proc_version))
+    }
+
+    fn save_run_record(&self, run_record: &RunRecord) -> StorageResult<RunRecord> {
+	self.change(|memory| memory.save_run_record(run_record))
+    }
+}
+
+impl BackupStore for FileStore {
+    fn all_docs(&self) -> StorageResult<Vec<serde_json::Value>> {
+	self.memory.all_docs()
+    }
+
+    fn load_docs(&self, docs: Vec<serde_json::Value>) -> StorageResult<DocsLoaded> {
+	self.change(|memory| memory.load_docs(docs))
+    }
+}
+
+impl SettingsStore for FileStore {
+    fn settings(&self) -> StorageResult<Settings> {
+	self.memory.settings()
+    }
+
+    fn save_settings_doc(&self, settings: Settings) -> StorageResult<Settings> {
+	self.change(|memory| memory.save_settings_doc(settings))
+    }
+}
+
+impl RackStore for FileStore {
+    fn rack(&self) -> StorageResult<Rack> {
+	self.memory.rack()
+    }
+
+    fn save_rack_doc(&self, rack: Rack) -> StorageResult<Rack> {
+	self.change(|memory| memory.save_rack_doc(rack))
+    }
+
+    fn reagent_thresholds(&self) -> StorageResult<ReagentThresholds> {
+	self.memory.reagent_thresholds()
+    }
+
+    fn save_reagent_thresholds(&self, thresholds: ReagentThresholdsInputObject) -> StorageResult<ReagentThresholds> {
+	self.change(|memory| memory.save_reagent_thresholds(thresholds))
+    }
+}
+
+// Copies the procedures, their versions and run history, the settings, the rack and the reagent thresholds out of
+// CouchDB into a new data file. Refuses to overwrite an existing data file. Returns a summary of what was copied.
+pub fn migrate_from_couchdb(path: PathBuf) -> Result<String, String> {
+    if path.exists() {
+	return Err(format!("{} already exists. Move it out of the way to migrate again.", path.display()));
+    }
+    let docs = crate::couchdb::all_docs().map_err(|e| e.message().to_string())?;
+    let mut data = MemoryStoreData::default();
+    let mut skipped : Vec<String> = Vec::new();
+    for doc in docs {
+	let id = doc["_id"].as_str().unwrap_or("").to_string();
+	match data.load_doc(doc) {
+	    Ok(true) => {}
+	    Ok(false) => skipped.push(format!("{} (unknown type)", id)),
+	    Err(e) => skipped.push(format!("{} ({})", id, e)),
+	}
+    }
+
+    let summary = format!("Copied {} procedures, {} procedure versions and {} runs, plus the settings, rack and reagent thresholds, to {}.",
+			  data.procedures.len(), data.procedure_versions.len(), data.runs.len(), path.display());
+    FileStore::with_data(path, data).save().map_err(|e| e.message().to_string())?;
+    if skipped.is_empty() {
+	Ok(summary)
+    } else {
+	Ok(format!("{}\nSkipped {} docs: {}", summary, skipped.len(), skipped.join(", ")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    // A data file path that no other test uses, cleared of anything left by an earlier run.
+    fn scratch_file(name: &str) -> PathBuf {
+	let path = std::env::temp_dir().join(format!("openstainer-file-store-{}-{}.json", name, std::process::id()));
+	let _ = fs::remove_file(&path);
+	path
+    }
+
+    fn alcohol_series() -> ProcedureInputObject {
+	serde_json::from_value(json!({
+	    "type": "procedure",
+	    "name": "Alcohol series",
+	    "jar_contents": ["100% ethanol", "95% ethanol", "70% ethanol"],
+	    "procedure_steps": [
+		{"time_in_seconds": 60, "jar_number": 1},
+		{"time_in_seconds": 60, "jar_number": 2},
+		{"time_in_seconds": 60, "jar_number": 3}
+	    ]
+	})).unwrap()
+    }
+
+    #[test]
+    fn missing_files_start_empty() {
+	let path = scratch_file("missing");
+	let store = FileStore::open(path.clone()).unwrap();
+	assert!(store.procedures().unwrap().is_empty());
+	assert!(!path.exists());
+    }
+
+    #[test]
+    fn changes_survive_reopening_the_file() {
+	let path = scratch_file("reopen");
+	let saved = FileStore::open(path.clone()).unwrap().save_procedure(alcohol_series()).unwrap();
+
+	let reopened = FileStore::open(path.clone()).unwrap();
+	let loaded = reopened.procedure_by_id(saved.id.clone()).unwrap();
+	assert_eq!((loaded.rev.as_str(), loaded.name.as_str()), (saved.rev.as_str(), "Alcohol series"));
+	assert_eq!(loaded.procedure_steps[2].substance, "70% ethanol");
+	// new ids carry on from where the file left off
+	let another = reopened.save_procedure(alcohol_series()).unwrap();
+	assert_ne!(another.id, saved.id);
+	let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn refused_changes_leave_the_file_alone() {
+	let path = scratch_file("refused");
+	let store = FileStore::open(path.clone()).unwrap();
+	let saved = store.save_procedure(alcohol_series()).unwrap();
+	let before = fs::read_to_string(&path).unwrap();
+
+	let stale = ProcedureInputObject { rev: Some("0-local".to_string()), ..ProcedureInputObject::from(saved) };
+	assert!(store.save_procedure(stale).is_err());
+	assert_eq!(fs::read_to_string(&path).unwrap(), before);
+	let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn unreadable_files_are_reported() {
+	let path = scratch_file("unreadable");
+	fs::write(&path, "{ not json").unwrap();
+	let message = FileStore::open(path.clone()).err().unwrap();
+	assert!(message.starts_with("Couldn't parse"), "{}", message);
+	let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn failed_writes_leave_memory_matching_the_file() {
+	let dir = std::env::temp_dir().join(format!("openstainer-file-store-unwritable-{}", std::process::id()));
+	fs::create_dir_all(&dir).unwrap();
+	let store = FileStore::open(dir.join("data.json")).unwrap();
+	let saved = store.save_procedure(alcohol_series()).unwrap();
+
+	// pulling the USB stick the data file is on
+	fs::remove_dir_all(&dir).unwrap();
+	assert_eq!(store.save_procedure(alcohol_series()).unwrap_err().code(), "STORAGE_UNAVAILABLE");
+	let edit = ProcedureInputObject { name: "Alcohol series (short)".to_string(), ..ProcedureInputObject::from(saved.clone()) };
+	assert!(store.save_procedure(edit.clone()).is_err());
+	assert_eq!(store.procedures().unwrap().len(), 1);
+	assert_eq!(store.procedure_by_id(saved.id).unwrap().name, "Alcohol series");
+
+	// the edit's _rev is still current, so it saves once the file can be written again
+	fs::create_dir_all(&dir).unwrap();
+	assert_eq!(store.save_procedure(edit).unwrap().name, "Alcohol series (short)");
+	let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -1,82 +1,403 @@
 pub use crate::structs_and_consts::*;
+pub use crate::backup::*;
 pub use crate::motion::*;
 pub use crate::couchdb::*;
+pub use crate::storage::*;
+pub use crate::procedure_run::*;
+pub use crate::procedure_versions::*;
+pub use crate::procedure_transfer::*;
+pub use crate::csv_import::*;
+pub use crate::validation::*;
+pub use crate::reagent_usage::*;
 
+use chrono::{DateTime, Utc};
 use juniper::{FieldResult};
 use rocket::State;
 
 pub type Schema = juniper::RootNode<'static, Query, Mutation>;
 
+// The GraphQL context gives resolvers access to both the Pi and the procedure execution state.
+// The PES is needed separately since the Pi is locked for the duration of a move.
+// Procedures, settings and the rack are read and saved through the stores rather than by calling CouchDB directly.
+pub struct GraphQLContext {
+    pub pi: SharedPi,
+    pub pes: SharedProcedureExecutionState,
+    pub procedures: SharedProcedureStore,
+    pub settings: SharedSettingsStore,
+    pub racks: SharedRackStore,
+    pub backups: SharedBackupStore,
+    pub changes: SharedChangeTracker,
+}
+
+impl juniper::Context for GraphQLContext {}
+
+#[juniper::object(Context = GraphQLContext, description="A axis of motion on the device.")]
+impl Axis {
+    #[graphql(description="The position in inches, or \"Not homed\".")]
+    fn position_inches(&self) -> String {
+	match self.position {
+	    Some(position) => format!("{}", position.as_inches()),
+	    None => "Not homed".to_string(),
+	}
+    }
+
+    #[graphql(description="The position in millimetres, or \"Not homed\".")]
+    fn position_mm(&self) -> String {
+	match self.position {
+	    Some(position) => format!("{}", position.as_mm()),
+	    None => "Not homed".to_string(),
+	}
+    }
+
+    #[graphql(description="The position in the given unit, or the settings' unit if none is given. Nil until the axis is homed.")]
+    fn position(&self, unit: Option<LengthUnit>) -> Option<f64> {
+	self.position.map(|position| position.as_unit(unit.unwrap_or(self.display_unit)))
+    }
+
+    #[graphql(description="The unit lengths are shown in, from the settings.")]
+    fn display_unit(&self) -> LengthUnit {
+	self.display_unit
+    }
+}
+
+#[juniper::object(Context = GraphQLContext, description="A staining procedure")]
+impl Procedure {
+    #[graphql(name="_id", description="The _id of the procedure.")]
+    fn id(&self) -> &str {
+	&self.id
+    }
+
+    #[graphql(name="_rev", description="The CouchDB _rev of the procedure.")]
+    fn rev(&self) -> &str {
+	&self.rev
+    }
+
+    #[graphql(name="type", description="The CouchDB type of the procedure. Will always be :procedure.")]
+    fn type_(&self) -> &str {
+	&self.type_
+    }
+
+    #[graphql(description="Name of the procedure.")]
+    fn name(&self) -> &str {
+	&self.name
+    }
+
+    #[graphql(description="List of contents of what substanc is in jar")]
+    fn jar_contents(&self) -> &Vec<String> {
+	&self.jar_contents
+    }
+
+    #[graphql(description="A list of steps in the staining procedure.")]
+    fn procedure_steps(&self) -> &Vec<ProcedureStep> {
+	&self.procedure_steps
+    }
+
+    #[graphql(description="Runs of steps that are repeated within each cycle.")]
+    fn step_groups(&self) -> &Vec<StepGroup> {
+	&self.step_groups
+    }
+
+    #[graphql(description="Number of times to repeat a given procedure for a single run.")]
+    fn repeat(&self) -> Option<i32> {
+	self.repeat
+    }
+
+    #[graphql(description="Number of times this procedure has ever been run.")]
+    fn runs(&self) -> Option<i32> {
+	self.runs
+    }
+
+    #[graphql(description="Version number of the procedure. Goes up by one each time a change to the procedure is saved.")]
+    fn version(&self) -> i32 {
+	current_version(self)
+    }
+
+    #[graphql(description="Whether the procedure has been deleted. Deleted procedures are kept in the trash until purged.")]
+    fn archived(&self) -> bool {
+	self.archived_at.is_some()
+    }
+
+    #[graphql(description="When the procedure was deleted. Nil unless it's archived.")]
+    fn archived_at(&self) -> Option<DateTime<Utc>> {
+	self.archived_at
+    }
+
+    #[graphql(description="Estimated seconds a run of this procedure takes, including repeats and travel between jars.")]
+    fn estimated_duration_seconds(&self, context: &GraphQLContext) -> i32 {
+	estimate_procedure_duration_seconds(&context.pes.motion_settings.read().unwrap(), self).round() as i32
+    }
+}
+
+#[juniper::object(Context = GraphQLContext, description="The saved procedures, and any procedure docs that couldn't be read.")]
+impl ProcedureList {
+    fn procedures(&self) -> &Vec<Procedure> {
+	&self.procedures
+    }
+
+    #[graphql(description="Procedure docs left out because they couldn't be read, e.g. because they're malformed.")]
+    fn skipped(&self) -> &Vec<SkippedDocument> {
+	&self.skipped
+    }
+}
+
 pub struct Query;
-#[juniper::object(Context = SharedPi)]
+#[juniper::object(Context = GraphQLContext)]
 impl Query {
     fn apiVersion() -> &'static str {
         "1.0"
     }
 
-    fn settings() -> FieldResult<Settings> {
-	crate::couchdb::settings()
+    fn settings(context: &GraphQLContext) -> FieldResult<Settings> {
+	Ok(context.settings.settings()?)
     }
 
-    fn axis(context: &SharedPi, id: AxisDirection) -> FieldResult<Axis> {
-        let pi = &mut *context.lock().unwrap();
+    #[graphql(description="The jars currently loaded on the instrument.")]
+    fn rack(context: &GraphQLContext) -> FieldResult<Rack> {
+	Ok(context.racks.rack()?)
+    }
+
+    #[graphql(description="When each reagent should be replaced.")]
+    fn reagent_thresholds(context: &GraphQLContext) -> FieldResult<ReagentThresholds> {
+	Ok(context.racks.reagent_thresholds()?)
+    }
+
+    #[graphql(description="Jars whose reagent has reached one of its replacement thresholds.")]
+    fn reagent_alerts(context: &GraphQLContext) -> FieldResult<Vec<ReagentAlert>> {
+	crate::reagent_usage::reagent_alerts(&*context.racks)
+    }
+
+    #[graphql(description="Compares the jars a procedure uses with the jars loaded on the instrument, and warns about jars due for replacement.")]
+    fn check_rack(context: &GraphQLContext, procedure_id: String) -> FieldResult<ProcedureValidation> {
+	let proc = context.procedures.procedure_by_id(procedure_id)?;
+	let mut validation = crate::validation::check_rack(&proc, &context.racks.rack()?);
+	validation.issues.extend(check_reagent_alerts(&proc, &crate::reagent_usage::reagent_alerts(&*context.racks)?));
+	Ok(validation)
+    }
+
+    fn axis(context: &GraphQLContext, id: AxisDirection) -> FieldResult<Axis> {
+	// the position is still worth showing if the settings can't be read
+	let display_unit = context.settings.settings().map(|settings| settings.length_unit).unwrap_or_default();
+        let pi = &mut *context.pi.lock().unwrap();
 	let stepper = get_stepper( pi, &id );
-	let position_inches = match stepper.pos {
-             Some(v) => format!("{}", pulses_to_inches(v, &stepper)),
-             None => "Not homed".to_string(),
- 	};
-	let axis = Axis {position_inches: position_inches};
+	let axis = Axis {
+	    position: stepper.pos.map(|v| pulses_to_length(v, &stepper)),
+	    display_unit,
+	};
         
         Ok(axis)
     }
 
-    fn procedures() -> FieldResult<Vec<Procedure>> {
-	crate::couchdb::procedures()
+    #[graphql(description="The saved procedures. Procedures in the trash are left out unless includeArchived is true.")]
+    fn procedures(context: &GraphQLContext, include_archived: Option<bool>) -> FieldResult<Vec<Procedure>> {
+	if include_archived.unwrap_or(false) {
+	    Ok(context.procedures.procedure_list()?.procedures)
+	} else {
+	    Ok(context.procedures.procedures()?)
+	}
+    }
+
+    #[graphql(description="Like procedures, but also lists the procedure docs that couldn't be read.")]
+    fn procedure_list(context: &GraphQLContext, include_archived: Option<bool>) -> FieldResult<ProcedureList> {
+	let mut list = context.procedures.procedure_list()?;
+	if !include_archived.unwrap_or(false) {
+	    list.procedures.retain(|proc| proc.archived_at.is_none());
+	}
+	Ok(list)
     }
 
-    fn procedure_by_id(id: String) -> FieldResult<Procedure> {
-	procedure_by_id(id)
+    #[graphql(description="The procedures in the trash, most recently deleted first.")]
+    fn archived_procedures(context: &GraphQLContext) -> FieldResult<Vec<Procedure>> {
+	Ok(context.procedures.archived_procedures()?)
     }
 
-    fn current_procedure(shared_pi: &SharedPi) -> FieldResult<Option<Procedure>> {
-	let pi = &mut *shared_pi.lock().unwrap();
-	Ok(pi.current_procedure.clone())
+    fn procedure_by_id(context: &GraphQLContext, id: String) -> FieldResult<Procedure> {
+	Ok(context.procedures.procedure_by_id(id)?)
     }
 
-    fn run_status(shared_pi: &SharedPi) -> FieldResult<Option<ProcedureRunStatus>> {
-	let pi = &mut *shared_pi.lock().unwrap();
-	let run_status_opt = pi.run_status.clone();
-	if run_status_opt.is_none() {
-	    return Ok(None);
-	}
-	let run_status = run_status_opt.unwrap();
-	Ok(Some(run_status))
+    #[graphql(description="Every saved version of a procedure, oldest first.")]
+    fn procedure_versions(context: &GraphQLContext, procedure_id: String) -> FieldResult<Vec<ProcedureVersion>> {
+	Ok(context.procedures.procedure_versions(procedure_id)?)
+    }
+
+    #[graphql(description="A single saved version of a procedure.")]
+    fn procedure_version(context: &GraphQLContext, procedure_id: String, version: i32) -> FieldResult<ProcedureVersion> {
+	Ok(context.procedures.procedure_version(procedure_id, version)?)
+    }
+
+    #[graphql(description="Lists what changed in a procedure between two of its versions.")]
+    fn diff_procedure_versions(context: &GraphQLContext, procedure_id: String, from_version: i32, to_version: i32) -> FieldResult<Vec<ProcedureChange>> {
+	Ok(diff_saved_procedure_versions(&*context.procedures, procedure_id, from_version, to_version)?)
+    }
+
+    #[graphql(description="For an edit whose save failed with a CONFLICT error: what the edit and the saved procedure each changed since the version the edit started from (the edit's version field).")]
+    fn procedure_three_way_diff(context: &GraphQLContext, procedure: ProcedureInputObject) -> FieldResult<ProcedureThreeWayDiff> {
+	Ok(procedure_three_way_diff(&*context.procedures, &procedure)?)
+    }
+
+    #[graphql(description="The backup files in a directory under the backup root, e.g. a USB stick's mount point, newest first.")]
+    fn backup_files(directory: String) -> FieldResult<Vec<BackupFile>> {
+	backup_files(&backup_root(), std::path::Path::new(&directory))
+    }
+
+    #[graphql(description="Exports procedures as a JSON procedure export file, to be imported on another instrument with importProcedures. Exports every procedure if no ids are given.")]
+    fn export_procedures(context: &GraphQLContext, ids: Option<Vec<String>>) -> FieldResult<String> {
+	export_procedures_json(&*context.procedures, ids)
+    }
+
+    #[graphql(description="Shows the procedure a CSV file with one row per step would be imported as, without saving it.")]
+    fn preview_csv_procedure(csv: String, mapping: Option<CsvColumnMappingInputObject>, name: String, repeat: Option<i32>) -> FieldResult<CsvImportPreview> {
+	crate::csv_import::preview_csv_procedure(&csv, &mapping.unwrap_or_default(), name, repeat)
+    }
+
+    #[graphql(description="Checks a procedure for errors and warnings without saving it.")]
+    fn validate_procedure(procedure: ProcedureInputObject) -> FieldResult<ProcedureValidation> {
+	Ok(crate::validation::validate_procedure(&procedure))
+    }
+
+    fn current_procedure(context: &GraphQLContext) -> FieldResult<Option<Procedure>> {
+	Ok(context.pes.current_procedure.lock().unwrap().clone())
+    }
+
+    fn run_status(context: &GraphQLContext) -> FieldResult<Option<ProcedureRunStatus>> {
+	Ok(current_run_status(&context.pes))
     }
 }
 
 pub struct Mutation;
-#[juniper::object(Context = SharedPi)]
+#[juniper::object(Context = GraphQLContext)]
 impl Mutation {
-    fn save_procedure(procedure: ProcedureInputObject) -> FieldResult<Procedure> {
-	crate::couchdb::save_procedure_input_object(procedure)
+    fn save_procedure(context: &GraphQLContext, procedure: ProcedureInputObject) -> FieldResult<Procedure> {
+	let validation = crate::validation::validate_procedure(&procedure);
+	if !validation.valid {
+	    return validation_err(&validation);
+	}
+	Ok(save_procedure_version(&*context.procedures, procedure)?)
+    }
+
+    #[graphql(description="Imports the procedures in a JSON procedure export file (see exportProcedures). onConflict defaults to RENAME.")]
+    fn import_procedures(context: &GraphQLContext, json: String, on_conflict: Option<ImportConflictPolicy>) -> FieldResult<Vec<ImportedProcedure>> {
+	crate::procedure_transfer::import_procedures(&*context.procedures, &json, on_conflict.unwrap_or(ImportConflictPolicy::Rename))
+    }
+
+    #[graphql(description="Saves a procedure from a CSV file with one row per step. See previewCsvProcedure.")]
+    fn import_csv_procedure(context: &GraphQLContext, csv: String, mapping: Option<CsvColumnMappingInputObject>, name: String, repeat: Option<i32>) -> FieldResult<Procedure> {
+	crate::csv_import::import_csv_procedure(&*context.procedures, &csv, &mapping.unwrap_or_default(), name, repeat)
+    }
+
+    #[graphql(description="Sets up the database if it isn't already: creates it, installs or updates its views and saves default settings. Safe to run more than once.")]
+    fn configure_database(context: &GraphQLContext) -> FieldResult<DatabaseSetup> {
+	Ok(context.procedures.configure()?)
+    }
+
+    #[graphql(description="Writes a backup of the whole database to a new file under the backup root. If path is a directory, e.g. a USB stick's mount point, a file named for the time is made in it. Existing files aren't overwritten.")]
+    fn backup_database(context: &GraphQLContext, path: String) -> FieldResult<BackupFile> {
+	write_backup_file(&*context.backups, &backup_root(), std::path::Path::new(&path))
+    }
+
+    #[graphql(description="Checks a backup file and restores it. Documents in the backup replace those with the same _id; others are kept. Refused while a procedure is running.")]
+    fn restore_database(context: &GraphQLContext, path: String) -> FieldResult<RestoreSummary> {
+	let summary = while_restoring(&context.pes, || restore_backup_file(&*context.backups, &backup_root(), std::path::Path::new(&path)))??;
+	reload_motion_settings(&*context.settings, &context.pes.motion_settings);
+	Ok(summary)
+    }
+
+    #[graphql(description="Removes the substance stored on each step of existing procedures, since it is derived from jar_contents. Returns the procedures that were rewritten.")]
+    fn migrate_step_substances(context: &GraphQLContext) -> FieldResult<Vec<Procedure>> {
+	Ok(context.procedures.migrate_step_substances()?)
+    }
+
+    #[graphql(description="Moves the procedure to the trash (see archivedProcedures) and returns the procedures that are left. Use restoreProcedure to undo.")]
+    fn delete_procedure(context: &GraphQLContext, id: String, rev: String) -> FieldResult<Vec<Procedure>> {
+	context.procedures.archive_procedure(id, rev)?;
+	Ok(context.procedures.procedures()?)
+    }
+
+    #[graphql(description="Takes a procedure back out of the trash.")]
+    fn restore_procedure(context: &GraphQLContext, id: String, rev: String) -> FieldResult<Procedure> {
+	Ok(context.procedures.restore_procedure(id, rev)?)
+    }
+
+    #[graphql(description="Deletes a procedure in the trash for good, and returns the procedures left in the trash. Procedures the run history refers to can't be purged. Its saved versions are kept.")]
+    fn purge_procedure(context: &GraphQLContext, id: String, rev: String) -> FieldResult<Vec<Procedure>> {
+	context.procedures.purge_procedure(id, rev)?;
+	Ok(context.procedures.archived_procedures()?)
+    }
+
+    #[graphql(description="Saves the settings. Fields that are left out aren't changed. Motion settings take effect from the next move, except in a run that's underway, which keeps the ones it started with.")]
+    fn save_settings(context: &GraphQLContext, settings: SettingsInputObject) -> FieldResult<Settings> {
+	let settings = context.settings.settings()?.updated(settings);
+	validate_settings(&settings)?;
+	let saved = context.settings.save_settings_doc(settings)?;
+	*context.pes.motion_settings.write().unwrap() = MotionSettings::from(&saved);
+	Ok(saved)
+    }
+
+    #[graphql(description="Marks a jar as freshly filled, resetting its usage counters. The substance, lot number and volume are only changed if given.")]
+    fn refill_jar(context: &GraphQLContext, jar_number: i32, substance: Option<String>, lot_number: Option<String>, volume_ml: Option<f64>) -> FieldResult<Rack> {
+	crate::reagent_usage::refill_jar(&*context.racks, jar_number, substance, lot_number, volume_ml)
+    }
+
+    #[graphql(description="Saves when each reagent should be replaced.")]
+    fn save_reagent_thresholds(context: &GraphQLContext, thresholds: ReagentThresholdsInputObject) -> FieldResult<ReagentThresholds> {
+	Ok(context.racks.save_reagent_thresholds(thresholds)?)
+    }
+
+    #[graphql(description="Saves what is loaded in each jar on the instrument.")]
+    fn save_rack(context: &GraphQLContext, rack: RackInputObject) -> FieldResult<Rack> {
+	validate_rack(&rack)?;
+	Ok(context.racks.save_rack(rack)?)
+    }
+
+    #[graphql(description="Starts running the procedure with the given ID. Fails if a procedure is already running, or if the jars loaded in the rack don't match the procedure unless ignoreRackMismatch is true.")]
+    fn start_run(context: &GraphQLContext, procedure_id: String, expected_rev: Option<String>, ignore_rack_mismatch: Option<bool>, slide_count: Option<i32>) -> FieldResult<ProcedureRunStatus> {
+	crate::procedure_run::start_run(&context.pi, &context.pes, &context.procedures, &context.racks, &context.changes,
+					procedure_id, expected_rev, ignore_rack_mismatch.unwrap_or(false), slide_count)
+    }
+
+    #[graphql(description="Pauses the running procedure.")]
+    fn pause_run(context: &GraphQLContext) -> FieldResult<ProcedureRunStatus> {
+	crate::procedure_run::pause_run(&context.pes)
+    }
+
+    #[graphql(description="Resumes a paused procedure.")]
+    fn resume_run(context: &GraphQLContext) -> FieldResult<ProcedureRunStatus> {
+	crate::procedure_run::resume_run(&context.pes)
     }
 
-    fn delete_procedure(id: String, rev: String) -> FieldResult<Vec<Procedure>> {
-	crate::couchdb::delete_procedure(id,rev)
+    #[graphql(description="Tells a run waiting on an operator action step that the operator is done, as if the green button had been pressed.")]
+    fn confirm_operator_action(context: &GraphQLContext) -> FieldResult<ProcedureRunStatus> {
+	crate::procedure_run::confirm_operator_action(&context.pes)
     }
 
-    fn save_settings(settings: SettingsInputObject) -> FieldResult<Settings> {
-	crate::couchdb::save_settings(settings)
+    #[graphql(description="Stops the running procedure. The rack is raised before the run ends.")]
+    fn stop_run(context: &GraphQLContext) -> FieldResult<ProcedureRunStatus> {
+	crate::procedure_run::stop_run(&context.pes)
     }
 }
 
 #[rocket::post("/graphql", data = "<request>")]
+#[allow(clippy::too_many_arguments)] // Rocket hands over each piece of managed state as its own argument
 pub fn post_graphql_handler(
     pi_state: State<SharedPi>,
+    pes: State<SharedProcedureExecutionState>,
+    procedure_store: State<SharedProcedureStore>,
+    settings_store: State<SharedSettingsStore>,
+    rack_store: State<SharedRackStore>,
+    backup_store: State<SharedBackupStore>,
+    changes: State<SharedChangeTracker>,
     request: juniper_rocket::GraphQLRequest,
     schema: State<Schema>,
 ) -> juniper_rocket::GraphQLResponse {
-    request.execute(&schema, &pi_state)
+    let context = GraphQLContext {
+	pi: pi_state.inner().clone(),
+	pes: pes.inner().clone(),
+	procedures: procedure_store.inner().clone(),
+	settings: settings_store.inner().clone(),
+	racks: rack_store.inner().clone(),
+	backups: backup_store.inner().clone(),
+	changes: changes.inner().clone(),
+    };
+    request.execute(&schema, &context)
 }
 
 #[get("/graphiql")]
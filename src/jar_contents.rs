@@ -0,0 +1,90 @@
+pub use crate::structs_and_consts::*;
+
+// jar_contents is the single source of truth for what substance is in each jar.
+// Step substances are derived from it whenever a procedure is loaded or saved, and aren't stored in CouchDB.
+
+fn jar_index(jar_number: i32) -> Option<usize> {
+    if jar_number < 1 || jar_number > NUMBER_OF_JARS {
+	return None; // out of range jars are reported by validation
+    }
+    Some((jar_number - 1) as usize)
+}
+
+// Fills in a blank jar from a step's substance. Documents saved before steps stopped storing substances
+// (and clients that only send step substances) can have steps naming a substance the jar contents don't list.
+fn fill_blank_jar(jar_contents: &mut Vec<String>, jar_number: i32, substance: &str) {
+    let index = match jar_index(jar_number) {
+	Some(index) => index,
+	None => return,
+    };
+    if substance.trim().is_empty() {
+	return;
+    }
+    if jar_contents.len() <= index {
+	jar_contents.resize(index + 1, "".to_string());
+    }
+    if jar_contents[index].trim().is_empty() {
+	jar_contents[index] = substance.to_string();
+    }
+}
+
+// The substance in a jar according to jar_contents, or an empty string if none is listed.
+pub fn substance_in_jar(jar_contents: &[String], jar_number: i32) -> String {
+    jar_index(jar_number)
+	.and_then(|index| jar_contents.get(index))
+	.cloned()
+	.unwrap_or_default()
+}
+
+pub fn reconcile_procedure(proc: &mut Procedure) {
+    for step in proc.procedure_steps.iter() {
+	fill_blank_jar(&mut proc.jar_contents, step.jar_number, &step.substance);
+    }
+    for step in proc.procedure_steps.iter_mut() {
+	step.substance = substance_in_jar(&proc.jar_contents, step.jar_number);
+    }
+}
+
+pub fn reconcile_procedure_input_object(proc: &mut ProcedureInputObject) {
+    for step in proc.procedure_steps.iter() {
+	if let Some(substance) = &step.substance {
+	    fill_blank_jar(&mut proc.jar_contents, step.jar_number, substance);
+	}
+    }
+    for step in proc.procedure_steps.iter_mut() {
+	step.substance = Some(substance_in_jar(&proc.jar_contents, step.jar_number));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn substances_come_from_the_jar_contents() {
+	let jar_contents = vec!["Xylene".to_string(), "".to_string()];
+	assert_eq!(substance_in_jar(&jar_contents, 1), "Xylene");
+	assert_eq!(substance_in_jar(&jar_contents, 2), "");
+	assert_eq!(substance_in_jar(&jar_contents, 3), "");
+	assert_eq!(substance_in_jar(&jar_contents, 0), "");
+    }
+
+    #[test]
+    fn blank_jars_are_filled_from_old_step_substances() {
+	let mut proc : ProcedureInputObject = serde_json::from_value(serde_json::json!({
+	    "type": "procedure",
+	    "name": "Old document",
+	    "jar_contents": ["Xylene"],
+	    "procedure_steps": [
+		{"substance": "Ethanol", "time_in_seconds": 30, "jar_number": 1},
+		{"substance": "Water", "time_in_seconds": 30, "jar_number": 3},
+		{"time_in_seconds": 30, "jar_number": 1},
+	    ],
+	})).unwrap();
+	reconcile_procedure_input_object(&mut proc);
+	// jar 1 already had contents, so the step's substance gives way to them
+	assert_eq!(proc.jar_contents, vec!["Xylene", "", "Water"]);
+	let substances : Vec<Option<String>> = proc.procedure_steps.iter().map(|step| step.substance.clone()).collect();
+	assert_eq!(substances, vec![Some("Xylene".to_string()), Some("Water".to_string()), Some("Xylene".to_string())]);
+    }
+}
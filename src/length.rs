@@ -0,0 +1,138 @@
+use serde::*;
+use std::fmt;
+use std::ops::{Add, Div, Mul, Neg, Sub};
+
+// Lengths on the instrument. The lead screws are specified in inches, while the lab and the CAD drawings (cad/) work in
+// millimetres, so a length is a Length rather than a bare number: it's made with Length::inches or Length::mm and
+// read with as_inches or as_mm, so a number can't be taken to be in the wrong unit. Conversion to pulses happens in the
+// motion layer (see length_to_pulses in motion.rs).
+
+pub const MM_PER_INCH: f64 = 25.4;
+
+#[derive(juniper::GraphQLEnum, Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[graphql(description="A unit that lengths are shown in.")]
+pub enum LengthUnit {
+    Inches,
+    Millimeters,
+}
+
+impl Default for LengthUnit {
+    fn default() -> Self { LengthUnit::Inches }
+}
+
+impl LengthUnit {
+    pub fn abbreviation(&self) -> &'static str {
+	match self {
+	    LengthUnit::Inches => "in",
+	    LengthUnit::Millimeters => "mm",
+	}
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Default)]
+pub struct Length {
+    inches: f64, // kept in inches since that's what the steppers are configured in
+}
+
+impl Length {
+    pub const fn inches(inches: f64) -> Length {
+	Length { inches }
+    }
+
+    pub fn mm(mm: f64) -> Length {
+	Length { inches: mm / MM_PER_INCH }
+    }
+
+    pub fn in_unit(value: f64, unit: LengthUnit) -> Length {
+	match unit {
+	    LengthUnit::Inches => Length::inches(value),
+	    LengthUnit::Millimeters => Length::mm(value),
+	}
+    }
+
+    pub fn as_inches(self) -> f64 {
+	self.inches
+    }
+
+    pub fn as_mm(self) -> f64 {
+	self.inches * MM_PER_INCH
+    }
+
+    pub fn as_unit(self, unit: LengthUnit) -> f64 {
+	match unit {
+	    LengthUnit::Inches => self.as_inches(),
+	    LengthUnit::Millimeters => self.as_mm(),
+	}
+    }
+
+    pub fn abs(self) -> Length {
+	Length::inches(self.inches.abs())
+    }
+}
+
+impl Add for Length {
+    type Output = Length;
+    fn add(self, other: Length) -> Length { Length::inches(self.inches + other.inches) }
+}
+
+impl Sub for Length {
+    type Output = Length;
+    fn sub(self, other: Length) -> Length { Length::inches(self.inches - other.inches) }
+}
+
+impl Neg for Length {
+    type Output = Length;
+    fn neg(self) -> Length { Length::inches(-self.inches) }
+}
+
+impl Mul<f64> for Length {
+    type Output = Length;
+    fn mul(self, factor: f64) -> Length { Length::inches(self.inches * factor) }
+}
+
+impl Div<f64> for Length {
+    type Output = Length;
+    fn div(self, divisor: f64) -> Length { Length::inches(self.inches / divisor) }
+}
+
+// The ratio of two lengths, e.g. how many turns of a lead screw a distance is.
+impl Div for Length {
+    type Output = f64;
+    fn div(self, other: Length) -> f64 { self.inches / other.inches }
+}
+
+impl fmt::Display for Length {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+	write!(f, "{} in", self.inches)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f64, b: f64) -> bool {
+	(a - b).abs() < 1e-9
+    }
+
+    #[test]
+    fn lengths_convert_between_units() {
+	assert!(close(Length::inches(2.0).as_mm(), 50.8));
+	assert!(close(Length::mm(127.0).as_inches(), 5.0));
+	for &unit in [LengthUnit::Inches, LengthUnit::Millimeters].iter() {
+	    assert!(close(Length::in_unit(3.5, unit).as_unit(unit), 3.5));
+	}
+	assert_eq!(Length::in_unit(25.4, LengthUnit::Millimeters), Length::inches(1.0));
+    }
+
+    #[test]
+    fn arithmetic_keeps_the_unit() {
+	let travel = Length::mm(300.0) - Length::inches(2.0);
+	assert!(close(travel.as_mm(), 249.2));
+	assert!(close((-travel).abs().as_mm(), 249.2));
+	assert!(close((Length::inches(1.0) + Length::mm(25.4) * 2.0 / 3.0).as_inches(), 1.0 + 2.0 / 3.0));
+	// turns of a 0.5 inch lead screw
+	assert!(close(Length::mm(50.8) / Length::inches(0.5), 4.0));
+	assert_eq!(Length::inches(0.25).to_string(), "0.25 in");
+    }
+}
@@ -4,337 +4,312 @@
 extern crate rocket;
 
 mod structs_and_consts;
+mod backup;
+mod changes_feed;
 mod graphql;
 mod motion;
 mod couchdb;
-
-use gpio::{GpioIn, GpioOut};
-use rocket::http::{Method};
-use rocket::State;
-use std::sync::Mutex;
-use std::convert::TryInto;
-use std::{thread, time};
-use juniper::{FieldResult};
+mod storage;
+mod file_store;
+mod memory_store;
+mod csv_import;
+mod jar_contents;
+mod length;
+mod duration_estimate;
+mod procedure_run;
+mod procedure_transfer;
+mod procedure_versions;
+mod reagent_usage;
+mod status_events;
+mod step_groups;
+mod validation;
+
+use gpio::GpioOut;
+use rocket::http::{ContentType, Method, Status};
+use rocket::response::{content, status};
+use rocket::{Data, State};
+use std::io::Read;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
 use rocket_contrib::serve::StaticFiles;
 use rocket_cors::{AllowedHeaders, AllowedOrigins};
-use std::time::{Instant};
 use std::sync::atomic::Ordering;
 use std::sync::atomic::*;
 use std::process::Command;
 pub use crate::structs_and_consts::*;
+pub use crate::backup::*;
+pub use crate::changes_feed::*;
 pub use crate::graphql::*;
 pub use crate::motion::*;
 pub use crate::couchdb::*;
+pub use crate::storage::*;
+pub use crate::file_store::*;
+pub use crate::memory_store::*;
+pub use crate::csv_import::*;
+pub use crate::jar_contents::*;
+pub use crate::duration_estimate::*;
+pub use crate::procedure_run::*;
+pub use crate::procedure_transfer::*;
+pub use crate::procedure_versions::*;
+pub use crate::reagent_usage::*;
+pub use crate::status_events::*;
+pub use crate::step_groups::*;
+pub use crate::validation::*;
 
 #[rocket::post("/pause_procedure")]
-fn pause_procedure(pes: State<ProcedureExecutionState>) -> String {
-    let pes = pes.inner();
-    pes.atm.store(ProcedureExecutionStateEnum::Paused, Ordering::Relaxed);
-    format! {"/pause {:?}", pes.atm.load(Ordering::Relaxed)}
+fn pause_procedure(pes: State<SharedProcedureExecutionState>) -> String {
+    match pause_run(pes.inner()) {
+	Ok(status) => format! {"/pause {:?}", status.run_state},
+	Err(e) => format! {"/pause {}", e.message()},
+    }
 }
 
 #[rocket::post("/resume_procedure")]
-fn resume_procedure(pes: State<ProcedureExecutionState>) -> String {
-    let pes = pes.inner();
-    pes.atm.store(ProcedureExecutionStateEnum::Running, Ordering::Relaxed);
-    format! {"/pause {:?}", pes.atm.load(Ordering::Relaxed)}
+fn resume_procedure(pes: State<SharedProcedureExecutionState>) -> String {
+    match resume_run(pes.inner()) {
+	Ok(status) => format! {"/resume {:?}", status.run_state},
+	Err(e) => format! {"/resume {}", e.message()},
+    }
 }
 
 #[rocket::post("/stop_procedure")]
-fn stop_procedure(pes: State<ProcedureExecutionState>) -> String {
-    let pes = pes.inner();
-    pes.atm.store(ProcedureExecutionStateEnum::Stopped, Ordering::Relaxed);
-    format! {"/pause {:?}", pes.atm.load(Ordering::Relaxed)}
+fn stop_procedure(pes: State<SharedProcedureExecutionState>) -> String {
+    match stop_run(pes.inner()) {
+	Ok(status) => format! {"/stop {:?}", status.run_state},
+	Err(e) => format! {"/stop {}", e.message()},
+    }
 }
 
 #[rocket::get("/read_procedure_status")]
-fn read_procedure_status(pes: State<ProcedureExecutionState>) -> String {
+fn read_procedure_status(pes: State<SharedProcedureExecutionState>) -> String {
     let pes = pes.inner();
     format! {"/read {:?}", pes.atm.load(Ordering::Relaxed)}
 }
 
 #[rocket::get("/seconds_remaining")]
-fn seconds_remaining(pes: State<ProcedureExecutionState>) -> String {
+fn seconds_remaining(pes: State<SharedProcedureExecutionState>) -> String {
     let pes = pes.inner();
     format! {"{}", pes.seconds_remaining.load(Ordering::Relaxed)}
 }
 
 #[post("/home")]
-fn home_handler(pi_state: State<SharedPi>, pes: State<ProcedureExecutionState>) -> String {
+fn home_handler(pi_state: State<SharedPi>, pes: State<SharedProcedureExecutionState>) -> String {
     let pi_mutex = &mut pi_state.inner();
     let pi = &mut *pi_mutex.lock().unwrap();
-    let pes = pes.inner();
+    let pes : &ProcedureExecutionState = pes.inner();
     let ret = home(pi, Some(pes));
     format! {"{:?}",ret}
 }
 
 #[post("/run_procedure/<id>")]
-fn run_procedure(pi_state: State<SharedPi>, pes: State<ProcedureExecutionState>, id: String) -> String {
-    let pes = pes.inner();
-    println!("In run_procedure, pes: {:?}",pes.atm.load(Ordering::Relaxed));
-    if pes.atm.load(Ordering::Relaxed) == ProcedureExecutionStateEnum::Running {
-	println!("Already running a procedure. Exiting.");
-	return "Already running a procedure. Exiting.".to_string();
+fn run_procedure(pi_state: State<SharedPi>, pes: State<SharedProcedureExecutionState>, store: State<SharedProcedureStore>, racks: State<SharedRackStore>,
+		 changes: State<SharedChangeTracker>, id: String) -> String {
+    match start_run(pi_state.inner(), pes.inner(), store.inner(), racks.inner(), changes.inner(), id, None, false, None) {
+	Ok(status) => format! {"/run_procedure {:?}", status.run_state},
+	Err(e) => format! {"/run_procedure {}", e.message()},
     }
+}
 
-    let pi_mutex = &mut pi_state.inner();
+type JsonResponse = Result<content::Json<String>, status::Custom<content::Json<String>>>;
+
+// The HTTP status for an error from the REST endpoints. Storage errors map by their extensions.code (see StorageError);
+// errors without a code are about the request itself, e.g. a file that isn't valid JSON.
+fn error_status(e: &juniper::FieldError) -> Status {
+    let code = e.extensions().as_object_value()
+	.and_then(|extensions| extensions.get_field_value("code"))
+	.and_then(|code| code.as_scalar_value::<String>());
+    match code.map(String::as_str) {
+	None => Status::BadRequest,
+	Some("NOT_FOUND") => Status::NotFound,
+	Some("CONFLICT") | Some("NOT_ALLOWED") => Status::Conflict,
+	Some("STORAGE_UNAVAILABLE") => Status::ServiceUnavailable,
+	Some(_) => Status::InternalServerError,
+    }
+}
 
-    pes.atm.store(ProcedureExecutionStateEnum::Running, Ordering::Relaxed);
+fn error_response(status: Status, message: &str) -> status::Custom<content::Json<String>> {
+    status::Custom(status, content::Json(serde_json::json!({ "error": message }).to_string()))
+}
 
-    // load the procedure
-    let proc : FieldResult<Procedure> = procedure_by_id(id);
-    if proc.is_err() { return format! {"Couldn't find procedure with that ID."}; }
-    let mut proc = proc.unwrap();
+// Exports the given comma-separated procedure _ids, or every procedure if none are given, as a procedure export file.
+#[get("/procedures/export?<ids>")]
+fn export_procedures_handler(store: State<SharedProcedureStore>, ids: Option<String>) -> JsonResponse {
+    let ids : Option<Vec<String>> = ids.map(|ids| ids.split(',').map(|id| id.trim().to_string()).collect());
+    match export_procedures_json(&**store.inner(), ids) {
+	Ok(json) => Ok(content::Json(json)),
+	Err(e) => Err(error_response(error_status(&e), e.message())),
+    }
+}
 
-    {
-	let pi = &mut *pi_mutex.lock().unwrap();
-	pi.current_procedure = Some(proc.clone());
-	// initialize the run status
-	pi.run_status = Some(ProcedureRunStatus {
-	    current_procedure_step_number : 0,
-	    current_cycle_number: 0,
-	    run_state: ProcedureExecutionStateEnum::Running,
-	});
-	pi.red_light.set_high().expect("Couldn't turn on estop light.");
+// Reads a request body of up to limit bytes. A larger body is refused with a 413 rather than cut off, which would
+// only fail later as bad JSON or a bad checksum.
+fn read_body(data: Data, limit: u64, what: &str) -> Result<String, status::Custom<content::Json<String>>> {
+    let mut body = String::new();
+    if let Err(e) = data.open().take(limit + 1).read_to_string(&mut body) {
+	return Err(error_response(Status::BadRequest, &format!("Couldn't read the {}: {}", what, e)));
     }
+    if body.len() as u64 > limit {
+	return Err(error_response(Status::PayloadTooLarge, &format!("The {} is larger than the {} MB limit.", what, limit / (1024 * 1024))));
+    }
+    Ok(body)
+}
 
-    let num_repeats = match proc.repeat {
-	Some(v) => v,
-	None => 1
+// Imports a procedure export file sent as the request body. on_conflict is rename (the default), overwrite or skip.
+#[post("/procedures/import?<on_conflict>", data = "<data>")]
+fn import_procedures_handler(store: State<SharedProcedureStore>, on_conflict: Option<String>, data: Data) -> JsonResponse {
+    let on_conflict = match on_conflict {
+	Some(name) => match ImportConflictPolicy::from_name(&name) {
+	    Some(policy) => policy,
+	    None => return Err(error_response(Status::BadRequest, &format!("Unknown on_conflict {}; use rename, overwrite or skip.", name))),
+	},
+	None => ImportConflictPolicy::Rename,
     };
-    // loop over repeats
-    for repeat_num in 0..num_repeats {
-	println!("Repeat #: {}",num_repeats);
-	// loop over steps
-	println!("proc.procedure_steps: {:?}", proc.procedure_steps);
-	for (index,step) in proc.procedure_steps.iter().enumerate() {
-	    if pes.atm.load(Ordering::Relaxed) == ProcedureExecutionStateEnum::Stopped {
-		break; // end the procedure if the user stopped it
-	    }
-	    println!("Trying to grab the lock.");
-	    // grab the lock
-	    {
-		let pi = &mut *pi_mutex.lock().unwrap();
-		let run_status = pi.run_status.as_mut().unwrap();
-		run_status.current_cycle_number = repeat_num + 1;
-		run_status.current_procedure_step_number = (index + 1).try_into().unwrap();
-		//pi.run_status.current_procedure_step_number = pi.run_status.current_procedure_step_number
-		println!("Step: {:?}",step);
-		// move to the jar
-		println!("Entering loop B");
-		loop {
-		    let state = pes.atm.load(Ordering::Relaxed);
-		    if state == ProcedureExecutionStateEnum::Running {
-			println!("============== Running move_to_jar {:?} ", step.jar_number);
-			pi.green_light.set_low().expect("Couldn't turn green light off");
-			pi.red_light.set_high().expect("Couldn't turn red light back on");
-			let ret = move_to_jar( pi, step.jar_number, Some(&pes) );
-			if ret == MoveResult::MovedFullDistance {
-			    break;
-			}
-			if ret == MoveResult::HitLimitSwitch {
-			    println!("move_to_jar hit a limit switch!");
-			    pes.atm.store(ProcedureExecutionStateEnum::Paused, Ordering::Relaxed);
-			    pi.green_light.set_high().expect("Couldn't turn green light on");
-			    pi.red_light.set_low().expect("Couldn't turn red light off");
-			}
-		    }
-		    if state == ProcedureExecutionStateEnum::Stopped {
-			break; // end the procedure if the user stopped it
-		    }
-		    // note: don't replace "pes.atm.load(Ordering::Relaxed)" with "state" in the below line.
-		    // This uses updates to the state in move_to_jar to perform logic."
-		    if pes.atm.load(Ordering::Relaxed) == ProcedureExecutionStateEnum::Paused {
-			pi.green_light.set_high().expect("Couldn't turn green light on");
-			pi.red_light.set_low().expect("Couldn't turn red light off");
-			if bool::from(pi.green_button.read_value().unwrap()) {
-			    pes.atm.store(ProcedureExecutionStateEnum::Running, Ordering::Relaxed);
-			    pi.green_light.set_low().expect("Couldn't turn green light off");
-			    pi.red_light.set_high().expect("Couldn't turn red light back on");
-			}
-		    }
-		    thread::sleep(time::Duration::from_millis(10));
-		}
-	    }
-	    println!("Exited loop B");
-
-	    //pi.run_status.current_procedure_step_start_instant = Instant::now();
-	    let mut start_instant = Instant::now();
-	    let mut us_remaining : u128 = (step.time_in_seconds * 1000 * 1000).try_into().unwrap();
-
-	    // sleep until it's time to move again
-	    println!("Entering loop C");
-	    while us_remaining > 0 {
-		if pes.atm.load(Ordering::Relaxed) == ProcedureExecutionStateEnum::Running {
-		    // update the timer controls
-		    let elapsed_us = start_instant.elapsed().as_micros();
-		    start_instant = Instant::now();
-		    if elapsed_us > us_remaining { // avoid attempts to subtract with overflow
-			us_remaining = 0;
-		    }
-		    else {
-			us_remaining = us_remaining - elapsed_us;
-		    }
-
-		    // update the PES to inform the client how many seconds are remaining
-		    pes.seconds_remaining.store((us_remaining / (1000 * 1000)).try_into().unwrap(), Ordering::Relaxed);
-		}
-		// grab the lock
-		{
-		    let pi = &mut *pi_mutex.lock().unwrap();
-		    // check stop button
-		    if bool::from(pi.estop.read_value().unwrap()) {
-			pes.atm.store(ProcedureExecutionStateEnum::Paused, Ordering::Relaxed);
-		    }
-		    let state = pes.atm.load(Ordering::Relaxed);
-		    if state == ProcedureExecutionStateEnum::Stopped {
-			break;
-		    }
-		    if state == ProcedureExecutionStateEnum::Paused {
-			// handle run/pause buttons
-			pi.green_light.set_high().expect("Couldn't turn green light on");
-			pi.red_light.set_low().expect("Couldn't turn red light off");
-			if bool::from(pi.green_button.read_value().unwrap()) {
-			    pes.atm.store(ProcedureExecutionStateEnum::Running, Ordering::Relaxed);
-			    pi.green_light.set_low().expect("Couldn't turn green light off");
-			    pi.red_light.set_high().expect("Couldn't turn red light back on");
-			    start_instant = Instant::now();
-			}
-		    }
-		}
-		thread::sleep(time::Duration::from_millis(20));
-	    }
-	    println!("Exited loop C");
-	}
+    let json = read_body(data, IMPORT_SIZE_LIMIT, "file")?;
+    match import_procedures(&**store.inner(), &json, on_conflict) {
+	Ok(results) => Ok(content::Json(serde_json::to_string(&results).unwrap())),
+	Err(e) => Err(error_response(error_status(&e), e.message())),
     }
+}
 
-    // End of procedure, so move to the up position
-    {
-	let pi = &mut *pi_mutex.lock().unwrap();
-	loop {
-	    let state = pes.atm.load(Ordering::Relaxed);
-	    if state == ProcedureExecutionStateEnum::Running ||
-		state == ProcedureExecutionStateEnum::Stopped
-	    {
-		println!("============== Running move_to_up ");
-		//let ret = move_to_jar( pi, step.jar_number, Some(&pes) );
-		pi.green_light.set_low().expect("Couldn't turn green light off");
-		pi.red_light.set_high().expect("Couldn't turn red light back on");
-		let ret = move_to_up_position( pi, Some(&pes), true);
-		if ret == MoveResult::MovedFullDistance {
-		    break;
-		}
-	    }
-	    if pes.atm.load(Ordering::Relaxed) == ProcedureExecutionStateEnum::Paused {
-		pi.green_light.set_high().expect("Couldn't turn green light on");
-		pi.red_light.set_low().expect("Couldn't turn red light off");
-		if bool::from(pi.green_button.read_value().unwrap()) {
-		    pes.atm.store(ProcedureExecutionStateEnum::Running, Ordering::Relaxed);
-		    pi.green_light.set_low().expect("Couldn't turn green light off");
-		    pi.red_light.set_high().expect("Couldn't turn red light back on");
-		}
-	    }
-	    thread::sleep(time::Duration::from_millis(10));
-	}
-	
-	pi.run_status = None;
-	pi.red_light.set_low().expect("Couldn't turn off estop light.");
-
-	pes.atm.store(ProcedureExecutionStateEnum::Completed, Ordering::Relaxed);
-	let inc_result : FieldResult<Procedure> = increment_run_count(&mut proc);
-	if inc_result.is_ok() {
-	    pi.current_procedure = Some(inc_result.unwrap());
+// Downloads a backup of the whole database (see backup.rs).
+#[get("/backup")]
+fn backup_handler(store: State<SharedBackupStore>) -> Result<content::Content<String>, status::Custom<content::Json<String>>> {
+    match make_backup(&**store.inner()) {
+	Ok(backup) => Ok(content::Content(ContentType::new("application", "x-ndjson"), backup)),
+	Err(e) => Err(error_response(error_status(&e), e.message())),
+    }
+}
+
+// Restores a backup sent as the request body. Refused while a procedure is running.
+#[post("/restore", data = "<data>")]
+fn restore_handler(store: State<SharedBackupStore>, settings_store: State<SharedSettingsStore>, pes: State<SharedProcedureExecutionState>, data: Data) -> JsonResponse {
+    let backup = read_body(data, BACKUP_SIZE_LIMIT, "backup")?;
+    let restored = match while_restoring(pes.inner(), || restore_backup(&**store.inner(), &backup)) {
+	Ok(restored) => restored,
+	// refused because a run or another restore is going
+	Err(e) => return Err(error_response(Status::Conflict, e.message())),
+    };
+    match restored {
+	Ok(summary) => {
+	    reload_motion_settings(&**settings_store.inner(), &pes.motion_settings);
+	    Ok(content::Json(serde_json::json!({
+		"created": summary.created,
+		"overwritten": summary.overwritten,
+		"skipped": summary.skipped,
+	    }).to_string()))
 	}
+	Err(e) => Err(error_response(error_status(&e), e.message())),
     }
-    format! {"run_procedure completed"}
 }
 
 #[post("/move_by_pulses/<axis>/<forward>/<pulses>")]
 fn move_by_pulses(
     pi_state: State<SharedPi>,
-    pes: State<ProcedureExecutionState>,
+    pes: State<SharedProcedureExecutionState>,
     axis: AxisDirection,
     forward: bool,
     pulses: PulseCount,
 ) -> String {
     let pi_mutex = &mut pi_state.inner();
     let pi = &mut *pi_mutex.lock().unwrap();
-    let pes = pes.inner();
+    let pes : &ProcedureExecutionState = pes.inner();
     let ret = move_steps(pi, axis, forward, pulses, false, Some(pes), false);
     format! {"{:?}",ret}
 }
 
-#[post("/move_by_inches/<axis>/<forward>/<inches>")]
-fn move_by_inches(pi_state: State<SharedPi>, pes: State<ProcedureExecutionState>, axis: AxisDirection, forward: bool, inches: Inch) -> String {
-    let pi_mutex = &mut pi_state.inner();
+fn move_by_length(pi_mutex: &SharedPi, pes: &ProcedureExecutionState, axis: AxisDirection, forward: bool, distance: Length) -> String {
     let pi = &mut *pi_mutex.lock().unwrap();
     let stepper = get_stepper(pi, &axis);
-    let pulses = inches_to_pulses(inches, stepper);
-    let pes = pes.inner();
+    let pulses = length_to_pulses(distance, stepper);
     let ret = move_steps(pi, axis, forward, pulses, false, Some(pes), false);
     format! {"{:?}",ret}
 }
 
+#[post("/move_by_inches/<axis>/<forward>/<inches>")]
+fn move_by_inches(pi_state: State<SharedPi>, pes: State<SharedProcedureExecutionState>, axis: AxisDirection, forward: bool, inches: f64) -> String {
+    move_by_length(pi_state.inner(), pes.inner(), axis, forward, Length::inches(inches))
+}
+
+#[post("/move_by_mm/<axis>/<forward>/<mm>")]
+fn move_by_mm(pi_state: State<SharedPi>, pes: State<SharedProcedureExecutionState>, axis: AxisDirection, forward: bool, mm: f64) -> String {
+    move_by_length(pi_state.inner(), pes.inner(), axis, forward, Length::mm(mm))
+}
 
-#[post("/move_to_pos/<axis>/<inches>")]
-fn move_to_pos_handler(pi_state: State<SharedPi>, pes: State<ProcedureExecutionState>, axis: AxisDirection, inches: Inch) -> String {
-    let pi_mutex = &mut pi_state.inner();
+fn move_to_length(pi_mutex: &SharedPi, pes: &ProcedureExecutionState, axis: AxisDirection, position: Length) -> String {
     let pi = &mut *pi_mutex.lock().unwrap();
-    let pes = pes.inner();
-    let ret = move_to_pos(pi, axis, inches, Some(pes), false);
+    let ret = move_to_pos(pi, axis, position, Some(pes), false);
     format! {"{:?}",ret}
 }
 
+#[post("/move_to_pos/<axis>/<inches>")]
+fn move_to_pos_handler(pi_state: State<SharedPi>, pes: State<SharedProcedureExecutionState>, axis: AxisDirection, inches: f64) -> String {
+    move_to_length(pi_state.inner(), pes.inner(), axis, Length::inches(inches))
+}
+
+#[post("/move_to_pos_mm/<axis>/<mm>")]
+fn move_to_pos_mm_handler(pi_state: State<SharedPi>, pes: State<SharedProcedureExecutionState>, axis: AxisDirection, mm: f64) -> String {
+    move_to_length(pi_state.inner(), pes.inner(), axis, Length::mm(mm))
+}
+
 #[post("/move_to_up_position")]
-fn move_to_up_position_handler(pi_state: State<SharedPi>, pes: State<ProcedureExecutionState>) -> String {
+fn move_to_up_position_handler(pi_state: State<SharedPi>, pes: State<SharedProcedureExecutionState>) -> String {
     let pi_mutex = &mut pi_state.inner();
     let pi = &mut *pi_mutex.lock().unwrap();
-    let pes = pes.inner();
+    let pes : &ProcedureExecutionState = pes.inner();
     let ret = move_to_up_position(pi, Some(pes), true); // skip_soft_estop_check is set to true so that the user can manually raise the rack while paused
     format! {"{:?}",ret}
 }
 
 #[post("/move_to_down_position")]
-fn move_to_down_position_handler(pi_state: State<SharedPi>, pes: State<ProcedureExecutionState>) -> String {
+fn move_to_down_position_handler(pi_state: State<SharedPi>, pes: State<SharedProcedureExecutionState>) -> String {
     let pi_mutex = &mut pi_state.inner();
     let pi = &mut *pi_mutex.lock().unwrap();
-    let pes = pes.inner();
+    let pes : &ProcedureExecutionState = pes.inner();
     let ret = move_to_down_position(pi, Some(pes), true); // skip_soft_estop_check is set to true so that the user can manually lower the rack while paused
     format! {"{:?}",ret}
 }
 
 #[post("/move_to_left_position")]
-fn move_to_left_position_handler(pi_state: State<SharedPi>, pes: State<ProcedureExecutionState>) -> String {
+fn move_to_left_position_handler(pi_state: State<SharedPi>, pes: State<SharedProcedureExecutionState>) -> String {
     let pi_mutex = &mut pi_state.inner();
     let pi = &mut *pi_mutex.lock().unwrap();
-    let pes = pes.inner();
+    let pes : &ProcedureExecutionState = pes.inner();
     let ret = move_to_left_position(pi, Some(pes));
     format! {"{:?}",ret}
 }
 
 #[post("/move_to_jar/<jar_number>")]
-fn move_to_jar_handler(pi_state: State<SharedPi>, pes: State<ProcedureExecutionState>, jar_number: i32) -> String {
+fn move_to_jar_handler(pi_state: State<SharedPi>, pes: State<SharedProcedureExecutionState>, jar_number: i32) -> String {
     let pi_mutex = &mut pi_state.inner();
-    let pes = pes.inner();
+    let pes : &ProcedureExecutionState = pes.inner();
     let pi = &mut *pi_mutex.lock().unwrap();
     println!("0.1: {}", jar_number);
     let ret = move_to_jar(pi, jar_number, Some(pes));
     format! {"{:?}",ret}
 }
 
-#[get("/pos/<axis>")]
-fn pos(pi: State<SharedPi>, axis: AxisDirection) -> String {
-    let pi = &mut *pi.inner().lock().unwrap();
+fn pos_in_unit(pi: &SharedPi, axis: AxisDirection, unit: LengthUnit) -> String {
+    let pi = &mut *pi.lock().unwrap();
     let stepper = match axis {
         AxisDirection::X => &mut pi.stepper_x,
         AxisDirection::Z => &mut pi.stepper_z,
     };
     match stepper.pos {
-        Some(v) => format!("{}", pulses_to_inches(v, stepper)),
+        Some(v) => format!("{}", pulses_to_length(v, stepper).as_unit(unit)),
         None => "Not homed".to_string(),
     }
 }
 
+#[get("/pos/<axis>")]
+fn pos(pi: State<SharedPi>, axis: AxisDirection) -> String {
+    pos_in_unit(pi.inner(), axis, LengthUnit::Inches)
+}
+
+#[get("/pos_mm/<axis>")]
+fn pos_mm(pi: State<SharedPi>, axis: AxisDirection) -> String {
+    pos_in_unit(pi.inner(), axis, LengthUnit::Millimeters)
+}
+
 #[get("/play_note?<note_hz>&<duration_ms>&<forward>")]
 fn play_note(pi_state: State<SharedPi>, note_hz: f64, duration_ms: u64, forward: bool) -> String {
     let pi_mutex = &mut pi_state.inner();
@@ -355,7 +330,7 @@ fn move_turns_at_freq(pi_state: State<SharedPi>, hz: f64, forward: bool, turns :
 fn run_motor_test(pi_state: State<SharedPi>, forward: bool, acceleration_constant : f64, number_of_turns: u64) -> String {
     let pi_mutex = &mut pi_state.inner();
     let pi = &mut *pi_mutex.lock().unwrap();
-    return crate::motion::run_motor_test(pi, AxisDirection::X, forward, acceleration_constant, number_of_turns);
+    crate::motion::run_motor_test(pi, AxisDirection::X, forward, acceleration_constant, number_of_turns)
 }
 
 // This runs a script that closes the current chromium-browser session (running in kiosk mode)
@@ -372,7 +347,23 @@ fn exit_kiosk_mode() -> String {
 }
 
 fn main() {
-    let shared_pi = Mutex::new(Pi {
+    // `rmrm migrate-from-couchdb [data file]` copies everything out of CouchDB for the file storage backend, then exits
+    let args : Vec<String> = std::env::args().collect();
+    if args.get(1).map(|arg| arg.as_str()) == Some("migrate-from-couchdb") {
+	let path = args.get(2).map(PathBuf::from).unwrap_or_else(data_file_from_env);
+	match migrate_from_couchdb(path) {
+	    Ok(summary) => println!("{}", summary),
+	    Err(e) => {
+		eprintln!("Migration failed: {}", e);
+		std::process::exit(1);
+	    }
+	}
+	return;
+    }
+
+    // defaults until the settings are read from storage below
+    let motion_settings : SharedMotionSettings = Arc::new(RwLock::new(MotionSettings::default()));
+    let shared_pi : SharedPi = Arc::new(Mutex::new(Pi {
         estop: gpio::sysfs::SysFsGpioInput::open(25).unwrap(),
 	green_button: gpio::sysfs::SysFsGpioInput::open(18).unwrap(),
 	red_light: gpio::sysfs::SysFsGpioOutput::open(24).unwrap(),
@@ -384,9 +375,9 @@ fn main() {
             limit_switch_low: Some(gpio::sysfs::SysFsGpioInput::open(14).unwrap()),
             limit_switch_high: None,
             pos: None,
-            position_limit: 10.0,
-            pulses_per_revolution: 4000,
-            travel_distance_per_turn: 0.063,
+            position_limit: X_POSITION_LIMIT,
+            pulses_per_revolution: PULSES_PER_REVOLUTION,
+            travel_distance_per_turn: TRAVEL_DISTANCE_PER_TURN,
         },
         stepper_z: Stepper {
             ena: gpio::sysfs::SysFsGpioOutput::open(3).unwrap(),
@@ -395,16 +386,24 @@ fn main() {
             limit_switch_low: None,
             limit_switch_high: Some(gpio::sysfs::SysFsGpioInput::open(15).unwrap()),
             pos: None,
-            position_limit: 3.75,
-            pulses_per_revolution: 4000,
-            travel_distance_per_turn: 0.063,
+            position_limit: Z_POSITION_LIMIT,
+            pulses_per_revolution: PULSES_PER_REVOLUTION,
+            travel_distance_per_turn: TRAVEL_DISTANCE_PER_TURN,
         },
-	current_procedure: None,
-	run_status: None,
-    });
+	motion_settings: motion_settings.clone(),
+    }));
 
     let atm : AtomicProcedureExecutionStateEnum = AtomicProcedureExecutionStateEnum::new(ProcedureExecutionStateEnum::NotStarted);
-    let pes : ProcedureExecutionState = ProcedureExecutionState { atm: atm, seconds_remaining: AtomicU64::new(0)};
+    let pes : SharedProcedureExecutionState = Arc::new(ProcedureExecutionState {
+	atm,
+	seconds_remaining: AtomicU64::new(0),
+	active_run: Mutex::new(None),
+	limit_switch_hit_unexpectedly: AtomicBool::new(false),
+	operator_action_confirmed: AtomicBool::new(false),
+	motion_settings: motion_settings.clone(),
+	current_procedure: Mutex::new(None),
+	restoring: AtomicBool::new(false),
+    });
 
     {
 	// initialize enable pins (this is needed since the logic is reversed since it's behind
@@ -415,6 +414,26 @@ fn main() {
 	pi.stepper_z.ena.set_high().expect("Couldn't set enable pin"); // high is low since it's behind a transistor
     }
     
+    // pick where procedures, settings and the rack are kept (see storage.rs)
+    let backend = StorageBackend::from_env().unwrap_or_else(|e| panic!("Couldn't open storage: {}", e));
+    let stores = open_stores(&backend).unwrap_or_else(|e| panic!("Couldn't open storage: {}", e));
+    // the database may not be up yet, so this isn't fatal; the configureDatabase mutation runs it again
+    match stores.procedures.configure() {
+	Ok(setup) => println!("Database set up: {:?}", setup),
+	Err(e) => println!("Couldn't set up the database: {}", e.message()),
+    }
+    reload_motion_settings(&*stores.settings, &motion_settings);
+
+    // stream status changes to clients
+    let broadcaster : SharedEventBroadcaster = Arc::new(EventBroadcaster::new());
+    start_event_server(shared_pi.clone(), pes.clone(), broadcaster.clone(), EVENT_SERVER_PORT);
+
+    // follow changes made to the database by anything else (see changes_feed.rs)
+    let changes : SharedChangeTracker = Arc::new(ChangeTracker::new());
+    if backend == StorageBackend::CouchDB {
+	start_changes_listener(COUCHDB_URL.to_string(), stores.procedures.clone(), pes.clone(), changes.clone(), broadcaster);
+    }
+
     // set up CORS
     let allowed_origins = AllowedOrigins::all();
     let cors = rocket_cors::CorsOptions {
@@ -430,14 +449,22 @@ fn main() {
         .manage(shared_pi)
 	.manage(Schema::new(Query, Mutation))
 	.manage(pes)
+	.manage(stores.procedures)
+	.manage(stores.settings)
+	.manage(stores.racks)
+	.manage(stores.backups)
+	.manage(changes)
         .mount(
             "/",
             routes![
                 home_handler,
                 pos,
+                pos_mm,
                 move_by_pulses,
                 move_by_inches,
+                move_by_mm,
                 move_to_pos_handler,
+                move_to_pos_mm_handler,
                 move_to_up_position_handler,
                 move_to_down_position_handler,
                 move_to_left_position_handler,
@@ -445,6 +472,10 @@ fn main() {
 		graphiql,
 		post_graphql_handler,
 		run_procedure,
+		export_procedures_handler,
+		import_procedures_handler,
+		backup_handler,
+		restore_handler,
 		pause_procedure,
 		resume_procedure,
 		stop_procedure,
@@ -459,3 +490,21 @@ fn main() {
 	.attach(cors)
         .launch();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rest_errors_get_a_status_from_their_code() {
+	let bad_json = import_procedures(&MemoryStore::new(), "{\"format\": ", ImportConflictPolicy::Rename).unwrap_err();
+	assert_eq!(error_status(&bad_json), Status::BadRequest);
+
+	let status = |e: StorageError| error_status(&juniper::FieldError::from(e));
+	assert_eq!(status(StorageError::conflict("Jar 3's procedure was saved somewhere else.".to_string())), Status::Conflict);
+	assert_eq!(status(StorageError::NotAllowed("It has been run.".to_string())), Status::Conflict);
+	assert_eq!(status(StorageError::NotFound("No procedure pap.".to_string())), Status::NotFound);
+	assert_eq!(status(StorageError::Unavailable("CouchDB isn't running.".to_string())), Status::ServiceUnavailable);
+	assert_eq!(status(StorageError::InvalidDocument("Bad settings doc.".to_string())), Status::InternalServerError);
+    }
+}
@@ -0,0 +1,516 @@
+pub use crate::structs_and_consts::*;
+pub use crate::storage::*;
+pub use crate::jar_contents::*;
+
+use chrono::{DateTime, Utc};
+use serde::*;
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+// Keeps procedures, settings and the rack in memory. Used on its own for testing, where nothing survives a restart,
+// and by FileStore (file_store.rs), which writes the data out to a file after every change.
+// _revs are checked like CouchDB checks them, so saving over a stale copy fails the same way.
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MemoryStoreData {
+    #[serde(default)]
+    pub procedures: BTreeMap<String, Procedure>, // keyed by _id
+    #[serde(default)]
+    pub procedure_versions: BTreeMap<String, ProcedureVersion>, // keyed by _id
+    #[serde(default)]
+    pub runs: Vec<RunRecord>,
+    #[serde(default = "default_settings")]
+    pub settings: Settings,
+    #[serde(default = "Rack::empty")]
+    pub rack: Rack,
+    #[serde(default = "ReagentThresholds::empty")]
+    pub reagent_thresholds: ReagentThresholds,
+    #[serde(default = "default_next_id")]
+    pub next_id: u64, // used to make _ids for new procedures and runs
+}
+
+fn default_settings() -> Settings {
+    Settings {
+	rev: "1-local".to_string(),
+	..Settings::default()
+    }
+}
+
+fn default_next_id() -> u64 {
+    1
+}
+
+impl Default for MemoryStoreData {
+    fn default() -> Self {
+	MemoryStoreData {
+	    procedures: BTreeMap::new(),
+	    procedure_versions: BTreeMap::new(),
+	    runs: Vec::new(),
+	    settings: default_settings(),
+	    rack: Rack::empty(),
+	    reagent_thresholds: ReagentThresholds::empty(),
+	    next_id: default_next_id(),
+	}
+    }
+}
+
+impl MemoryStoreData {
+    fn new_id(&mut self, prefix: &str) -> String {
+	loop {
+	    let id = format!("{}-{}", prefix, self.next_id);
+	    self.next_id += 1;
+	    if !self.procedures.contains_key(&id) && !self.runs.iter().any(|run| run.id.as_ref() == Some(&id)) {
+		return id;
+	    }
+	}
+    }
+
+    // The procedure as returned to callers, with its run count filled in from the run records.
+    fn with_run_count(&self, proc: &Procedure) -> Procedure {
+	let mut proc = proc.clone();
+	let recorded_runs = self.runs.iter().filter(|run| run.procedure_id == proc.id).count() as i32;
+	proc.runs = Some(proc.runs_before_run_history.unwrap_or(0) + recorded_runs);
+	proc
+    }
+
+    fn sorted_procedures(&self) -> Vec<Procedure> {
+	// the map is ordered by _id, like the CouchDB view
+	self.procedures.values().map(|proc| self.with_run_count(proc)).collect()
+    }
+
+    pub fn has_doc(&self, id: &str) -> bool {
+	match id {
+	    "settings" | "rack" | "reagent_thresholds" => true, // there are always defaults
+	    _ => self.procedures.contains_key(id)
+		|| self.procedure_versions.contains_key(id)
+		|| self.runs.iter().any(|run| run.id.as_deref() == Some(id)),
+	}
+    }
+
+    // Adds a document as it's stored in CouchDB or a backup, replacing any with the same _id.
+    // Returns false, leaving the data alone, if it isn't a kind of document kept here.
+    pub fn load_doc(&mut self, doc: serde_json::Value) -> Result<bool, String> {
+	let id = doc["_id"].as_str().unwrap_or("").to_string();
+	let doc_type = doc["type"].as_str().unwrap_or("").to_string();
+	let loaded = match (id.as_str(), doc_type.as_str()) {
+	    ("settings", _) => serde_json::from_value::<Settings>(doc).map(|settings| self.settings = settings),
+	    ("rack", _) => serde_json::from_value::<Rack>(doc).map(|rack| self.rack = rack),
+	    ("reagent_thresholds", _) => serde_json::from_value::<ReagentThresholds>(doc).map(|thresholds| self.reagent_thresholds = thresholds),
+	    (_, "procedure") => serde_json::from_value::<Procedure>(doc).map(|mut proc| {
+		reconcile_procedure(&mut proc);
+		self.procedures.insert(id.clone(), proc);
+	    }),
+	    (_, "procedure_version") => serde_json::from_value::<ProcedureVersion>(doc).map(|proc_version| {
+		self.procedure_versions.insert(id.clone(), proc_version);
+	    }),
+	    (_, "run") => serde_json::from_value::<RunRecord>(doc).map(|run_record| {
+		if run_record.id.is_some() {
+		    self.runs.retain(|run| run.id != run_record.id);
+		}
+		self.runs.push(run_record);
+	    }),
+	    _ => return Ok(false),
+	};
+	loaded.map(|_| true).map_err(|e| e.to_string())
+    }
+
+    // Every document as CouchDB would store it.
+    pub fn docs(&self) -> Vec<serde_json::Value> {
+	let mut docs = vec![
+	    serde_json::to_value(&self.settings),
+	    serde_json::to_value(&self.rack),
+	    serde_json::to_value(&self.reagent_thresholds),
+	];
+	docs.extend(self.procedures.values().map(serde_json::to_value));
+	docs.extend(self.procedure_versions.values().map(serde_json::to_value));
+	docs.extend(self.runs.iter().map(serde_json::to_value));
+	docs.into_iter().map(|doc| doc.expect("stored documents always serialize")).collect()
+    }
+}
+
+pub struct MemoryStore {
+    data: Mutex<MemoryStoreData>,
+}
+
+// Revs look like CouchDB's: a generation number that goes up on every save, then a dash.
+fn next_rev(rev: &Option<String>) -> String {
+    let generation = rev.as_ref()
+	.and_then(|rev| rev.split('-').next())
+	.and_then(|generation| generation.parse::<u64>().ok())
+	.unwrap_or(0);
+    format!("{}-local", generation + 1)
+}
+
+fn conflict_err<T>(what: &str) -> StorageResult<T> {
+    Err(StorageError::conflict(format!("Document update conflict: {} has been changed since it was loaded.", what)))
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+	MemoryStore::new()
+    }
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+	MemoryStore::from_data(MemoryStoreData::default())
+    }
+
+    pub fn from_data(data: MemoryStoreData) -> MemoryStore {
+	MemoryStore {
+	    data: Mutex::new(data),
+	}
+    }
+
+    pub fn to_json(&self) -> serde_json::Result<String> {
+	serde_json::to_string_pretty(&*self.data.lock().unwrap())
+    }
+
+    // A copy of everything in the store.
+    pub fn data(&self) -> MemoryStoreData {
+	self.data.lock().unwrap().clone()
+    }
+
+    pub fn into_data(self) -> MemoryStoreData {
+	self.data.into_inner().unwrap()
+    }
+
+    pub fn replace_data(&self, data: MemoryStoreData) {
+	*self.data.lock().unwrap() = data;
+    }
+}
+
+impl ProcedureStore for MemoryStore {
+    fn configure(&self) -> StorageResult<DatabaseSetup> {
+	Ok(DatabaseSetup {
+	    database_created: false,
+	    design_docs: Vec::new(),
+	    settings_created: false, // there are always settings, see MemoryStoreData::default
+	})
+    }
+
+    fn procedure_list(&self) -> StorageResult<ProcedureList> {
+	Ok(ProcedureList {
+	    procedures: self.data.lock().unwrap().sorted_procedures(),
+	    skipped: Vec::new(), // everything was parsed when the store was loaded
+	})
+    }
+
+    fn find_procedure(&self, id: String) -> StorageResult<Option<Procedure>> {
+	let data = self.data.lock().unwrap();
+	Ok(data.procedures.get(&id).map(|proc| data.with_run_count(proc)))
+    }
+
+    fn save_procedure(&self, mut procedure: ProcedureInputObject) -> StorageResult<Procedure> {
+	reconcile_procedure_input_object(&mut procedure);
+	let data = &mut *self.data.lock().unwrap();
+	let id = match procedure.id.clone() {
+	    Some(id) => id,
+	    None => data.new_id("procedure"),
+	};
+	let existing = data.procedures.get(&id);
+	let stale = match (existing, &procedure.rev) {
+	    (Some(proc), Some(rev)) => &proc.rev != rev,
+	    (Some(_), None) => true, // CouchDB won't overwrite a doc without its _rev
+	    (None, Some(_)) => true,
+	    (None, None) => false,
+	};
+	if stale {
+	    return conflict_err(&format!("procedure {}", id));
+	}
+	let runs_before_run_history = existing.and_then(|proc| proc.runs_before_run_history);
+	let archived_at = existing.and_then(|proc| proc.archived_at);
+
+	let mut proc = Procedure {
+	    id: id.clone(),
+	    rev: next_rev(&procedure.rev),
+	    type_: procedure.type_,
+	    name: procedure.name,
+	    jar_contents: procedure.jar_contents,
+	    procedure_steps: procedure.procedure_steps.into_iter().map(ProcedureStep::from).collect(),
+	    step_groups: procedure.step_groups.unwrap_or_default().into_iter().map(StepGroup::from).collect(),
+	    repeat: procedure.repeat,
+	    runs_before_run_history,
+	    runs: None,
+	    version: procedure.version,
+	    archived_at,
+	};
+	reconcile_procedure(&mut proc);
+	data.procedures.insert(id, proc.clone());
+	Ok(data.with_run_count(&proc))
+    }
+
+    fn set_procedure_archived(&self, id: String, rev: String, archived_at: Option<DateTime<Utc>>) -> StorageResult<Procedure> {
+	let data = &mut *self.data.lock().unwrap();
+	let proc = match data.procedures.get_mut(&id) {
+	    None => return Err(StorageError::NotFound(format!("No procedure with ID {} found.", id))),
+	    Some(proc) if proc.rev != rev => return conflict_err(&format!("procedure {}", id)),
+	    Some(proc) => proc,
+	};
+	proc.archived_at = archived_at;
+	proc.rev = next_rev(&Some(rev));
+	let proc = proc.clone();
+	Ok(data.with_run_count(&proc))
+    }
+
+    fn delete_procedure(&self, id: String, rev: String) -> StorageResult<()> {
+	let data = &mut *self.data.lock().unwrap();
+	match data.procedures.get(&id) {
+	    None => return Err(StorageError::NotFound(format!("No procedure with ID {} found.", id))),
+	    Some(proc) if proc.rev != rev => return conflict_err(&format!("procedure {}", id)),
+	    Some(_) => {}
+	}
+	data.procedures.remove(&id);
+	Ok(())
+    }
+
+    fn procedure_versions(&self, procedure_id: String) -> StorageResult<Vec<ProcedureVersion>> {
+	let data = self.data.lock().unwrap();
+	let mut versions : Vec<ProcedureVersion> = data.procedure_versions.values()
+	    .filter(|proc_version| proc_version.procedure_id == procedure_id)
+	    .cloned()
+	    .collect();
+	versions.sort_by_key(|proc_version| proc_version.version);
+	Ok(versions)
+    }
+
+    fn procedure_version(&self, procedure_id: String, version: i32) -> StorageResult<ProcedureVersion> {
+	let data = self.data.lock().unwrap();
+	match data.procedure_versions.values().find(|v| v.procedure_id == procedure_id && v.version == version) {
+	    Some(proc_version) => Ok(proc_version.clone()),
+	    None => Err(StorageError::NotFound(format!("Procedure {} has no version {}.", procedure_id, version))),
+	}
+    }
+
+    fn create_procedure_version(&self, proc_version: &ProcedureVersion) -> StorageResult<()> {
+	let data = &mut *self.data.lock().unwrap();
+	data.procedure_versions.entry(proc_version.id.clone()).or_insert_with(|| proc_version.clone());
+	Ok(())
+    }
+
+    fn save_run_record(&self, run_record: &RunRecord) -> StorageResult<RunRecord> {
+	let data = &mut *self.data.lock().unwrap();
+	let mut run_record = run_record.clone();
+	if run_record.id.is_none() {
+	    run_record.id = Some(data.new_id("run"));
+	}
+	run_record.rev = Some(next_rev(&run_record.rev));
+	data.runs.push(run_record.clone());
+	Ok(run_record)
+    }
+}
+
+impl BackupStore for MemoryStore {
+    fn all_docs(&self) -> StorageResult<Vec<serde_json::Value>> {
+	Ok(self.data.lock().unwrap().docs())
+    }
+
+    // All or nothing: if any document can't be read, nothing is changed.
+    fn load_docs(&self, docs: Vec<serde_json::Value>) -> StorageResult<DocsLoaded> {
+	let data = &mut *self.data.lock().unwrap();
+	let mut restored = data.clone();
+	let mut loaded = DocsLoaded {
+	    created: 0,
+	    overwritten: 0,
+	    skipped: Vec::new(),
+	};
+	for doc in docs {
+	    let id = doc["_id"].as_str().unwrap_or("").to_string();
+	    let existed = restored.has_doc(&id);
+	    // versions are immutable, like in create_procedure_version
+	    if existed && doc["type"] == "procedure_version" {
+		loaded.skipped.push(SkippedDocument {
+		    id,
+		    reason: "This procedure version already exists.".to_string(),
+		});
+		continue;
+	    }
+	    match restored.load_doc(doc) {
+		Ok(true) if existed => loaded.overwritten += 1,
+		Ok(true) => loaded.created += 1,
+		Ok(false) => loaded.skipped.push(SkippedDocument {
+		    id,
+		    reason: "Not a kind of document this storage backend keeps.".to_string(),
+		}),
+		Err(e) => return Err(StorageError::InvalidDocument(format!("Couldn't read {}: {}", id, e))),
+	    }
+	}
+	*data = restored;
+	Ok(loaded)
+    }
+}
+
+impl SettingsStore for MemoryStore {
+    fn settings(&self) -> StorageResult<Settings> {
+	Ok(self.data.lock().unwrap().settings.clone())
+    }
+
+    fn save_settings_doc(&self, mut settings: Settings) -> StorageResult<Settings> {
+	let data = &mut *self.data.lock().unwrap();
+	if settings.rev != data.settings.rev {
+	    return conflict_err("the settings");
+	}
+	settings.id = data.settings.id.clone();
+	settings.rev = next_rev(&Some(settings.rev));
+	data.settings = settings;
+	Ok(data.settings.clone())
+    }
+}
+
+impl RackStore for MemoryStore {
+    fn rack(&self) -> StorageResult<Rack> {
+	Ok(self.data.lock().unwrap().rack.clone())
+    }
+
+    fn save_rack_doc(&self, mut rack: Rack) -> StorageResult<Rack> {
+	let data = &mut *self.data.lock().unwrap();
+	if rack.rev != data.rack.rev {
+	    return conflict_err("the rack");
+	}
+	rack.rev = Some(next_rev(&rack.rev));
+	data.rack = rack;
+	Ok(data.rack.clone())
+    }
+
+    fn reagent_thresholds(&self) -> StorageResult<ReagentThresholds> {
+	Ok(self.data.lock().unwrap().reagent_thresholds.clone())
+    }
+
+    fn save_reagent_thresholds(&self, thresholds: ReagentThresholdsInputObject) -> StorageResult<ReagentThresholds> {
+	let data = &mut *self.data.lock().unwrap();
+	if thresholds.rev != data.reagent_thresholds.rev {
+	    return conflict_err("the reagent thresholds");
+	}
+	data.reagent_thresholds = ReagentThresholds {
+	    id: thresholds.id,
+	    rev: Some(next_rev(&thresholds.rev)),
+	    type_: thresholds.type_,
+	    thresholds: thresholds.thresholds.into_iter().map(ReagentThreshold::from).collect(),
+	};
+	Ok(data.reagent_thresholds.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn xylene_rinse() -> ProcedureInputObject {
+	ProcedureInputObject {
+	    id: None,
+	    rev: None,
+	    type_: "procedure".to_string(),
+	    name: "Xylene rinse".to_string(),
+	    jar_contents: vec!["Xylene".to_string()],
+	    procedure_steps: vec![ProcedureStepInputObject {
+		substance: None,
+		time_in_seconds: 300,
+		jar_number: 1,
+		step_type: None,
+		operator_message: None,
+	    }],
+	    step_groups: None,
+	    repeat: None,
+	    runs: None,
+	    version: None,
+	}
+    }
+
+    #[test]
+    fn saving_assigns_ids_and_bumps_revs() {
+	let store = MemoryStore::new();
+	let first = store.save_procedure(xylene_rinse()).unwrap();
+	let second = store.save_procedure(xylene_rinse()).unwrap();
+	assert_ne!(first.id, second.id);
+	assert_eq!(first.rev, "1-local");
+	// substances are filled in from the jar contents like they are when loading from CouchDB
+	assert_eq!(first.procedure_steps[0].substance, "Xylene");
+
+	let resaved = store.save_procedure(ProcedureInputObject::from(first.clone())).unwrap();
+	assert_eq!((resaved.id.as_str(), resaved.rev.as_str()), (first.id.as_str(), "2-local"));
+	assert_eq!(store.procedures().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn stale_revs_are_refused() {
+	let store = MemoryStore::new();
+	let saved = store.save_procedure(xylene_rinse()).unwrap();
+	store.save_procedure(ProcedureInputObject::from(saved.clone())).unwrap();
+
+	assert_eq!(store.save_procedure(ProcedureInputObject::from(saved.clone())).unwrap_err().code(), "CONFLICT");
+	assert!(store.save_procedure(ProcedureInputObject { rev: None, ..ProcedureInputObject::from(saved.clone()) }).is_err());
+	assert_eq!(store.delete_procedure(saved.id.clone(), saved.rev.clone()).unwrap_err().code(), "CONFLICT");
+
+	let current = store.procedure_by_id(saved.id.clone()).unwrap();
+	store.delete_procedure(saved.id.clone(), current.rev).unwrap();
+	assert!(store.find_procedure(saved.id).unwrap().is_none());
+    }
+
+    #[test]
+    fn versions_are_never_overwritten() {
+	let store = MemoryStore::new();
+	let saved = store.save_procedure(xylene_rinse()).unwrap();
+	let mut snapshot = crate::procedure_versions::version_snapshot(&saved);
+	store.create_procedure_version(&snapshot).unwrap();
+	snapshot.name = "Renamed".to_string();
+	store.create_procedure_version(&snapshot).unwrap();
+	assert_eq!(store.procedure_version(saved.id.clone(), 1).unwrap().name, "Xylene rinse");
+	assert_eq!(store.procedure_version(saved.id.clone(), 2).unwrap_err().code(), "NOT_FOUND");
+
+	// nor by restoring a backup
+	let loaded = store.load_docs(vec![serde_json::to_value(&snapshot).unwrap()]).unwrap();
+	assert_eq!((loaded.created, loaded.overwritten), (0, 0));
+	assert_eq!(loaded.skipped[0].id, snapshot.id);
+	assert_eq!(store.procedure_version(saved.id, 1).unwrap().name, "Xylene rinse");
+    }
+
+    #[test]
+    fn settings_saves_check_the_rev() {
+	let store = MemoryStore::new();
+	let settings = store.settings().unwrap();
+	let saved = store.save_settings_doc(Settings { developer: true, ..settings.clone() }).unwrap();
+	assert!(saved.developer);
+	assert_eq!(store.save_settings_doc(Settings { developer: false, ..settings }).unwrap_err().code(), "CONFLICT");
+    }
+
+    fn run_of(procedure_id: &str) -> RunRecord {
+	serde_json::from_value(serde_json::json!({
+	    "type": "run",
+	    "procedure_id": procedure_id,
+	    "procedure_name": "Xylene rinse",
+	    "start_time": "2020-04-06T13:00:00Z",
+	    "end_time": "2020-04-06T13:05:00Z",
+	    "final_state": "Completed",
+	    "slide_count": 2,
+	    "jar_usage": [{"jar_number": 1, "substance": "Xylene", "immersion_seconds": 300}],
+	})).unwrap()
+    }
+
+    #[test]
+    fn trashed_procedures_can_be_restored_or_purged() {
+	let store = MemoryStore::new();
+	let saved = store.save_procedure(xylene_rinse()).unwrap();
+	assert_eq!(store.purge_procedure(saved.id.clone(), saved.rev.clone()).unwrap_err().code(), "NOT_ALLOWED");
+
+	let trashed = store.archive_procedure(saved.id.clone(), saved.rev.clone()).unwrap();
+	assert!(trashed.archived_at.is_some());
+	let restored = store.restore_procedure(saved.id.clone(), trashed.rev).unwrap();
+	assert!(restored.archived_at.is_none());
+	assert_eq!(restored.version, saved.version);
+
+	let trashed = store.archive_procedure(saved.id.clone(), restored.rev).unwrap();
+	store.purge_procedure(saved.id.clone(), trashed.rev).unwrap();
+	assert!(store.find_procedure(saved.id).unwrap().is_none());
+    }
+
+    #[test]
+    fn procedures_in_the_run_history_stay_in_the_trash() {
+	let store = MemoryStore::new();
+	let saved = store.save_procedure(xylene_rinse()).unwrap();
+	store.save_run_record(&run_of(&saved.id)).unwrap();
+	let trashed = store.archive_procedure(saved.id.clone(), saved.rev).unwrap();
+	assert_eq!(trashed.runs, Some(1));
+	assert_eq!(store.purge_procedure(saved.id.clone(), trashed.rev).unwrap_err().code(), "NOT_ALLOWED");
+	assert!(store.find_procedure(saved.id).unwrap().is_some());
+    }
+}
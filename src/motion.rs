@@ -1,5 +1,6 @@
 pub use crate::structs_and_consts::*;
 pub use crate::couchdb::*;
+pub use crate::storage::*;
 
 use gpio::{GpioIn, GpioOut};
 use std::{thread, time};
@@ -8,14 +9,15 @@ use std::sync::atomic::Ordering;
 use std::convert::TryFrom;
 use std::convert::TryInto;
 
-// Converts inches to pulses for a given stepper's configuration
-pub fn inches_to_pulses(inches: Inch, stepper: &Stepper) -> PulseCount {
-    (stepper.pulses_per_revolution as f64 * inches / stepper.travel_distance_per_turn) as u64
+// Converts a length to pulses for a given stepper's configuration.
+// This and pulses_to_length are where lengths meet the steppers, whatever unit they were given in.
+pub fn length_to_pulses(length: Length, stepper: &Stepper) -> PulseCount {
+    (stepper.pulses_per_revolution as f64 * (length / stepper.travel_distance_per_turn)) as u64
 }
 
-// Converts pulses to inches for a given stepper's configuration
-pub fn pulses_to_inches(pulses: PulseCount, stepper: &Stepper) -> Inch {
-    pulses as f64 * stepper.travel_distance_per_turn / stepper.pulses_per_revolution as f64
+// Converts pulses to a length for a given stepper's configuration
+pub fn pulses_to_length(pulses: PulseCount, stepper: &Stepper) -> Length {
+    stepper.travel_distance_per_turn * (pulses as f64 / stepper.pulses_per_revolution as f64)
 }
 
 // Generates an array of wait times to play a note for a given duration.
@@ -40,14 +42,35 @@ fn generate_note_times(note_hz: f64, duration_ms: u64, step_override: Option<u64
     times
 }
 
+// The time, from the start of a move, at which pulse i (one-indexed) of the acceleration ramp happens.
+fn ramp_time(i: u64, a: f64) -> time::Duration {
+    // If we want a constant acceleration, then given steps y, time t and acceleration a, acceleration is
+    // (d^2y / dt^2) = a
+    // Integrating gives us velocity:
+    // dy/dt = at
+    // (For our application we don't care about the +C term resulting from integration).
+    // Integrating once again gives us:
+    // y = (1/2)at^2
+    // When driving a stepper, we have to provide a signal for each pulse.
+    // Thus, we can't pick y values based on t, instead we need to find t for values y = 1,2,3,4,5,6 etc.
+    // Sovled for t: t = sqrt(2y/a).
+    // (We don't care about the negative root since negative t values are nonsensical for our application).
+    let calculated_time : f64 = (1_000_000_000.0/2.0) * (2.0/1.0 as f64).sqrt() * (i as f64 / a).sqrt();
+    let selected_time = time::Duration::from_nanos(calculated_time.round() as u64);
+    // minimum size of the signal supported by the stepper driver
+    std::cmp::max(selected_time, time::Duration::from_micros(5))
+}
+
 // Generates an array of wait times to be used to drive the stepper.
 // Size is the number of steps to move the stepper, and a is a constant acceleration to apply.
 // a is in Hz/sec BUT it has a hidden constant coefficient based upon the stepper driver settings.
 // E.g. if you set the stepper driver to 4000 steps/rev, then a is going to need to be different than if
 // it was set to 1600 steps/rev.
+// min_wait is the shortest wait allowed, which caps the speed; see min_pulse_wait.
 fn generate_wait_times(
     size: u64,
     a: f64,
+    min_wait: time::Duration,
 ) -> Vec<std::time::Duration> {
     let size = size + 1; // we generate one more tmie than we need for an intermediate array, and then
     // put it in an array that's the right size.
@@ -56,23 +79,7 @@ fn generate_wait_times(
     // We fill up the array with times halfway through and then mirror it to produce the deceleration ramp.
     let halfway = size / 2;
     for i in 1..halfway + 2 {
-	// If we want a constant acceleration, then given steps y, time t and acceleration a, acceleration is
-	// (d^2y / dt^2) = a
-	// Integrating gives us velocity:
-	// dy/dt = at
-	// (For our application we don't care about the +C term resulting from integration).
-	// Integrating once again gives us:
-	// y = (1/2)at^2
-	// When driving a stepper, we have to provide a signal for each pulse.
-	// Thus, we can't pick y values based on t, instead we need to find t for values y = 1,2,3,4,5,6 etc.
-	// Sovled for t: t = sqrt(2y/a).
-	// (We don't care about the negative root since negative t values are nonsensical for our application).
-	let calculated_time : f64 = (1_000_000_000.0/2.0) * (2.0/1.0 as f64).sqrt() * (i as f64 / a).sqrt();
-	let mut selected_time = time::Duration::from_nanos(calculated_time.round() as u64);
-	if selected_time < time::Duration::from_micros(5) {
-	    selected_time = time::Duration::from_micros(5); // minimum size of the signal supported by the stepper driver
-	}
-        times[usize::try_from(i).unwrap() - 1] = selected_time;
+        times[usize::try_from(i).unwrap() - 1] = ramp_time(i, a);
     }
     // Now mirror the array ot produce the deceleration ramp
     for i in halfway+1..size {
@@ -87,13 +94,13 @@ fn generate_wait_times(
     for i in 1..halfway + 1{
 	let t_b = times[usize::try_from(i).unwrap()];
 	let t_a = if i == 0 { time::Duration::from_nanos(0) } else { times[usize::try_from(i-1).unwrap()] };
-	wait_times[usize::try_from(i).unwrap()] = (t_b - t_a) / 2;
+	wait_times[usize::try_from(i).unwrap()] = std::cmp::max((t_b - t_a) / 2, min_wait);
     }
     for i in halfway+1..size {
          wait_times[usize::try_from(i).unwrap()] = wait_times[usize::try_from(size - i).unwrap()];
     }
     wait_times.remove(0); // the first element is always 0 s, (since we start at index 1) so taking that one out.
-    return wait_times;
+    wait_times
 }
 
 // Plays a note on a given stepper at a given frequency.
@@ -184,7 +191,7 @@ pub fn run_motor_test(pi: &mut Pi, axis: AxisDirection, forward: bool, accelerat
     }
 
     println!("Running for {} steps", number_of_turns*4000);
-    let times = generate_wait_times(number_of_turns*4000, acceleration_constant);
+    let times = generate_wait_times(number_of_turns*4000, acceleration_constant, time::Duration::from_nanos(0));
 
     // Enable and set the direction
     stepper.ena.set_low().expect("Couldn't turn on ena"); // logic is reversed to due transistor
@@ -219,8 +226,88 @@ pub fn run_motor_test(pi: &mut Pi, axis: AxisDirection, forward: bool, accelerat
     "Finished motor test.".to_string()
 }
 
+// Time spent in move_steps outside of the pulse train: the 1 ms sleeps around enabling and disabling the stepper.
+const MOVE_SETUP_TIME: time::Duration = time::Duration::from_millis(4);
+
+// Approximate latency added to each sleep in the pulse train by waking the thread and toggling the GPIO pin.
+// Tune this against timed moves on the instrument if estimates drift.
+const PULSE_SLEEP_OVERHEAD: time::Duration = time::Duration::from_micros(20);
+
+// The shortest wait between pulse edges that keeps a stepper at or below max_speed, the distance per second.
+// Each pulse is two waits, one with the signal HIGH and one LOW. A max_speed of 0 means no limit.
+fn min_pulse_wait(max_speed: Length, pulses_per_revolution: u64, travel_distance_per_turn: Length) -> time::Duration {
+    if max_speed <= Length::inches(0.0) {
+	return time::Duration::from_nanos(0);
+    }
+    let pulses_per_second = pulses_per_revolution as f64 * (max_speed / travel_distance_per_turn);
+    time::Duration::from_nanos((1_000_000_000.0 / (2.0 * pulses_per_second)) as u64)
+}
+
+// The settings a move uses: those of the active run if there is one, otherwise the instrument's current ones.
+pub fn current_motion_settings(pi: &Pi, opt_pes: Option<&ProcedureExecutionState>) -> MotionSettings {
+    if let Some(pes) = opt_pes {
+	if let Some(run) = pes.active_run.lock().unwrap().as_ref() {
+	    return run.motion_settings.clone();
+	}
+    }
+    pi.motion_settings.read().unwrap().clone()
+}
+
+// Rereads the motion settings from the settings doc, e.g. after a backup was restored over it.
+// The ones in use are kept if it can't be read.
+pub fn reload_motion_settings(store: &dyn SettingsStore, motion_settings: &SharedMotionSettings) {
+    match store.settings() {
+	Ok(settings) => *motion_settings.write().unwrap() = MotionSettings::from(&settings),
+	Err(e) => println!("Couldn't read the settings: {}", e.message()),
+    }
+}
+
+// Sets the red and green lights, unless the status lights are turned off in the settings, in which case they're kept dark.
+pub fn set_status_lights(pi: &mut Pi, opt_pes: Option<&ProcedureExecutionState>, green: bool, red: bool) {
+    let enabled = current_motion_settings(pi, opt_pes).status_lights;
+    pi.green_light.set_value(enabled && green).expect("Couldn't set green light");
+    pi.red_light.set_value(enabled && red).expect("Couldn't set red light");
+}
+
+// Adds up the first count wait times of the acceleration ramp generate_wait_times produces, without generating them.
+// The waits shrink as the stepper speeds up until min_wait holds them; the unheld ones telescope to a difference of ramp times.
+fn ramp_wait_sum(count: u64, a: f64, min_wait: time::Duration) -> time::Duration {
+    let unheld_wait = |i: u64| (ramp_time(i + 1, a) - ramp_time(i, a)) / 2;
+    // binary search for the first wait min_wait holds, or count + 1 if none are
+    let (mut low, mut high) = (1, count + 1);
+    while low < high {
+	let mid = low + (high - low) / 2;
+	if unheld_wait(mid) < min_wait { high = mid; } else { low = mid + 1; }
+    }
+    let first_held = low;
+    (ramp_time(first_held, a) - ramp_time(1, a)) / 2
+	+ time::Duration::from_nanos(min_wait.as_nanos() as u64 * (count + 1 - first_held))
+}
+
+// Predicts how long move_steps will take to move a given number of pulses on an axis.
+// This uses the same acceleration profile as move_steps, so it includes the ramp up and ramp down.
+pub fn estimate_move_duration(pulses: PulseCount, axis: &AxisDirection, settings: &MotionSettings) -> time::Duration {
+    if pulses == 0 {
+	return time::Duration::from_nanos(0);
+    }
+    let a = settings.acceleration(axis);
+    let min_wait = min_pulse_wait(settings.max_speed(axis), PULSES_PER_REVOLUTION, TRAVEL_DISTANCE_PER_TURN);
+    // generate_wait_times mirrors the acceleration ramp to decelerate, so the waits are two runs of the ramp
+    let halfway = (pulses + 1) / 2;
+    let waits = ramp_wait_sum(halfway, a, min_wait) + ramp_wait_sum(pulses - halfway, a, min_wait);
+    // each pulse sleeps once with the signal HIGH and once with it LOW
+    MOVE_SETUP_TIME + waits * 2 + time::Duration::from_nanos(PULSE_SLEEP_OVERHEAD.as_nanos() as u64 * 2 * pulses)
+}
+
+// Predicts how long it takes to move a given distance on an axis.
+pub fn estimate_move_seconds(distance: Length, axis: &AxisDirection, settings: &MotionSettings) -> f64 {
+    let pulses = (PULSES_PER_REVOLUTION as f64 * (distance.abs() / TRAVEL_DISTANCE_PER_TURN)) as u64;
+    estimate_move_duration(pulses, axis, settings).as_secs_f64()
+}
+
 // Moves the stepper by a certain number of steps
 pub fn move_steps(pi: &mut Pi, axis: AxisDirection, forward: bool, pulses: u64, is_homing: bool, opt_pes: Option<&ProcedureExecutionState>, skip_soft_estop_check: bool) -> MoveResult {
+    let settings = current_motion_settings(pi, opt_pes);
     let stepper = match axis {
         AxisDirection::X => &mut pi.stepper_x,
         AxisDirection::Z => &mut pi.stepper_z,
@@ -244,7 +331,8 @@ pub fn move_steps(pi: &mut Pi, axis: AxisDirection, forward: bool, pulses: u64,
     }
 
     // generate the pulses
-    let times = generate_wait_times(pulses,64_000_000.0);
+    let min_wait = min_pulse_wait(settings.max_speed(&axis), stepper.pulses_per_revolution, stepper.travel_distance_per_turn);
+    let times = generate_wait_times(pulses, settings.acceleration(&axis), min_wait);
 
     // Enable and set the direction
     stepper.ena.set_low().expect("Couldn't turn on ena"); // logic is reversed to due transistor
@@ -258,7 +346,7 @@ pub fn move_steps(pi: &mut Pi, axis: AxisDirection, forward: bool, pulses: u64,
 
     let mut moved_pulses = 0;
     for t in times.iter() {
-        moved_pulses = moved_pulses + 1;
+        moved_pulses += 1;
         // check limit switch
         if !forward
             && stepper.limit_switch_low.is_some()
@@ -295,15 +383,15 @@ pub fn move_steps(pi: &mut Pi, axis: AxisDirection, forward: bool, pulses: u64,
         if  bool::from(pi.estop.read_value().unwrap()) {
             hit_e_stop = true;
 	    println!("Hit estop!");
-	    if opt_pes.is_some() { 
-		opt_pes.unwrap().atm.store(ProcedureExecutionStateEnum::Paused, Ordering::Relaxed);
+	    if let Some(pes) = opt_pes {
+		pes.atm.store(ProcedureExecutionStateEnum::Paused, Ordering::Relaxed);
 		pi.red_light.set_low().expect("Couldn't run off red light.");
 	    }
             break;
         }
 	// check the software estop
-	if opt_pes.is_some() && !skip_soft_estop_check{
-	    let state = opt_pes.unwrap().atm.load(Ordering::Relaxed); // == ProcedureExecutionStateEnum::Paused;
+	if let Some(pes) = opt_pes.filter(|_| !skip_soft_estop_check) {
+	    let state = pes.atm.load(Ordering::Relaxed); // == ProcedureExecutionStateEnum::Paused;
 	    if state == ProcedureExecutionStateEnum::Paused || state == ProcedureExecutionStateEnum::Stopped {
 		hit_e_stop = true;
 		break;
@@ -339,10 +427,7 @@ pub fn move_steps(pi: &mut Pi, axis: AxisDirection, forward: bool, pulses: u64,
         if !forward {
             stepper.pos = Some(0);
         } else {
-            stepper.pos = Some(
-                (stepper.position_limit * stepper.pulses_per_revolution as f64
-                    / stepper.travel_distance_per_turn) as u64,
-            );
+            stepper.pos = Some(length_to_pulses(stepper.position_limit, stepper));
         }
     } else if is_homing {
         return MoveResult::FailedToHome;
@@ -373,9 +458,9 @@ pub fn move_steps(pi: &mut Pi, axis: AxisDirection, forward: bool, pulses: u64,
     MoveResult::MovedFullDistance
 }
 
-// Moves to a position given in inches.
-pub fn move_to_pos(pi: &mut Pi, axis: AxisDirection, inches: Inch, opt_pes: Option<&ProcedureExecutionState>, skip_soft_estop_check: bool) -> MoveResult {
-    println!("move_to_pos axis: {}  inches: {}", axis, inches);
+// Moves to a position on an axis.
+pub fn move_to_pos(pi: &mut Pi, axis: AxisDirection, position: Length, opt_pes: Option<&ProcedureExecutionState>, skip_soft_estop_check: bool) -> MoveResult {
+    println!("move_to_pos axis: {}  position: {}", axis, position);
     let is_not_homed = get_stepper(pi, &axis).pos.is_none();
     
     if is_not_homed {
@@ -387,7 +472,7 @@ pub fn move_to_pos(pi: &mut Pi, axis: AxisDirection, inches: Inch, opt_pes: Opti
     
     let stepper = get_stepper(pi, &axis);
     let cur_pos = stepper.pos.unwrap();
-    let dest_pos = inches_to_pulses(inches, stepper);
+    let dest_pos = length_to_pulses(position, stepper);
     let forward = cur_pos < dest_pos;
     let pulses = if cur_pos < dest_pos {
         dest_pos - cur_pos
@@ -398,11 +483,13 @@ pub fn move_to_pos(pi: &mut Pi, axis: AxisDirection, inches: Inch, opt_pes: Opti
 }
 
 pub fn move_to_up_position(pi: &mut Pi, opt_pes: Option<&ProcedureExecutionState>, skip_soft_estop_check: bool) -> MoveResult {
-    move_to_pos(pi, AxisDirection::Z, UP_POSITION, opt_pes, skip_soft_estop_check)
+    let up_position = current_motion_settings(pi, opt_pes).up_position;
+    move_to_pos(pi, AxisDirection::Z, up_position, opt_pes, skip_soft_estop_check)
 }
 
 pub fn move_to_down_position(pi: &mut Pi, opt_pes: Option<&ProcedureExecutionState>, skip_soft_estop_check: bool) -> MoveResult {
-    move_to_pos(pi, AxisDirection::Z, 0.0, opt_pes, skip_soft_estop_check)
+    let down_position = current_motion_settings(pi, opt_pes).down_position;
+    move_to_pos(pi, AxisDirection::Z, down_position, opt_pes, skip_soft_estop_check)
 }
 
 pub fn move_to_left_position(pi: &mut Pi, opt_pes: Option<&ProcedureExecutionState>) -> MoveResult {
@@ -423,15 +510,20 @@ pub fn home(pi: &mut Pi, opt_pes: Option<&ProcedureExecutionState>) -> MoveResul
     ret_two
 }
 
+// The x-axis position of a given one-indexed jar.
+pub fn jar_position(jar_number: i32) -> Length {
+    LEFT_POSITION + JAR_SPACING * (jar_number - 1) as f64
+}
+
 // This is used to determine whether or not move_to_jar needs to move to the up position and move over before
 // moving down.
 fn known_to_be_at_jar_position(pi: &mut Pi, jar_number: i32) -> bool {
     if pi.stepper_x.pos.is_none() {
 	return false;
     }
-    let current_inches : Inch = pulses_to_inches(pi.stepper_x.pos.unwrap(), &pi.stepper_x);
-    let target_inches  : Inch = LEFT_POSITION + JAR_SPACING * (jar_number - 1) as f64;
-    (target_inches - current_inches).abs() < 0.1
+    let current : Length = pulses_to_length(pi.stepper_x.pos.unwrap(), &pi.stepper_x);
+    let target  : Length = jar_position(jar_number);
+    (target - current).abs() < Length::inches(0.1)
 }
 
 pub fn move_to_jar(pi: &mut Pi, jar_number: i32, opt_pes: Option<&ProcedureExecutionState>) -> MoveResult {
@@ -447,7 +539,7 @@ pub fn move_to_jar(pi: &mut Pi, jar_number: i32, opt_pes: Option<&ProcedureExecu
 	let ret = move_to_pos(
             pi,
             AxisDirection::X,
-            LEFT_POSITION + JAR_SPACING * (jar_number - 1) as f64,
+            jar_position(jar_number),
 	    opt_pes,
 	    false
 	);
@@ -463,3 +555,44 @@ pub fn move_to_jar(pi: &mut Pi, jar_number: i32, opt_pes: Option<&ProcedureExecu
     println!("Result of move_to_down_position {:?}", ret);
     ret
 }
+
+// Moves the rack up and over a jar without lowering it, so the operator can get at the slides or the jar.
+pub fn move_over_jar(pi: &mut Pi, jar_number: i32, opt_pes: Option<&ProcedureExecutionState>) -> MoveResult {
+    let ret: MoveResult = move_to_up_position(pi, opt_pes, false);
+    println!("Result of move_to_up_position {:?}", ret);
+    if ret != MoveResult::MovedFullDistance {
+	return ret;
+    }
+    let ret = move_to_pos(pi, AxisDirection::X, jar_position(jar_number), opt_pes, false);
+    println!("Result of move_to_pos {:?}", ret);
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The same sum estimate_move_duration used to take, one wait time at a time.
+    fn summed_move_duration(pulses: PulseCount, axis: &AxisDirection, settings: &MotionSettings) -> time::Duration {
+	let min_wait = min_pulse_wait(settings.max_speed(axis), PULSES_PER_REVOLUTION, TRAVEL_DISTANCE_PER_TURN);
+	let mut duration = MOVE_SETUP_TIME;
+	for t in generate_wait_times(pulses, settings.acceleration(axis), min_wait).iter() {
+	    duration += (*t + PULSE_SLEEP_OVERHEAD) * 2;
+	}
+	duration
+    }
+
+    #[test]
+    fn estimated_move_duration_matches_the_wait_times() {
+	let mut limited = MotionSettings::default();
+	limited.x_max_speed = Length::inches(0.5);
+	for settings in [MotionSettings::default(), limited].iter() {
+	    for pulses in [1, 2, 3, 100, 4001, 40_000, 635_000].iter() {
+		let estimated = estimate_move_duration(*pulses, &AxisDirection::X, settings).as_nanos() as i128;
+		let summed = summed_move_duration(*pulses, &AxisDirection::X, settings).as_nanos() as i128;
+		// halving each wait rounds down by up to a nanosecond, twice per pulse
+		assert!((estimated - summed).abs() <= 2 * *pulses as i128, "{} pulses: {} ns vs {} ns", pulses, estimated, summed);
+	    }
+	}
+    }
+}
@@ -0,0 +1,745 @@
+pub use crate::structs_and_consts::*;
+pub use crate::motion::*;
+pub use crate::couchdb::*;
+pub use crate::duration_estimate::*;
+pub use crate::validation::*;
+pub use crate::reagent_usage::*;
+pub use crate::procedure_versions::*;
+pub use crate::changes_feed::*;
+
+use chrono::{Duration, Utc};
+use gpio::GpioIn;
+use juniper::FieldResult;
+use std::convert::TryInto;
+use std::sync::Mutex;
+use std::sync::atomic::Ordering;
+use std::time::Instant;
+use std::{thread, time};
+
+// Builds the status of a run. Elapsed times and the completion estimate are calculated as of now.
+fn build_run_status(active_run: &ActiveRun, pes: &ProcedureExecutionState) -> ProcedureRunStatus {
+    let now = Utc::now();
+    let proc = &active_run.procedure;
+    let run_state = pes.atm.load(Ordering::Relaxed);
+    let step_index : usize = (active_run.current_procedure_step_number - 1).max(0).try_into().unwrap();
+    let cycle_index = (active_run.current_cycle_number - 1).max(0);
+    let sequence_index = active_run.current_sequence_index;
+    let step_time = proc.procedure_steps.get(step_index).map(|step| step.time_in_seconds).unwrap_or(0);
+    let immersing = active_run.activity == RunActivity::Immersing;
+
+    let current_step_seconds_remaining : i32 = if immersing {
+	pes.seconds_remaining.load(Ordering::Relaxed).try_into().unwrap_or(i32::max_value())
+    } else {
+	step_time
+    };
+
+    let estimated_seconds_remaining = match active_run.activity {
+	RunActivity::Draining => estimate_raise_seconds(&active_run.motion_settings),
+	RunActivity::Immersing | RunActivity::WaitingForOperator => {
+	    let rack = proc.procedure_steps.get(step_index).map(rack_after_step).unwrap_or(RackPosition::Homed);
+	    current_step_seconds_remaining as f64
+		+ estimate_seconds_from_step(&active_run.motion_settings, proc, &active_run.step_sequence, cycle_index, sequence_index + 1, rack)
+	}
+	_ => estimate_seconds_from_step(&active_run.motion_settings, proc, &active_run.step_sequence, cycle_index, sequence_index,
+					rack_before_step(proc, &active_run.step_sequence, cycle_index, sequence_index)),
+    };
+
+    ProcedureRunStatus {
+	current_procedure_id: proc.id.clone(),
+	current_procedure_name: proc.name.clone(),
+	current_procedure_step_number: active_run.current_procedure_step_number,
+	current_cycle_number: active_run.current_cycle_number,
+	current_group_path: active_run.current_group_path.clone(),
+	current_procedure_step_start_time: active_run.current_procedure_step_start_time,
+	current_step_elapsed_seconds: if immersing { step_time - current_step_seconds_remaining } else { 0 },
+	current_step_seconds_remaining,
+	run_start_time: active_run.run_start_time,
+	total_elapsed_seconds: (now - active_run.run_start_time).num_seconds() as i32,
+	estimated_seconds_remaining: estimated_seconds_remaining.round() as i32,
+	estimated_completion_time: now + Duration::milliseconds((estimated_seconds_remaining * 1000.0) as i64),
+	activity: if run_state == ProcedureExecutionStateEnum::Paused { RunActivity::Paused } else { active_run.activity },
+	operator_message: active_run.operator_message.clone(),
+	run_state,
+    }
+}
+
+// Returns the current run status, or None if no procedure is running.
+pub fn current_run_status(pes: &ProcedureExecutionState) -> Option<ProcedureRunStatus> {
+    let active_run = pes.active_run.lock().unwrap();
+    active_run.as_ref().map(|active_run| build_run_status(active_run, pes))
+}
+
+// The error for starting a run of a procedure that changed after the operator loaded it.
+fn changed_procedure_err(store: &dyn ProcedureStore, proc: &Procedure) -> FieldResult<ProcedureRunStatus> {
+    let current = store.find_procedure(proc.id.clone())?.unwrap_or_else(|| proc.clone());
+    Err(StorageError::Conflict {
+	message: format!("{} was changed while the run was being started. Review the procedure and start it again.", proc.name),
+	current: Some(CurrentRevision {
+	    id: current.id.clone(),
+	    rev: current.rev.clone(),
+	    version: Some(current_version(&current)),
+	}),
+    }.into())
+}
+
+// Loads the procedure and kicks off a run of it on its own thread.
+// slide_count is optional, and is used to count how many slides have been through each jar.
+// expected_rev is the _rev of the procedure the operator is looking at; if given, the run is only started if that's still current.
+// Returns the initial run status, or an error if a run is already active, the procedure can't be found or is invalid,
+// the jars loaded in the rack don't match the procedure (unless ignore_rack_mismatch is set), or the procedure changed.
+#[allow(clippy::too_many_arguments)] // the shared state it checks against, then what the caller asked for
+pub fn start_run(shared_pi: &SharedPi, pes: &SharedProcedureExecutionState, store: &SharedProcedureStore, racks: &SharedRackStore, changes: &ChangeTracker,
+		 id: String, expected_rev: Option<String>, ignore_rack_mismatch: bool, slide_count: Option<i32>) -> FieldResult<ProcedureRunStatus> {
+    // fail fast, without doing any IO, if there's obviously a run already
+    if pes.active_run.lock().unwrap().is_some() {
+	return juniper_err("A procedure is already running.".to_string());
+    }
+
+    // the checks below hit the store, so they're done without holding the active_run lock, which the status monitor needs
+    let proc = store.procedure_by_id(id)?;
+    if expected_rev.as_ref().map_or(false, |rev| rev != &proc.rev) {
+	return changed_procedure_err(&**store, &proc);
+    }
+    if proc.archived_at.is_some() {
+	return juniper_err(format!("{} is in the trash. Restore it before running it.", proc.name));
+    }
+    let validation = validate_procedure(&ProcedureInputObject::from(proc.clone()));
+    if !validation.valid {
+	return validation_err(&validation);
+    }
+    if !ignore_rack_mismatch {
+	let rack_check = check_rack(&proc, &racks.rack()?);
+	if !rack_check.valid {
+	    return validation_err(&rack_check);
+	}
+    }
+    // the run record refers to this version, so make sure it has been snapshotted
+    ensure_version_snapshot(&**store, &proc)?;
+    // the checks above take a while, so make sure the feed hasn't seen a save since the revision the operator loaded
+    if let Some(rev) = &expected_rev {
+	if changes.procedure_changed_since(&proc.id, rev) {
+	    return changed_procedure_err(&**store, &proc);
+	}
+    }
+
+    let run = ActiveRun {
+	procedure: proc.clone(),
+	run_start_time: Utc::now(),
+	current_procedure_step_number : 0,
+	current_cycle_number: 0,
+	current_procedure_step_start_time: None,
+	current_sequence_index: 0,
+	current_group_path: Vec::new(),
+	activity: RunActivity::Moving,
+	operator_message: None,
+	slide_count,
+	motion_settings: pes.motion_settings.read().unwrap().clone(),
+	step_sequence: step_sequence(&proc),
+    };
+    let sequence = run.step_sequence.clone();
+    let initial_status = claim_run(pes, run)?;
+
+    let shared_pi = shared_pi.clone();
+    let pes = pes.clone();
+    let store = store.clone();
+    let racks = racks.clone();
+    thread::spawn(move || {
+	let _guard = RunGuard { pes: &pes };
+	execute_procedure(&shared_pi, &pes, &*store, &*racks, proc, &sequence);
+    });
+    Ok(initial_status)
+}
+
+// Makes run the active run, unless there's one already or a backup is being restored.
+// The check-and-set is done under one lock so two starts, or a start and a restore, can't race each other.
+fn claim_run(pes: &ProcedureExecutionState, run: ActiveRun) -> FieldResult<ProcedureRunStatus> {
+    let mut active_run = pes.active_run.lock().unwrap();
+    if active_run.is_some() {
+	return juniper_err("A procedure is already running.".to_string());
+    }
+    if pes.restoring.load(Ordering::SeqCst) {
+	return juniper_err("A backup is being restored. Start the run once it's done.".to_string());
+    }
+    pes.limit_switch_hit_unexpectedly.store(false, Ordering::Relaxed);
+    pes.atm.store(ProcedureExecutionStateEnum::Running, Ordering::Relaxed);
+    let initial_status = build_run_status(&run, pes);
+    *active_run = Some(run);
+    Ok(initial_status)
+}
+
+// Calls restore with runs kept from starting until it returns. Refused if a procedure is running or another
+// backup is being restored. The flag is set under the active_run lock that claim_run checks it under, so they can't race.
+pub fn while_restoring<T>(pes: &ProcedureExecutionState, restore: impl FnOnce() -> T) -> FieldResult<T> {
+    {
+	let active_run = pes.active_run.lock().unwrap();
+	if active_run.is_some() {
+	    return juniper_err("A backup can't be restored while a procedure is running.".to_string());
+	}
+	if pes.restoring.swap(true, Ordering::SeqCst) {
+	    return juniper_err("Another backup is already being restored.".to_string());
+	}
+    }
+    let _guard = RestoreGuard { pes };
+    Ok(restore())
+}
+
+// Lets runs start again once a restore is over, even if it panicked.
+struct RestoreGuard<'a> {
+    pes: &'a ProcedureExecutionState,
+}
+
+impl<'a> Drop for RestoreGuard<'a> {
+    fn drop(&mut self) {
+	self.pes.restoring.store(false, Ordering::SeqCst);
+    }
+}
+
+// Clears the active run if the runner thread panics, so the instrument isn't left refusing new runs until a restart.
+struct RunGuard<'a> {
+    pes: &'a ProcedureExecutionState,
+}
+
+impl<'a> Drop for RunGuard<'a> {
+    fn drop(&mut self) {
+	if thread::panicking() {
+	    println!("The run ended unexpectedly.");
+	    let mut active_run = self.pes.active_run.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+	    *active_run = None;
+	    self.pes.atm.store(ProcedureExecutionStateEnum::Stopped, Ordering::Relaxed);
+	}
+    }
+}
+
+pub fn pause_run(pes: &ProcedureExecutionState) -> FieldResult<ProcedureRunStatus> {
+    if pes.active_run.lock().unwrap().is_none() {
+	return juniper_err("No procedure is running.".to_string());
+    }
+    match pes.atm.load(Ordering::Relaxed) {
+	ProcedureExecutionStateEnum::Running => {
+	    pes.atm.store(ProcedureExecutionStateEnum::Paused, Ordering::Relaxed);
+	}
+	ProcedureExecutionStateEnum::Paused => {
+	    return juniper_err("The procedure is already paused.".to_string());
+	}
+	state => {
+	    return juniper_err(format!("Can't pause a procedure that is {:?}.", state));
+	}
+    }
+    run_status_or_err(pes)
+}
+
+pub fn resume_run(pes: &ProcedureExecutionState) -> FieldResult<ProcedureRunStatus> {
+    if pes.active_run.lock().unwrap().is_none() {
+	return juniper_err("No procedure is running.".to_string());
+    }
+    match pes.atm.load(Ordering::Relaxed) {
+	ProcedureExecutionStateEnum::Paused => {
+	    pes.atm.store(ProcedureExecutionStateEnum::Running, Ordering::Relaxed);
+	}
+	ProcedureExecutionStateEnum::Running => {
+	    return juniper_err("The procedure is already running.".to_string());
+	}
+	state => {
+	    return juniper_err(format!("Can't resume a procedure that is {:?}.", state));
+	}
+    }
+    run_status_or_err(pes)
+}
+
+pub fn stop_run(pes: &ProcedureExecutionState) -> FieldResult<ProcedureRunStatus> {
+    if pes.active_run.lock().unwrap().is_none() {
+	return juniper_err("No procedure is running.".to_string());
+    }
+    match pes.atm.load(Ordering::Relaxed) {
+	ProcedureExecutionStateEnum::Running | ProcedureExecutionStateEnum::Paused => {
+	    pes.atm.store(ProcedureExecutionStateEnum::Stopped, Ordering::Relaxed);
+	}
+	state => {
+	    return juniper_err(format!("Can't stop a procedure that is {:?}.", state));
+	}
+    }
+    run_status_or_err(pes)
+}
+
+// Lets the run carry on from an operator action step, as if the green button had been pressed.
+pub fn confirm_operator_action(pes: &ProcedureExecutionState) -> FieldResult<ProcedureRunStatus> {
+    {
+	let active_run = pes.active_run.lock().unwrap();
+	match active_run.as_ref() {
+	    None => return juniper_err("No procedure is running.".to_string()),
+	    Some(run) if run.activity != RunActivity::WaitingForOperator => {
+		return juniper_err("The procedure isn't waiting for the operator.".to_string());
+	    }
+	    Some(_) if pes.atm.load(Ordering::Relaxed) == ProcedureExecutionStateEnum::Paused => {
+		return juniper_err("Resume the procedure before confirming the operator action.".to_string());
+	    }
+	    Some(_) => pes.operator_action_confirmed.store(true, Ordering::Relaxed),
+	}
+    }
+    run_status_or_err(pes)
+}
+
+// The run may finish between the state change and reading the status back, so that case is reported as an error
+// rather than unwrapped.
+fn run_status_or_err(pes: &ProcedureExecutionState) -> FieldResult<ProcedureRunStatus> {
+    match current_run_status(pes) {
+	Some(status) => Ok(status),
+	None => juniper_err("The procedure finished before its status could be read.".to_string()),
+    }
+}
+
+fn update_active_run<F: FnOnce(&mut ActiveRun)>(pes: &ProcedureExecutionState, update: F) {
+    let mut active_run = pes.active_run.lock().unwrap();
+    if let Some(run) = active_run.as_mut() {
+	update(run);
+    }
+}
+
+// Turns green button readings into presses. A button that is already held down when watching starts has to be
+// released and pressed again, so a press that resumed the run just beforehand doesn't count.
+struct ButtonPresses {
+    held: bool,
+}
+
+impl ButtonPresses {
+    fn new() -> ButtonPresses {
+	ButtonPresses { held: true }
+    }
+
+    fn pressed(&mut self, down: bool) -> bool {
+	let pressed = down && !self.held;
+	self.held = down;
+	pressed
+    }
+}
+
+// Holds the run at an operator action step until the operator presses the green button or confirms in the UI.
+// The green light is lit while waiting. As in raise_rack, while the run is paused a press resumes it rather than
+// confirming the action, and the e-stop pauses it.
+fn wait_for_operator(pi_mutex: &Mutex<Pi>, pes: &ProcedureExecutionState, step_number: i32, message: String) -> OperatorActionRecord {
+    pes.operator_action_confirmed.store(false, Ordering::Relaxed);
+    update_active_run(pes, |run| {
+	run.current_procedure_step_start_time = Some(Utc::now());
+	run.activity = RunActivity::WaitingForOperator;
+	run.operator_message = Some(message.clone());
+    });
+
+    let start_instant = Instant::now();
+    let mut green_button = ButtonPresses::new();
+    let mut confirmed = false;
+    while !confirmed {
+	{
+	    let pi = &mut *pi_mutex.lock().unwrap();
+	    if pes.atm.load(Ordering::Relaxed) == ProcedureExecutionStateEnum::Stopped {
+		break;
+	    }
+	    if bool::from(pi.estop.read_value().unwrap()) {
+		pes.atm.store(ProcedureExecutionStateEnum::Paused, Ordering::Relaxed);
+	    }
+	    let pressed = green_button.pressed(bool::from(pi.green_button.read_value().unwrap()));
+	    set_status_lights(pi, Some(pes), true, false);
+	    if pes.atm.load(Ordering::Relaxed) == ProcedureExecutionStateEnum::Paused {
+		if pressed {
+		    pes.atm.store(ProcedureExecutionStateEnum::Running, Ordering::Relaxed);
+		}
+	    } else {
+		confirmed = pes.operator_action_confirmed.load(Ordering::Relaxed) || pressed;
+		if confirmed {
+		    set_status_lights(pi, Some(pes), false, true);
+		}
+	    }
+	}
+	thread::sleep(time::Duration::from_millis(20));
+    }
+
+    update_active_run(pes, |run| {
+	run.operator_message = None;
+    });
+    OperatorActionRecord {
+	step_number,
+	message,
+	wait_seconds: start_instant.elapsed().as_secs() as i32,
+	confirmed,
+    }
+}
+
+// Raises the rack to the up position, waiting out any pause. The rack is raised even if the run was stopped.
+fn raise_rack(pi_mutex: &Mutex<Pi>, pes: &ProcedureExecutionState) {
+    let pi = &mut *pi_mutex.lock().unwrap();
+    loop {
+	let state = pes.atm.load(Ordering::Relaxed);
+	if state == ProcedureExecutionStateEnum::Running ||
+	    state == ProcedureExecutionStateEnum::Stopped
+	{
+	    println!("============== Running move_to_up ");
+	    set_status_lights(pi, Some(pes), false, true);
+	    let ret = move_to_up_position( pi, Some(pes), true);
+	    if ret == MoveResult::MovedFullDistance {
+		break;
+	    }
+	}
+	if pes.atm.load(Ordering::Relaxed) == ProcedureExecutionStateEnum::Paused {
+	    set_status_lights(pi, Some(pes), true, false);
+	    if bool::from(pi.green_button.read_value().unwrap()) {
+		pes.atm.store(ProcedureExecutionStateEnum::Running, Ordering::Relaxed);
+		set_status_lights(pi, Some(pes), false, true);
+	    }
+	}
+	thread::sleep(time::Duration::from_millis(10));
+    }
+}
+
+// Holds the rack where it is for the given number of seconds so the slides drip off. Time spent paused doesn't count,
+// and a stop ends it.
+fn hold_to_drain(pi_mutex: &Mutex<Pi>, pes: &ProcedureExecutionState, seconds: i32) {
+    let mut remaining = time::Duration::from_secs(seconds.max(0) as u64);
+    let mut last_instant = Instant::now();
+    while remaining > time::Duration::from_nanos(0) {
+	let state = pes.atm.load(Ordering::Relaxed);
+	if state == ProcedureExecutionStateEnum::Stopped {
+	    break;
+	}
+	let elapsed = last_instant.elapsed();
+	last_instant = Instant::now();
+	if state == ProcedureExecutionStateEnum::Running {
+	    remaining = remaining.checked_sub(elapsed).unwrap_or_default();
+	}
+	{
+	    let pi = &mut *pi_mutex.lock().unwrap();
+	    if bool::from(pi.estop.read_value().unwrap()) {
+		pes.atm.store(ProcedureExecutionStateEnum::Paused, Ordering::Relaxed);
+	    }
+	    if pes.atm.load(Ordering::Relaxed) == ProcedureExecutionStateEnum::Paused {
+		set_status_lights(pi, Some(pes), true, false);
+		if bool::from(pi.green_button.read_value().unwrap()) {
+		    pes.atm.store(ProcedureExecutionStateEnum::Running, Ordering::Relaxed);
+		    set_status_lights(pi, Some(pes), false, true);
+		    last_instant = Instant::now();
+		}
+	    }
+	}
+	thread::sleep(time::Duration::from_millis(20));
+    }
+}
+
+// Runs the procedure from start to finish, following the run's step sequence so the status and estimates line up
+// with what the runner does. This blocks until the procedure completes or is stopped, so it is expected to be called
+// on its own thread (see start_run).
+pub fn execute_procedure(pi_mutex: &Mutex<Pi>, pes: &ProcedureExecutionState, store: &dyn ProcedureStore, racks: &dyn RackStore,
+			 proc: Procedure, sequence: &[SequencedStep]) {
+    {
+	*pes.current_procedure.lock().unwrap() = Some(proc.clone());
+	let pi = &mut *pi_mutex.lock().unwrap();
+	set_status_lights(pi, Some(pes), false, true);
+    }
+
+    let motion_settings = pes.active_run.lock().unwrap().as_ref().map(|run| run.motion_settings.clone()).unwrap_or_default();
+    let mut rack = RackPosition::Homed;
+    let mut jar_usage : Vec<JarUsage> = Vec::new();
+    let mut operator_actions : Vec<OperatorActionRecord> = Vec::new();
+    let num_repeats = match proc.repeat {
+	Some(v) => v,
+	None => 1
+    };
+    // loop over repeats
+    for repeat_num in 0..num_repeats {
+	println!("Repeat #: {}",num_repeats);
+	// loop over steps, with step groups expanded
+	println!("proc.procedure_steps: {:?}", proc.procedure_steps);
+	for (sequence_index, sequenced) in sequence.iter().enumerate() {
+	    let index = sequenced.step_index;
+	    let step = &proc.procedure_steps[index];
+	    if pes.atm.load(Ordering::Relaxed) == ProcedureExecutionStateEnum::Stopped {
+		break; // end the procedure if the user stopped it
+	    }
+	    pes.seconds_remaining.store(step.time_in_seconds.max(0).try_into().unwrap(), Ordering::Relaxed);
+	    update_active_run(pes, |run| {
+		run.current_cycle_number = repeat_num + 1;
+		run.current_procedure_step_number = (index + 1).try_into().unwrap();
+		run.current_sequence_index = sequence_index;
+		run.current_group_path = sequenced.group_path.clone();
+		run.current_procedure_step_start_time = None;
+		run.activity = RunActivity::Moving;
+	    });
+	    // raise the slides out of the last jar and let them drain before moving them to a different one
+	    let leaving_jar = match rack {
+		RackPosition::In(jar) => jar != step.jar_number,
+		_ => false,
+	    };
+	    if leaving_jar && motion_settings.drain_between_steps_seconds > 0 {
+		raise_rack(pi_mutex, pes);
+		hold_to_drain(pi_mutex, pes, motion_settings.drain_between_steps_seconds);
+	    }
+	    println!("Trying to grab the lock.");
+	    // grab the lock
+	    {
+		let pi = &mut *pi_mutex.lock().unwrap();
+		println!("Step: {:?}",step);
+		// move to the jar
+		println!("Entering loop B");
+		loop {
+		    let state = pes.atm.load(Ordering::Relaxed);
+		    if state == ProcedureExecutionStateEnum::Running {
+			println!("============== Running move_to_jar {:?} ", step.jar_number);
+			set_status_lights(pi, Some(pes), false, true);
+			let ret = match step.step_type {
+			    StepType::Immerse => move_to_jar( pi, step.jar_number, Some(pes) ),
+			    StepType::OperatorAction => move_over_jar( pi, step.jar_number, Some(pes) ),
+			};
+			if ret == MoveResult::MovedFullDistance {
+			    pes.limit_switch_hit_unexpectedly.store(false, Ordering::Relaxed);
+			    break;
+			}
+			if ret == MoveResult::HitLimitSwitch {
+			    println!("move_to_jar hit a limit switch!");
+			    pes.limit_switch_hit_unexpectedly.store(true, Ordering::Relaxed);
+			    pes.atm.store(ProcedureExecutionStateEnum::Paused, Ordering::Relaxed);
+			    set_status_lights(pi, Some(pes), true, false);
+			}
+		    }
+		    if state == ProcedureExecutionStateEnum::Stopped {
+			break; // end the procedure if the user stopped it
+		    }
+		    // note: don't replace "pes.atm.load(Ordering::Relaxed)" with "state" in the below line.
+		    // This uses updates to the state in move_to_jar to perform logic."
+		    if pes.atm.load(Ordering::Relaxed) == ProcedureExecutionStateEnum::Paused {
+			set_status_lights(pi, Some(pes), true, false);
+			if bool::from(pi.green_button.read_value().unwrap()) {
+			    pes.atm.store(ProcedureExecutionStateEnum::Running, Ordering::Relaxed);
+			    set_status_lights(pi, Some(pes), false, true);
+			}
+		    }
+		    thread::sleep(time::Duration::from_millis(10));
+		}
+	    }
+	    println!("Exited loop B");
+	    rack = rack_after_step(step);
+
+	    if step.step_type == StepType::OperatorAction {
+		if pes.atm.load(Ordering::Relaxed) != ProcedureExecutionStateEnum::Stopped {
+		    let message = step.operator_message.clone().unwrap_or_default();
+		    operator_actions.push(wait_for_operator(pi_mutex, pes, (index + 1).try_into().unwrap(), message));
+		}
+		continue;
+	    }
+
+	    update_active_run(pes, |run| {
+		run.current_procedure_step_start_time = Some(Utc::now());
+		run.activity = RunActivity::Immersing;
+	    });
+	    let mut start_instant = Instant::now();
+	    let mut us_remaining : u128 = step.time_in_seconds.max(0) as u128 * 1_000_000;
+
+	    // sleep until it's time to move again
+	    println!("Entering loop C");
+	    while us_remaining > 0 {
+		if pes.atm.load(Ordering::Relaxed) == ProcedureExecutionStateEnum::Running {
+		    // update the timer controls
+		    let elapsed_us = start_instant.elapsed().as_micros();
+		    start_instant = Instant::now();
+		    if elapsed_us > us_remaining { // avoid attempts to subtract with overflow
+			us_remaining = 0;
+		    }
+		    else {
+			us_remaining -= elapsed_us;
+		    }
+
+		    // update the PES to inform the client how many seconds are remaining
+		    pes.seconds_remaining.store((us_remaining / (1000 * 1000)).try_into().unwrap(), Ordering::Relaxed);
+		}
+		// grab the lock
+		{
+		    let pi = &mut *pi_mutex.lock().unwrap();
+		    // check stop button
+		    if bool::from(pi.estop.read_value().unwrap()) {
+			pes.atm.store(ProcedureExecutionStateEnum::Paused, Ordering::Relaxed);
+		    }
+		    let state = pes.atm.load(Ordering::Relaxed);
+		    if state == ProcedureExecutionStateEnum::Stopped {
+			break;
+		    }
+		    if state == ProcedureExecutionStateEnum::Paused {
+			// handle run/pause buttons
+			set_status_lights(pi, Some(pes), true, false);
+			if bool::from(pi.green_button.read_value().unwrap()) {
+			    pes.atm.store(ProcedureExecutionStateEnum::Running, Ordering::Relaxed);
+			    set_status_lights(pi, Some(pes), false, true);
+			    start_instant = Instant::now();
+			}
+		    }
+		}
+		thread::sleep(time::Duration::from_millis(20));
+	    }
+	    println!("Exited loop C");
+
+	    // record how long the slides actually spent in the jar (the step may have been cut short by a stop)
+	    let immersed_seconds : i32 = step.time_in_seconds.max(0) - (us_remaining / (1000 * 1000)) as i32;
+	    if immersed_seconds > 0 {
+		match jar_usage.iter_mut().find(|usage| usage.jar_number == step.jar_number) {
+		    Some(usage) => usage.immersion_seconds += immersed_seconds,
+		    None => jar_usage.push(JarUsage {
+			jar_number: step.jar_number,
+			substance: step.substance.clone(),
+			immersion_seconds: immersed_seconds,
+		    }),
+		}
+	    }
+	}
+    }
+
+    // End of procedure, so move to the up position and let the slides drain
+    update_active_run(pes, |run| {
+	run.current_procedure_step_start_time = None;
+	run.activity = RunActivity::Draining;
+    });
+    raise_rack(pi_mutex, pes);
+    hold_to_drain(pi_mutex, pes, motion_settings.drain_seconds);
+    set_status_lights(&mut *pi_mutex.lock().unwrap(), Some(pes), false, false);
+
+    let final_state = if pes.atm.load(Ordering::Relaxed) == ProcedureExecutionStateEnum::Stopped {
+	ProcedureExecutionStateEnum::Stopped
+    } else {
+	ProcedureExecutionStateEnum::Completed
+    };
+    let finished_run;
+    {
+	let mut active_run = pes.active_run.lock().unwrap();
+	finished_run = active_run.take();
+	pes.atm.store(ProcedureExecutionStateEnum::Completed, Ordering::Relaxed);
+    }
+
+    if let Some(finished_run) = finished_run {
+	let run_record = RunRecord {
+	    id: None,
+	    rev: None,
+	    type_: "run".to_string(),
+	    procedure_id: proc.id.clone(),
+	    procedure_name: proc.name.clone(),
+	    procedure_version: Some(current_version(&proc)),
+	    start_time: finished_run.run_start_time,
+	    end_time: Utc::now(),
+	    final_state,
+	    slide_count: finished_run.slide_count,
+	    jar_usage,
+	    operator_actions,
+	};
+	if let Err(e) = record_run(store, racks, &run_record) {
+	    println!("Couldn't record the run: {:?}", e);
+	}
+	// reload so the procedure's run count includes this run
+	if let Ok(updated) = store.procedure_by_id(proc.id) {
+	    *pes.current_procedure.lock().unwrap() = Some(updated);
+	}
+    }
+    println!("execute_procedure completed");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, AtomicU64};
+    use std::sync::{Arc, RwLock};
+
+    fn idle_instrument() -> ProcedureExecutionState {
+	ProcedureExecutionState {
+	    atm: AtomicProcedureExecutionStateEnum::new(ProcedureExecutionStateEnum::NotStarted),
+	    seconds_remaining: AtomicU64::new(0),
+	    active_run: Mutex::new(None),
+	    limit_switch_hit_unexpectedly: AtomicBool::new(false),
+	    operator_action_confirmed: AtomicBool::new(false),
+	    motion_settings: Arc::new(RwLock::new(MotionSettings::default())),
+	    current_procedure: Mutex::new(None),
+	    restoring: AtomicBool::new(false),
+	}
+    }
+
+    fn run_of_papanicolaou() -> ActiveRun {
+	let proc : Procedure = serde_json::from_value(serde_json::json!({
+	    "_id": "papanicolaou",
+	    "_rev": "2-b",
+	    "type": "procedure",
+	    "name": "Papanicolaou",
+	    "jar_contents": ["Hematoxylin", "Orange G", "EA-50"],
+	    "procedure_steps": [
+		{"time_in_seconds": 180, "jar_number": 1},
+		{"time_in_seconds": 90, "jar_number": 2},
+		{"time_in_seconds": 150, "jar_number": 3},
+	    ],
+	})).unwrap();
+	ActiveRun {
+	    step_sequence: step_sequence(&proc),
+	    procedure: proc,
+	    run_start_time: Utc::now(),
+	    current_procedure_step_number: 0,
+	    current_cycle_number: 0,
+	    current_procedure_step_start_time: None,
+	    current_sequence_index: 0,
+	    current_group_path: Vec::new(),
+	    activity: RunActivity::Moving,
+	    operator_message: None,
+	    slide_count: None,
+	    motion_settings: MotionSettings::default(),
+	}
+    }
+
+    #[test]
+    fn runs_and_restores_keep_each_other_out() {
+	let pes = idle_instrument();
+	let refused = while_restoring(&pes, || {
+	    let start = claim_run(&pes, run_of_papanicolaou()).err().unwrap();
+	    assert!(start.message().contains("being restored"), "{}", start.message());
+	    while_restoring(&pes, || ()).err().unwrap().message().to_string()
+	}).unwrap();
+	assert!(refused.contains("already being restored"), "{}", refused);
+
+	claim_run(&pes, run_of_papanicolaou()).unwrap();
+	assert_eq!(pes.atm.load(Ordering::Relaxed), ProcedureExecutionStateEnum::Running);
+	assert!(while_restoring(&pes, || ()).is_err());
+	assert!(claim_run(&pes, run_of_papanicolaou()).is_err());
+    }
+
+    #[test]
+    fn a_restore_that_panics_lets_runs_start_again() {
+	let pes = idle_instrument();
+	let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+	    let _ = while_restoring(&pes, || panic!("the USB stick was pulled out"));
+	}));
+	assert!(panicked.is_err());
+	assert!(!pes.restoring.load(Ordering::SeqCst));
+	claim_run(&pes, run_of_papanicolaou()).unwrap();
+    }
+
+    #[test]
+    fn the_green_button_must_be_released_before_a_press_counts() {
+	let mut green_button = ButtonPresses::new();
+	// still held from resuming the run
+	assert!(!green_button.pressed(true));
+	assert!(!green_button.pressed(true));
+	assert!(!green_button.pressed(false));
+	assert!(green_button.pressed(true));
+	assert!(!green_button.pressed(true));
+
+	let mut green_button = ButtonPresses::new();
+	assert!(!green_button.pressed(false));
+	assert!(green_button.pressed(true));
+    }
+
+    #[test]
+    fn operator_actions_cant_be_confirmed_while_paused() {
+	let pes = idle_instrument();
+	claim_run(&pes, run_of_papanicolaou()).unwrap();
+	assert!(confirm_operator_action(&pes).is_err());
+
+	update_active_run(&pes, |run| {
+	    run.current_procedure_step_number = 2;
+	    run.activity = RunActivity::WaitingForOperator;
+	});
+	pause_run(&pes).unwrap();
+	assert!(confirm_operator_action(&pes).err().unwrap().message().contains("Resume"));
+	assert!(!pes.operator_action_confirmed.load(Ordering::Relaxed));
+
+	resume_run(&pes).unwrap();
+	confirm_operator_action(&pes).unwrap();
+	assert!(pes.operator_action_confirmed.load(Ordering::Relaxed));
+    }
+}
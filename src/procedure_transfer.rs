@@ -0,0 +1,436 @@
+pub use crate::structs_and_consts::*;
+pub use crate::couchdb::*;
+pub use crate::procedure_versions::*;
+pub use crate::validation::*;
+
+use chrono::{DateTime, Utc};
+use juniper::FieldResult;
+use serde::*;
+
+// Procedures are shared between instruments as JSON files. The file says what it is (format and format_version)
+// and leaves out everything that only makes sense in one instrument's database, such as _rev and run counts.
+// Imported procedures start again at version 1 on the new instrument.
+
+pub const PROCEDURE_EXPORT_FORMAT: &str = "openstainer-procedures";
+pub const PROCEDURE_EXPORT_FORMAT_VERSION: i32 = 1; // bump when the file layout changes, and keep reading the older layouts
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ProcedureExport {
+    pub format: String,
+    pub format_version: i32,
+    pub exported_at: DateTime<Utc>,
+    pub procedures: Vec<ExportedProcedure>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ExportedProcedure {
+    pub id: String, // the _id on the exporting instrument, used to recognize a procedure being imported back
+    pub version: i32,
+    pub name: String,
+    pub jar_contents: Vec<String>,
+    pub procedure_steps: Vec<ExportedStep>,
+    #[serde(default)]
+    pub step_groups: Vec<StepGroup>,
+    #[serde(default)]
+    pub repeat: Option<i32>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ExportedStep {
+    pub time_in_seconds: i32,
+    pub jar_number: i32,
+    #[serde(default)]
+    pub step_type: StepType,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub operator_message: Option<String>,
+}
+
+#[derive(juniper::GraphQLEnum, Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[graphql(description="What to do when an imported procedure has the same _id or name as an existing one.")]
+pub enum ImportConflictPolicy {
+    #[graphql(description="Import it under a new name, e.g. \"H&E (2)\".")]
+    Rename,
+    #[graphql(description="Replace the existing procedure. Its old version is kept in its version history.")]
+    Overwrite,
+    #[graphql(description="Leave the existing procedure alone and don't import this one.")]
+    Skip,
+}
+
+impl ImportConflictPolicy {
+    pub fn from_name(name: &str) -> Option<ImportConflictPolicy> {
+	match name.to_lowercase().as_str() {
+	    "rename" => Some(ImportConflictPolicy::Rename),
+	    "overwrite" => Some(ImportConflictPolicy::Overwrite),
+	    "skip" => Some(ImportConflictPolicy::Skip),
+	    _ => None,
+	}
+    }
+}
+
+#[derive(juniper::GraphQLEnum, Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ImportAction {
+    Created,
+    Renamed,
+    Overwritten,
+    Skipped,
+    Invalid,
+    Failed, // it couldn't be saved; the messages say why
+}
+
+#[derive(juniper::GraphQLObject, Debug, Clone, Serialize, Deserialize)]
+#[graphql(description="What happened to one procedure in an import.")]
+pub struct ImportedProcedure {
+    #[graphql(description="The name of the procedure in the file.")]
+    pub original_name: String,
+
+    #[graphql(description="The name it was saved under. Differs from originalName if it was renamed.")]
+    pub name: String,
+
+    #[graphql(description="The _id it was saved under. Nil if it was skipped or invalid.")]
+    pub procedure_id: Option<String>,
+
+    pub action: ImportAction,
+
+    #[graphql(description="Why it was skipped or invalid, and any validation warnings.")]
+    pub messages: Vec<String>,
+}
+
+fn exported_procedure(proc: &Procedure) -> ExportedProcedure {
+    ExportedProcedure {
+	id: proc.id.clone(),
+	version: current_version(proc),
+	name: proc.name.clone(),
+	jar_contents: proc.jar_contents.clone(),
+	procedure_steps: proc.procedure_steps.iter().map(|step| ExportedStep {
+	    time_in_seconds: step.time_in_seconds,
+	    jar_number: step.jar_number,
+	    step_type: step.step_type,
+	    operator_message: step.operator_message.clone(),
+	}).collect(),
+	step_groups: proc.step_groups.clone(),
+	repeat: proc.repeat,
+    }
+}
+
+// Exports the procedures with the given _ids, or all procedures if no ids are given.
+pub fn export_procedures(store: &dyn ProcedureStore, ids: Option<Vec<String>>) -> FieldResult<ProcedureExport> {
+    let procs = match ids {
+	Some(ids) => {
+	    let mut procs = Vec::new();
+	    for id in ids {
+		procs.push(store.procedure_by_id(id)?);
+	    }
+	    procs
+	}
+	None => store.procedures()?,
+    };
+    Ok(ProcedureExport {
+	format: PROCEDURE_EXPORT_FORMAT.to_string(),
+	format_version: PROCEDURE_EXPORT_FORMAT_VERSION,
+	exported_at: Utc::now(),
+	procedures: procs.iter().map(exported_procedure).collect(),
+    })
+}
+
+pub fn export_procedures_json(store: &dyn ProcedureStore, ids: Option<Vec<String>>) -> FieldResult<String> {
+    let export = export_procedures(store, ids)?;
+    match serde_json::to_string_pretty(&export) {
+	Ok(json) => Ok(json),
+	Err(e) => juniper_err(format!("Couldn't write the export: {:?}", e)),
+    }
+}
+
+// Checks that the JSON is a procedure export this version can read, then parses it.
+pub fn parse_procedure_export(json: &str) -> FieldResult<ProcedureExport> {
+    let value : serde_json::Value = match serde_json::from_str(json) {
+	Ok(value) => value,
+	Err(e) => return juniper_err(format!("The file isn't valid JSON: {}", e)),
+    };
+    if value["format"] != PROCEDURE_EXPORT_FORMAT {
+	return juniper_err(format!("The file isn't a procedure export; its format should be \"{}\".", PROCEDURE_EXPORT_FORMAT));
+    }
+    match value["format_version"].as_i64() {
+	Some(v) if v >= 1 && v <= PROCEDURE_EXPORT_FORMAT_VERSION as i64 => {}
+	Some(v) => return juniper_err(format!("The file is format version {}, but only versions up to {} can be imported. Update this instrument's software to import it.",
+					      v, PROCEDURE_EXPORT_FORMAT_VERSION)),
+	None => return juniper_err("The file doesn't say which format version it is.".to_string()),
+    }
+    // parsed from the string rather than the Value so errors include the line and column
+    match serde_json::from_str::<ProcedureExport>(json) {
+	Ok(export) => Ok(export),
+	Err(e) => juniper_err(format!("The file doesn't match the procedure export format: {}", e)),
+    }
+}
+
+fn input_object(exported: &ExportedProcedure) -> ProcedureInputObject {
+    ProcedureInputObject {
+	id: None,
+	rev: None,
+	type_: "procedure".to_string(),
+	name: exported.name.clone(),
+	jar_contents: exported.jar_contents.clone(),
+	procedure_steps: exported.procedure_steps.iter().map(|step| ProcedureStepInputObject {
+	    substance: None,
+	    time_in_seconds: step.time_in_seconds,
+	    jar_number: step.jar_number,
+	    step_type: Some(step.step_type),
+	    operator_message: step.operator_message.clone(),
+	}).collect(),
+	step_groups: Some(exported.step_groups.iter().cloned().map(StepGroupInputObject::from).collect()),
+	repeat: exported.repeat,
+	runs: None,
+	version: None,
+    }
+}
+
+// Picks "<name> (2)", "<name> (3)", ... so that the name isn't taken.
+fn unused_name(name: &str, taken_names: &[String]) -> String {
+    let mut n = 2;
+    loop {
+	let candidate = format!("{} ({})", name, n);
+	if !taken_names.contains(&candidate) {
+	    return candidate;
+	}
+	n += 1;
+    }
+}
+
+// Imports every procedure in an export. A procedure conflicts with an existing one if it has the same _id
+// (it's being imported back into the instrument it came from) or the same name.
+pub fn import_procedures(store: &dyn ProcedureStore, json: &str, on_conflict: ImportConflictPolicy) -> FieldResult<Vec<ImportedProcedure>> {
+    let export = parse_procedure_export(json)?;
+    let mut existing = store.procedures()?; // kept up to date as procedures are overwritten, so a later one gets the new _rev
+    let mut taken_names : Vec<String> = existing.iter().map(|proc| proc.name.clone()).collect();
+    let mut results : Vec<ImportedProcedure> = Vec::new();
+
+    for exported in export.procedures.iter() {
+	let mut procedure = input_object(exported);
+	let validation = validate_procedure(&procedure);
+	let mut messages : Vec<String> = validation.issues.iter().map(|issue| issue.message.clone()).collect();
+	if !validation.valid {
+	    results.push(ImportedProcedure {
+		original_name: exported.name.clone(),
+		name: exported.name.clone(),
+		procedure_id: None,
+		action: ImportAction::Invalid,
+		messages,
+	    });
+	    continue;
+	}
+
+	let conflict = existing.iter().find(|proc| proc.id == exported.id)
+	    .or_else(|| existing.iter().find(|proc| proc.name == exported.name));
+	let action = match (conflict, on_conflict) {
+	    (None, _) => {
+		if taken_names.contains(&procedure.name) {
+		    // an earlier procedure in the same file has this name
+		    procedure.name = unused_name(&procedure.name, &taken_names);
+		    ImportAction::Renamed
+		} else {
+		    ImportAction::Created
+		}
+	    }
+	    (Some(conflict), ImportConflictPolicy::Skip) => {
+		messages.push(format!("A procedure named {} already exists.", conflict.name));
+		ImportAction::Skipped
+	    }
+	    (Some(conflict), ImportConflictPolicy::Overwrite) => {
+		procedure.id = Some(conflict.id.clone());
+		procedure.rev = Some(conflict.rev.clone());
+		ImportAction::Overwritten
+	    }
+	    (Some(_), ImportConflictPolicy::Rename) => {
+		procedure.name = unused_name(&procedure.name, &taken_names);
+		ImportAction::Renamed
+	    }
+	};
+
+	let (procedure_id, name, action) = if action == ImportAction::Skipped {
+	    (None, exported.name.clone(), action)
+	} else {
+	    // a failed save is reported with the rest rather than ending the import, since earlier ones are already saved
+	    match save_procedure_version(store, procedure.clone()) {
+		Ok(saved) => {
+		    taken_names.push(saved.name.clone());
+		    if let Some(proc) = existing.iter_mut().find(|proc| proc.id == saved.id) {
+			*proc = saved.clone();
+		    }
+		    (Some(saved.id), saved.name, action)
+		}
+		Err(e) => {
+		    messages.push(e.message().to_string());
+		    (None, procedure.name, ImportAction::Failed)
+		}
+	    }
+	};
+	results.push(ImportedProcedure {
+	    original_name: exported.name.clone(),
+	    name,
+	    procedure_id,
+	    action,
+	    messages,
+	});
+    }
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_store::MemoryStore;
+    use serde_json::json;
+
+    fn export_file(format_version: i32, procedures: serde_json::Value) -> String {
+	json!({
+	    "format": PROCEDURE_EXPORT_FORMAT,
+	    "format_version": format_version,
+	    "exported_at": "2020-03-02T09:00:00Z",
+	    "procedures": procedures,
+	}).to_string()
+    }
+
+    fn error_message<T: std::fmt::Debug>(result: FieldResult<T>) -> String {
+	result.expect_err("expected an error").message().to_string()
+    }
+
+    #[test]
+    fn exports_parse_back_into_procedures() {
+	let json = export_file(1, json!([{
+	    "id": "h-and-e",
+	    "version": 3,
+	    "name": "H&E",
+	    "jar_contents": ["Hematoxylin", "Water"],
+	    "procedure_steps": [
+		{"time_in_seconds": 150, "jar_number": 1},
+		{"time_in_seconds": 0, "jar_number": 2, "step_type": "OperatorAction", "operator_message": "Check the slides"},
+	    ],
+	    "repeat": 2,
+	}]));
+	let export = parse_procedure_export(&json).unwrap();
+	let procedure = input_object(&export.procedures[0]);
+	assert_eq!((procedure.id.clone(), procedure.version, procedure.repeat), (None, None, Some(2)));
+	assert_eq!(procedure.procedure_steps[1].step_type, Some(StepType::OperatorAction));
+	assert_eq!(procedure.procedure_steps[1].operator_message.as_deref(), Some("Check the slides"));
+	assert!(validate_procedure(&procedure).valid);
+    }
+
+    #[test]
+    fn other_files_are_refused() {
+	assert!(error_message(parse_procedure_export("{\"format\":")).starts_with("The file isn't valid JSON"));
+	assert!(error_message(parse_procedure_export("{\"procedures\": []}")).contains("isn't a procedure export"));
+	assert!(error_message(parse_procedure_export(&export_file(PROCEDURE_EXPORT_FORMAT_VERSION + 1, json!([])))).contains("Update this instrument's software"));
+	// _rev and run counts are instrument specific, so they're not allowed in the file
+	let with_rev = export_file(1, json!([{"id": "a", "_rev": "1-a", "version": 1, "name": "A", "jar_contents": [], "procedure_steps": []}]));
+	assert!(error_message(parse_procedure_export(&with_rev)).contains("doesn't match the procedure export format"));
+    }
+
+    #[test]
+    fn renamed_procedures_get_the_next_free_number() {
+	let taken = vec!["H&E".to_string(), "H&E (2)".to_string(), "H&E (4)".to_string()];
+	assert_eq!(unused_name("H&E", &taken), "H&E (3)");
+	assert_eq!(unused_name("Pap", &taken), "Pap (2)");
+    }
+
+    fn store_with(names: &[&str]) -> MemoryStore {
+	let store = MemoryStore::new();
+	for name in names {
+	    let procedure : ProcedureInputObject = serde_json::from_value(json!({
+		"type": "procedure",
+		"name": name,
+		"jar_contents": ["Hematoxylin"],
+		"procedure_steps": [{"time_in_seconds": 60, "jar_number": 1}],
+	    })).unwrap();
+	    save_procedure_version(&store, procedure).unwrap();
+	}
+	store
+    }
+
+    // A file holding a procedure with the given name for each step time given, all from another instrument.
+    fn file_from_elsewhere(name: &str, step_times: &[i32]) -> String {
+	let procedures : Vec<serde_json::Value> = step_times.iter().enumerate().map(|(i, seconds)| json!({
+	    "id": format!("elsewhere-{}", i + 1),
+	    "version": 1,
+	    "name": name,
+	    "jar_contents": ["Hematoxylin"],
+	    "procedure_steps": [{"time_in_seconds": seconds, "jar_number": 1}],
+	})).collect();
+	export_file(PROCEDURE_EXPORT_FORMAT_VERSION, json!(procedures))
+    }
+
+    fn actions(results: &[ImportedProcedure]) -> Vec<ImportAction> {
+	results.iter().map(|result| result.action).collect()
+    }
+
+    #[test]
+    fn exported_procedures_import_into_another_instrument() {
+	let here = store_with(&["H&E", "Pap"]);
+	let json = export_procedures_json(&here, None).unwrap();
+	let there = MemoryStore::new();
+	let results = import_procedures(&there, &json, ImportConflictPolicy::Skip).unwrap();
+	assert_eq!(actions(&results), vec![ImportAction::Created, ImportAction::Created]);
+	let imported = there.procedures().unwrap();
+	assert_eq!(imported.iter().map(|proc| proc.version).collect::<Vec<Option<i32>>>(), vec![Some(1), Some(1)]);
+	assert_eq!(imported[0].procedure_steps[0].substance, "Hematoxylin");
+    }
+
+    #[test]
+    fn rename_imports_under_an_unused_name() {
+	let store = store_with(&["H&E", "H&E (2)"]);
+	let results = import_procedures(&store, &file_from_elsewhere("H&E", &[90]), ImportConflictPolicy::Rename).unwrap();
+	assert_eq!(actions(&results), vec![ImportAction::Renamed]);
+	assert_eq!((results[0].original_name.as_str(), results[0].name.as_str()), ("H&E", "H&E (3)"));
+	assert_eq!(store.procedures().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn skip_leaves_the_existing_procedure_alone() {
+	let store = store_with(&["H&E"]);
+	let results = import_procedures(&store, &file_from_elsewhere("H&E", &[90]), ImportConflictPolicy::Skip).unwrap();
+	assert_eq!(actions(&results), vec![ImportAction::Skipped]);
+	assert_eq!(results[0].procedure_id, None);
+	let procedures = store.procedures().unwrap();
+	assert_eq!(procedures.len(), 1);
+	assert_eq!(procedures[0].procedure_steps[0].time_in_seconds, 60);
+    }
+
+    #[test]
+    fn overwrite_keeps_the_old_procedure_as_a_version() {
+	let store = store_with(&["H&E"]);
+	let results = import_procedures(&store, &file_from_elsewhere("H&E", &[90]), ImportConflictPolicy::Overwrite).unwrap();
+	assert_eq!(actions(&results), vec![ImportAction::Overwritten]);
+	let id = results[0].procedure_id.clone().unwrap();
+	assert_eq!(store.procedure_by_id(id.clone()).unwrap().version, Some(2));
+	assert_eq!(store.procedure_version(id, 1).unwrap().procedure_steps[0].time_in_seconds, 60);
+    }
+
+    #[test]
+    fn a_name_overwritten_twice_in_one_import_keeps_both_old_versions() {
+	let store = store_with(&["H&E"]);
+	let results = import_procedures(&store, &file_from_elsewhere("H&E", &[90, 120]), ImportConflictPolicy::Overwrite).unwrap();
+	assert_eq!(actions(&results), vec![ImportAction::Overwritten, ImportAction::Overwritten]);
+	let id = results[1].procedure_id.clone().unwrap();
+	assert_eq!(store.procedure_by_id(id.clone()).unwrap().version, Some(3));
+	let times : Vec<i32> = store.procedure_versions(id).unwrap().iter().map(|v| v.procedure_steps[0].time_in_seconds).collect();
+	assert_eq!(times, vec![60, 90, 120]);
+    }
+
+    #[test]
+    fn names_repeated_in_the_file_are_renamed() {
+	let store = MemoryStore::new();
+	let results = import_procedures(&store, &file_from_elsewhere("Pap", &[90, 120]), ImportConflictPolicy::Skip).unwrap();
+	assert_eq!(actions(&results), vec![ImportAction::Created, ImportAction::Renamed]);
+	assert_eq!(results[1].name, "Pap (2)");
+    }
+
+    #[test]
+    fn invalid_procedures_are_reported_and_not_saved() {
+	let store = MemoryStore::new();
+	let results = import_procedures(&store, &file_from_elsewhere("Pap", &[0]), ImportConflictPolicy::Rename).unwrap();
+	assert_eq!(actions(&results), vec![ImportAction::Invalid]);
+	assert!(!results[0].messages.is_empty());
+	assert!(store.procedures().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,381 @@
+pub use crate::structs_and_consts::*;
+pub use crate::couchdb::*;
+pub use crate::storage::*;
+
+use chrono::Utc;
+
+// CouchDB's _rev changes on every write and old revisions are lost on compaction, so procedures carry their own
+// version number. Saving a change to a procedure bumps the version and stores a snapshot of it as a separate
+// procedure_version doc, which runs refer to.
+
+pub fn current_version(proc: &Procedure) -> i32 {
+    proc.version.unwrap_or(1)
+}
+
+pub fn version_snapshot(proc: &Procedure) -> ProcedureVersion {
+    let version = current_version(proc);
+    ProcedureVersion {
+	id: procedure_version_id(&proc.id, version),
+	rev: None,
+	type_: "procedure_version".to_string(),
+	procedure_id: proc.id.clone(),
+	version,
+	saved_at: Utc::now(),
+	name: proc.name.clone(),
+	jar_contents: proc.jar_contents.clone(),
+	procedure_steps: proc.procedure_steps.clone(),
+	step_groups: proc.step_groups.clone(),
+	repeat: proc.repeat,
+    }
+}
+
+// Makes sure the procedure's current version has a snapshot. Procedures saved before versioning existed don't have one
+// until they're next saved or run.
+pub fn ensure_version_snapshot(store: &dyn ProcedureStore, proc: &Procedure) -> StorageResult<()> {
+    store.create_procedure_version(&version_snapshot(proc))
+}
+
+fn describe_step(step: &ProcedureStep) -> String {
+    match step.step_type {
+	StepType::Immerse => format!("{} seconds in jar {}", step.time_in_seconds, step.jar_number),
+	StepType::OperatorAction => format!("operator action over jar {}: {}", step.jar_number, step.operator_message.clone().unwrap_or_default()),
+    }
+}
+
+fn describe_group(group: &StepGroup) -> String {
+    format!("steps {} to {} repeated {} times", group.first_step_number, group.last_step_number, group.repeat)
+}
+
+fn change(field: &str, step_number: Option<i32>, jar_number: Option<i32>, old_value: Option<String>, new_value: Option<String>) -> ProcedureChange {
+    ProcedureChange {
+	field: field.to_string(),
+	step_number,
+	jar_number,
+	old_value,
+	new_value,
+    }
+}
+
+// Lists the differences between two versions. Steps are compared by position, so inserting a step shows up as
+// changes to every step after it plus an added step at the end. A changed group shows up as one removed and one added.
+pub fn diff_procedure_versions(old: &ProcedureVersion, new: &ProcedureVersion) -> Vec<ProcedureChange> {
+    let mut changes : Vec<ProcedureChange> = Vec::new();
+
+    if old.name != new.name {
+	changes.push(change("name", None, None, Some(old.name.clone()), Some(new.name.clone())));
+    }
+    if old.repeat.unwrap_or(1) != new.repeat.unwrap_or(1) {
+	changes.push(change("repeat", None, None,
+			    Some(old.repeat.unwrap_or(1).to_string()), Some(new.repeat.unwrap_or(1).to_string())));
+    }
+
+    for index in 0..old.jar_contents.len().max(new.jar_contents.len()) {
+	let old_contents = old.jar_contents.get(index).filter(|contents| !contents.trim().is_empty());
+	let new_contents = new.jar_contents.get(index).filter(|contents| !contents.trim().is_empty());
+	if old_contents != new_contents {
+	    changes.push(change("jar_contents", None, Some(index as i32 + 1), old_contents.cloned(), new_contents.cloned()));
+	}
+    }
+
+    for index in 0..old.procedure_steps.len().max(new.procedure_steps.len()) {
+	let step_number = Some(index as i32 + 1);
+	match (old.procedure_steps.get(index), new.procedure_steps.get(index)) {
+	    (Some(old_step), Some(new_step)) => {
+		if old_step.jar_number != new_step.jar_number {
+		    changes.push(change("jar_number", step_number, None,
+					Some(old_step.jar_number.to_string()), Some(new_step.jar_number.to_string())));
+		}
+		if old_step.time_in_seconds != new_step.time_in_seconds {
+		    changes.push(change("time_in_seconds", step_number, None,
+					Some(old_step.time_in_seconds.to_string()), Some(new_step.time_in_seconds.to_string())));
+		}
+		if old_step.step_type != new_step.step_type {
+		    changes.push(change("step_type", step_number, None,
+					Some(format!("{:?}", old_step.step_type)), Some(format!("{:?}", new_step.step_type))));
+		}
+		if old_step.operator_message != new_step.operator_message {
+		    changes.push(change("operator_message", step_number, None,
+					old_step.operator_message.clone(), new_step.operator_message.clone()));
+		}
+	    }
+	    (Some(old_step), None) => changes.push(change("procedure_steps", step_number, None, Some(describe_step(old_step)), None)),
+	    (None, Some(new_step)) => changes.push(change("procedure_steps", step_number, None, None, Some(describe_step(new_step)))),
+	    (None, None) => {}
+	}
+    }
+
+    // groups have no identity of their own, so they're compared as a set
+    for group in old.step_groups.iter().filter(|group| !new.step_groups.contains(group)) {
+	changes.push(change("step_groups", Some(group.first_step_number), None, Some(describe_group(group)), None));
+    }
+    for group in new.step_groups.iter().filter(|group| !old.step_groups.contains(group)) {
+	changes.push(change("step_groups", Some(group.first_step_number), None, None, Some(describe_group(group))));
+    }
+
+    changes
+}
+
+// What the procedure would look like as a version if the edit were saved over the saved procedure.
+fn edit_snapshot(saved: &Procedure, procedure: &ProcedureInputObject) -> ProcedureVersion {
+    let mut edit = procedure.clone();
+    reconcile_procedure_input_object(&mut edit);
+    let mut snapshot = version_snapshot(saved);
+    snapshot.name = edit.name;
+    snapshot.jar_contents = edit.jar_contents;
+    snapshot.procedure_steps = edit.procedure_steps.into_iter().map(ProcedureStep::from).collect();
+    snapshot.step_groups = edit.step_groups.unwrap_or_default().into_iter().map(StepGroup::from).collect();
+    snapshot.repeat = edit.repeat;
+    snapshot
+}
+
+// The conflict error for an edit of an out-of-date copy of the procedure, saying what's saved now.
+fn edit_conflict(current: &Procedure) -> StorageError {
+    StorageError::Conflict {
+	message: format!("Document update conflict: {} has been changed since it was loaded and is now at version {}. Use procedureThreeWayDiff to compare.",
+			 current.name, current_version(current)),
+	current: Some(CurrentRevision {
+	    id: current.id.clone(),
+	    rev: current.rev.clone(),
+	    version: Some(current_version(current)),
+	}),
+    }
+}
+
+// Saves a procedure, bumping its version if anything other than bookkeeping (runs, _rev) changed.
+// The previous version is snapshotted first in case it predates versioning, then the new version is snapshotted.
+// Fails with a conflict, before anything is written, if the edit was made to an out-of-date copy (its _rev is stale).
+pub fn save_procedure_version(store: &dyn ProcedureStore, mut procedure: ProcedureInputObject) -> StorageResult<Procedure> {
+    let previous = match &procedure.id {
+	Some(id) => store.find_procedure(id.clone())?,
+	None => None,
+    };
+    if let Some(previous) = &previous {
+	if procedure.rev.as_ref() != Some(&previous.rev) {
+	    return Err(edit_conflict(previous));
+	}
+    }
+
+    procedure.version = match &previous {
+	None => Some(1),
+	Some(previous) => {
+	    let previous_snapshot = version_snapshot(previous);
+	    if diff_procedure_versions(&previous_snapshot, &edit_snapshot(previous, &procedure)).is_empty() {
+		Some(current_version(previous))
+	    } else {
+		store.create_procedure_version(&previous_snapshot)?;
+		Some(current_version(previous) + 1)
+	    }
+	}
+    };
+
+    let saved = match store.save_procedure(procedure.clone()) {
+	// saved by someone else since it was checked above
+	Err(StorageError::Conflict { message, .. }) => return Err(match procedure.id {
+	    Some(id) => match store.find_procedure(id)? {
+		Some(current) => edit_conflict(&current),
+		None => StorageError::conflict(message),
+	    },
+	    None => StorageError::conflict(message),
+	}),
+	saved => saved?,
+    };
+    ensure_version_snapshot(store, &saved)?;
+    Ok(saved)
+}
+
+// Compares an edit that hit a conflict with what's saved now, relative to the version the edit started from
+// (the edit's version field). Changes both sides made to the same field in different ways are listed as conflicts.
+pub fn procedure_three_way_diff(store: &dyn ProcedureStore, procedure: &ProcedureInputObject) -> StorageResult<ProcedureThreeWayDiff> {
+    let id = match &procedure.id {
+	Some(id) => id.clone(),
+	None => return Err(StorageError::NotFound("A new procedure has no saved version to compare with.".to_string())),
+    };
+    let current = store.procedure_by_id(id.clone())?;
+    let base_version = procedure.version.unwrap_or(1);
+    let base = if base_version == current_version(&current) {
+	version_snapshot(&current)
+    } else {
+	store.procedure_version(id.clone(), base_version)?
+    };
+
+    let their_changes = diff_procedure_versions(&base, &version_snapshot(&current));
+    let my_changes = diff_procedure_versions(&base, &edit_snapshot(&current, procedure));
+    let conflicts : Vec<ProcedureMergeConflict> = my_changes.iter().filter_map(|mine| {
+	let theirs = their_changes.iter().find(|theirs| {
+	    theirs.field == mine.field && theirs.step_number == mine.step_number && theirs.jar_number == mine.jar_number
+	})?;
+	if theirs.new_value == mine.new_value {
+	    return None; // both made the same change
+	}
+	Some(ProcedureMergeConflict {
+	    field: mine.field.clone(),
+	    step_number: mine.step_number,
+	    jar_number: mine.jar_number,
+	    base_value: mine.old_value.clone(),
+	    their_value: theirs.new_value.clone(),
+	    my_value: mine.new_value.clone(),
+	})
+    }).collect();
+
+    Ok(ProcedureThreeWayDiff {
+	procedure_id: id,
+	base_version,
+	current_version: current_version(&current),
+	current_rev: current.rev.clone(),
+	their_changes,
+	my_changes,
+	conflicts,
+    })
+}
+
+pub fn diff_saved_procedure_versions(store: &dyn ProcedureStore, procedure_id: String, from_version: i32, to_version: i32) -> StorageResult<Vec<ProcedureChange>> {
+    let old = store.procedure_version(procedure_id.clone(), from_version)?;
+    let new = store.procedure_version(procedure_id, to_version)?;
+    Ok(diff_procedure_versions(&old, &new))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory_store::MemoryStore;
+
+    fn version(number: i32, jar_contents: &[&str], steps: &[(i32, i32)]) -> ProcedureVersion {
+	ProcedureVersion {
+	    id: procedure_version_id("h-and-e", number),
+	    rev: None,
+	    type_: "procedure_version".to_string(),
+	    procedure_id: "h-and-e".to_string(),
+	    version: number,
+	    saved_at: Utc::now(),
+	    name: "H&E".to_string(),
+	    jar_contents: jar_contents.iter().map(|contents| contents.to_string()).collect(),
+	    procedure_steps: steps.iter().map(|&(jar_number, time_in_seconds)| ProcedureStep {
+		substance: "".to_string(),
+		time_in_seconds,
+		jar_number,
+		step_type: StepType::Immerse,
+		operator_message: None,
+	    }).collect(),
+	    step_groups: Vec::new(),
+	    repeat: None,
+	}
+    }
+
+    fn fields(changes: &[ProcedureChange]) -> Vec<(&str, Option<i32>, Option<i32>)> {
+	changes.iter().map(|change| (change.field.as_str(), change.step_number, change.jar_number)).collect()
+    }
+
+    #[test]
+    fn identical_versions_have_no_changes() {
+	let old = version(1, &["Hematoxylin", "Water"], &[(1, 150), (2, 20)]);
+	let mut new = version(2, &["Hematoxylin", "Water", " "], &[(1, 150), (2, 20)]);
+	new.repeat = Some(1);
+	assert!(diff_procedure_versions(&old, &new).is_empty());
+    }
+
+    #[test]
+    fn steps_are_compared_by_position() {
+	let old = version(1, &["Hematoxylin", "Water"], &[(1, 150), (2, 20)]);
+	let new = version(2, &["Hematoxylin", "Tap water", "Eosin"], &[(1, 120), (3, 20), (2, 20)]);
+	let changes = diff_procedure_versions(&old, &new);
+	assert_eq!(fields(&changes), vec![
+	    ("jar_contents", None, Some(2)),
+	    ("jar_contents", None, Some(3)),
+	    ("time_in_seconds", Some(1), None),
+	    ("jar_number", Some(2), None),
+	    ("procedure_steps", Some(3), None),
+	]);
+	assert_eq!(changes[2].old_value.as_deref(), Some("150"));
+	assert_eq!(changes[4].new_value.as_deref(), Some("20 seconds in jar 2"));
+    }
+
+    #[test]
+    fn procedures_without_a_version_are_version_1() {
+	let mut proc : Procedure = serde_json::from_value(serde_json::json!({
+	    "_id": "h-and-e",
+	    "_rev": "3-c",
+	    "type": "procedure",
+	    "name": "H&E",
+	    "jar_contents": ["Hematoxylin"],
+	    "procedure_steps": [{"time_in_seconds": 150, "jar_number": 1}],
+	})).unwrap();
+	assert_eq!(current_version(&proc), 1);
+	assert_eq!(version_snapshot(&proc).id, procedure_version_id("h-and-e", 1));
+	proc.version = Some(4);
+	let snapshot = version_snapshot(&proc);
+	assert_eq!((snapshot.version, snapshot.procedure_steps.len()), (4, 1));
+    }
+
+    fn saved_pap(store: &MemoryStore) -> Procedure {
+	let pap : ProcedureInputObject = serde_json::from_value(serde_json::json!({
+	    "type": "procedure",
+	    "name": "Pap",
+	    "jar_contents": ["Hematoxylin", "Orange G"],
+	    "procedure_steps": [
+		{"time_in_seconds": 60, "jar_number": 1},
+		{"time_in_seconds": 90, "jar_number": 2},
+	    ],
+	})).unwrap();
+	save_procedure_version(store, pap).unwrap()
+    }
+
+    #[test]
+    fn new_procedures_start_at_version_1() {
+	let store = MemoryStore::new();
+	let saved = saved_pap(&store);
+	assert_eq!(saved.version, Some(1));
+	let versions = store.procedure_versions(saved.id).unwrap();
+	assert_eq!(versions.iter().map(|v| v.version).collect::<Vec<i32>>(), vec![1]);
+    }
+
+    #[test]
+    fn edits_bump_the_version_and_keep_the_old_one() {
+	let store = MemoryStore::new();
+	let v1 = saved_pap(&store);
+	let mut edit = ProcedureInputObject::from(v1.clone());
+	edit.procedure_steps[1].time_in_seconds = 120;
+	let v2 = save_procedure_version(&store, edit).unwrap();
+	assert_eq!(v2.version, Some(2));
+	assert_eq!(store.procedure_version(v1.id.clone(), 1).unwrap().procedure_steps[1].time_in_seconds, 90);
+	let changes = diff_saved_procedure_versions(&store, v1.id, 1, 2).unwrap();
+	assert_eq!(changes.len(), 1);
+	assert_eq!(changes[0].new_value.as_deref(), Some("120"));
+    }
+
+    #[test]
+    fn saving_without_changes_keeps_the_version() {
+	let store = MemoryStore::new();
+	let v1 = saved_pap(&store);
+	let resaved = save_procedure_version(&store, ProcedureInputObject::from(v1.clone())).unwrap();
+	assert_eq!(resaved.version, Some(1));
+	assert_ne!(resaved.rev, v1.rev);
+	assert_eq!(store.procedure_versions(v1.id).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn stale_edits_conflict_with_the_current_version() {
+	let store = MemoryStore::new();
+	let v1 = saved_pap(&store);
+	let mut theirs = ProcedureInputObject::from(v1.clone());
+	theirs.procedure_steps[0].time_in_seconds = 45;
+	let v2 = save_procedure_version(&store, theirs).unwrap();
+
+	let mut mine = ProcedureInputObject::from(v1.clone());
+	mine.procedure_steps[0].time_in_seconds = 75;
+	mine.procedure_steps[1].time_in_seconds = 100;
+	match save_procedure_version(&store, mine.clone()) {
+	    Err(StorageError::Conflict { current: Some(current), .. }) => {
+		assert_eq!((current.rev, current.version), (v2.rev, Some(2)));
+	    }
+	    other => panic!("expected a conflict, got {:?}", other),
+	}
+	assert_eq!(store.procedure_by_id(v1.id).unwrap().procedure_steps[0].time_in_seconds, 45);
+
+	let diff = procedure_three_way_diff(&store, &mine).unwrap();
+	assert_eq!((diff.base_version, diff.current_version), (1, 2));
+	assert_eq!((diff.their_changes.len(), diff.my_changes.len()), (1, 2));
+	assert_eq!(diff.conflicts.len(), 1);
+	assert_eq!((diff.conflicts[0].base_value.as_deref(), diff.conflicts[0].their_value.as_deref(), diff.conflicts[0].my_value.as_deref()),
+		   (Some("60"), Some("45"), Some("75")));
+    }
+}
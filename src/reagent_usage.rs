@@ -0,0 +1,179 @@
+pub use crate::structs_and_consts::*;
+pub use crate::couchdb::*;
+pub use crate::storage::*;
+
+use chrono::{DateTime, Utc};
+use juniper::FieldResult;
+
+// Reagents degrade with use, so each jar in the rack counts the runs, immersion time and slides since it was last filled.
+// These are compared against per-reagent thresholds to decide when a jar is due for replacement.
+
+// Lists the limits a jar has reached. Empty if the jar isn't due for replacement.
+fn replacement_reasons(jar: &RackJar, threshold: &ReagentThreshold, now: DateTime<Utc>) -> Vec<String> {
+    let mut reasons : Vec<String> = Vec::new();
+    if let Some(max_runs) = threshold.max_runs {
+	if jar.runs_since_filled >= max_runs {
+	    reasons.push(format!("{} of {} runs", jar.runs_since_filled, max_runs));
+	}
+    }
+    if let Some(max_minutes) = threshold.max_immersion_minutes {
+	let minutes = jar.immersion_seconds_since_filled / 60;
+	if minutes >= max_minutes {
+	    reasons.push(format!("{} of {} minutes of immersion", minutes, max_minutes));
+	}
+    }
+    if let Some(max_slides) = threshold.max_slides {
+	if jar.slides_since_filled >= max_slides {
+	    reasons.push(format!("{} of {} slides", jar.slides_since_filled, max_slides));
+	}
+    }
+    if let (Some(max_days), Some(filled_at)) = (threshold.max_days, jar.filled_at) {
+	let days = (now - filled_at).num_days();
+	if days >= max_days as i64 {
+	    reasons.push(format!("{} of {} days since filling", days, max_days));
+	}
+    }
+    reasons
+}
+
+pub fn reagent_alerts_for(rack: &Rack, thresholds: &ReagentThresholds, now: DateTime<Utc>) -> Vec<ReagentAlert> {
+    rack.jars.iter().filter_map(|jar| {
+	let threshold = thresholds.for_substance(&jar.substance)?;
+	let reasons = replacement_reasons(jar, threshold, now);
+	if reasons.is_empty() {
+	    return None;
+	}
+	Some(ReagentAlert {
+	    jar_number: jar.jar_number,
+	    substance: jar.substance.clone(),
+	    reasons,
+	})
+    }).collect()
+}
+
+// The jars in the rack that are due for replacement.
+pub fn reagent_alerts(racks: &dyn RackStore) -> FieldResult<Vec<ReagentAlert>> {
+    Ok(reagent_alerts_for(&racks.rack()?, &racks.reagent_thresholds()?, Utc::now()))
+}
+
+// Adds a finished run's usage to the jars it used.
+pub fn add_run_usage(rack: &mut Rack, run_record: &RunRecord) {
+    for usage in run_record.jar_usage.iter() {
+	if let Some(jar) = rack.jar_mut(usage.jar_number) {
+	    jar.runs_since_filled += 1;
+	    jar.immersion_seconds_since_filled += usage.immersion_seconds;
+	    jar.slides_since_filled += run_record.slide_count.unwrap_or(0);
+	}
+    }
+}
+
+// Saves the record of a run and adds its usage to the rack's counters.
+pub fn record_run(procedures: &dyn ProcedureStore, racks: &dyn RackStore, run_record: &RunRecord) -> FieldResult<RunRecord> {
+    let saved = procedures.save_run_record(run_record)?;
+    let mut rack = racks.rack()?;
+    add_run_usage(&mut rack, run_record);
+    racks.save_rack_doc(rack)?;
+    Ok(saved)
+}
+
+// Marks a jar as freshly filled, resetting its usage counters. Any of the optional details given replace the old ones.
+pub fn refill_jar(racks: &dyn RackStore, jar_number: i32, substance: Option<String>, lot_number: Option<String>, volume_ml: Option<f64>) -> FieldResult<Rack> {
+    let mut rack = racks.rack()?;
+    {
+	let jar = match rack.jar_mut(jar_number) {
+	    Some(jar) => jar,
+	    None => return juniper_err(format!("Jar {} isn't in the rack.", jar_number)),
+	};
+	jar.filled_at = Some(Utc::now());
+	jar.reset_usage();
+	if let Some(substance) = substance {
+	    jar.substance = substance;
+	}
+	if lot_number.is_some() {
+	    jar.lot_number = lot_number;
+	}
+	if volume_ml.is_some() {
+	    jar.volume_ml = volume_ml;
+	}
+    }
+    Ok(racks.save_rack_doc(rack)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{Duration, TimeZone};
+
+    fn thresholds() -> ReagentThresholds {
+	let mut thresholds = ReagentThresholds::empty();
+	thresholds.thresholds.push(ReagentThreshold {
+	    substance: "Hematoxylin".to_string(),
+	    max_runs: Some(2),
+	    max_immersion_minutes: Some(10),
+	    max_slides: None,
+	    max_days: Some(7),
+	});
+	thresholds
+    }
+
+    fn run_using(jars: &[(i32, i32)], slide_count: Option<i32>) -> RunRecord {
+	let start_time = Utc.ymd(2020, 3, 2).and_hms(9, 0, 0);
+	RunRecord {
+	    id: None,
+	    rev: None,
+	    type_: "run".to_string(),
+	    procedure_id: "h-and-e".to_string(),
+	    procedure_name: "H&E".to_string(),
+	    procedure_version: Some(1),
+	    start_time,
+	    end_time: start_time + Duration::minutes(20),
+	    final_state: ProcedureExecutionStateEnum::Completed,
+	    slide_count,
+	    jar_usage: jars.iter().map(|&(jar_number, immersion_seconds)| JarUsage {
+		jar_number,
+		substance: "".to_string(),
+		immersion_seconds,
+	    }).collect(),
+	    operator_actions: Vec::new(),
+	}
+    }
+
+    #[test]
+    fn runs_add_to_the_jars_they_used() {
+	let mut rack = Rack::empty();
+	add_run_usage(&mut rack, &run_using(&[(1, 150), (3, 90)], Some(4)));
+	add_run_usage(&mut rack, &run_using(&[(1, 150)], None));
+	let jar = rack.jar(1).unwrap();
+	assert_eq!((jar.runs_since_filled, jar.immersion_seconds_since_filled, jar.slides_since_filled), (2, 300, 4));
+	assert_eq!(rack.jar(2).unwrap().runs_since_filled, 0);
+	assert_eq!(rack.jar(3).unwrap().immersion_seconds_since_filled, 90);
+    }
+
+    #[test]
+    fn jars_are_due_once_a_limit_is_reached() {
+	let filled_at = Utc.ymd(2020, 3, 1).and_hms(8, 0, 0);
+	let mut rack = Rack::empty();
+	for jar in rack.jars.iter_mut() {
+	    jar.substance = "hematoxylin".to_string();
+	    jar.filled_at = Some(filled_at);
+	}
+	add_run_usage(&mut rack, &run_using(&[(1, 300), (2, 600)], Some(10)));
+	add_run_usage(&mut rack, &run_using(&[(1, 300)], Some(10)));
+
+	let alerts = reagent_alerts_for(&rack, &thresholds(), filled_at + Duration::days(1));
+	let due : Vec<(i32, usize)> = alerts.iter().map(|alert| (alert.jar_number, alert.reasons.len())).collect();
+	// jar 1 has reached both the run and immersion limits; jar 2 only the immersion limit
+	assert_eq!(due, vec![(1, 2), (2, 1)]);
+
+	let alerts = reagent_alerts_for(&rack, &thresholds(), filled_at + Duration::days(7));
+	assert_eq!(alerts.len(), NUMBER_OF_JARS as usize);
+    }
+
+    #[test]
+    fn jars_without_a_threshold_are_never_due() {
+	let mut rack = Rack::empty();
+	rack.jar_mut(1).unwrap().substance = "Water".to_string();
+	add_run_usage(&mut rack, &run_using(&[(1, 10_000)], Some(100)));
+	assert!(reagent_alerts_for(&rack, &thresholds(), Utc::now()).is_empty());
+    }
+}
@@ -0,0 +1,258 @@
+pub use crate::structs_and_consts::*;
+pub use crate::motion::*;
+pub use crate::procedure_run::*;
+
+use chrono::{DateTime, Utc};
+use gpio::GpioIn;
+use serde::*;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::{thread, time};
+
+// Rocket 0.4 buffers chunked responses and has no way to flush them per event, so Server-Sent Events
+// are served from a small dedicated listener instead. Clients connect with an EventSource to EVENT_SERVER_PORT.
+
+// A snapshot of everything a client needs to draw the run screen. A new snapshot is published whenever any field changes.
+#[derive(Debug, Serialize, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct StatusEvent {
+    pub run_state: ProcedureExecutionStateEnum,
+    pub current_procedure_step_number: Option<i32>,
+    pub current_cycle_number: Option<i32>,
+    pub current_group_path: Vec<GroupIteration>,
+    pub activity: Option<RunActivity>,
+    pub operator_message: Option<String>,
+    pub seconds_remaining: u64,
+    pub estimated_seconds_remaining: Option<i32>,
+    pub x_position_inches: Option<f64>,
+    pub z_position_inches: Option<f64>,
+    pub x_position_mm: Option<f64>,
+    pub z_position_mm: Option<f64>,
+    pub alarms: Vec<String>,
+}
+
+// Fans events out to every connected client.
+// Each event is kept pre-formatted as an SSE message so it only has to be serialized once.
+#[derive(Default)]
+pub struct EventBroadcaster {
+    subscribers: Mutex<Vec<Sender<String>>>,
+    latest_status: Mutex<Option<String>>,
+}
+
+pub type SharedEventBroadcaster = Arc<EventBroadcaster>;
+
+fn format_sse_message(event_name: &str, data: &str) -> String {
+    format!("event: {}\ndata: {}\n\n", event_name, data)
+}
+
+impl EventBroadcaster {
+    pub fn new() -> EventBroadcaster {
+	EventBroadcaster {
+	    subscribers: Mutex::new(Vec::new()),
+	    latest_status: Mutex::new(None),
+	}
+    }
+
+    // Registers a new client. The latest status message (if any) is returned so the client can be brought up to date immediately.
+    pub fn subscribe(&self) -> (Receiver<String>, Option<String>) {
+	let (sender, receiver) = channel();
+	self.subscribers.lock().unwrap().push(sender);
+	(receiver, self.latest_status.lock().unwrap().clone())
+    }
+
+    // Sends an event with JSON data to all clients, dropping any that have disconnected.
+    pub fn publish<T: Serialize>(&self, event_name: &str, data: &T) {
+	let json = match serde_json::to_string(data) {
+	    Ok(json) => json,
+	    Err(e) => {
+		println!("Couldn't serialize {} event: {:?}", event_name, e);
+		return;
+	    }
+	};
+	let message = format_sse_message(event_name, &json);
+	if event_name == "status" {
+	    *self.latest_status.lock().unwrap() = Some(message.clone());
+	}
+	self.subscribers.lock().unwrap().retain(|sender| sender.send(message.clone()).is_ok());
+    }
+}
+
+// Builds the current status. The Pi is only try_locked since it is held for the whole of a move;
+// while it is busy the previous positions and e-stop reading are carried over.
+fn read_status(pi_mutex: &SharedPi, pes: &ProcedureExecutionState, previous: &Option<StatusEvent>) -> StatusEvent {
+    let run_status = current_run_status(pes);
+    let (mut x_position, mut z_position, mut estop_pressed) = match previous {
+	Some(prev) => (prev.x_position_inches.map(Length::inches), prev.z_position_inches.map(Length::inches), prev.alarms.iter().any(|a| a == "estop_pressed")),
+	None => (None, None, false),
+    };
+    if let Ok(mut pi) = pi_mutex.try_lock() {
+	let pi = &mut *pi;
+	x_position = pi.stepper_x.pos.map(|v| pulses_to_length(v, &pi.stepper_x));
+	z_position = pi.stepper_z.pos.map(|v| pulses_to_length(v, &pi.stepper_z));
+	estop_pressed = pi.estop.read_value().map(bool::from).unwrap_or(false);
+    }
+
+    let mut alarms = Vec::new();
+    if estop_pressed {
+	alarms.push("estop_pressed".to_string());
+    }
+    if pes.limit_switch_hit_unexpectedly.load(Ordering::Relaxed) {
+	alarms.push("limit_switch_hit_unexpectedly".to_string());
+    }
+    alarms.extend(run_alarms(run_status.as_ref(), Utc::now()));
+
+    StatusEvent {
+	run_state: pes.atm.load(Ordering::Relaxed),
+	current_procedure_step_number: run_status.as_ref().map(|s| s.current_procedure_step_number),
+	current_cycle_number: run_status.as_ref().map(|s| s.current_cycle_number),
+	current_group_path: run_status.as_ref().map(|s| s.current_group_path.clone()).unwrap_or_default(),
+	activity: run_status.as_ref().map(|s| s.activity),
+	operator_message: run_status.as_ref().and_then(|s| s.operator_message.clone()),
+	seconds_remaining: pes.seconds_remaining.load(Ordering::Relaxed),
+	estimated_seconds_remaining: run_status.as_ref().map(|s| s.estimated_seconds_remaining),
+	x_position_inches: x_position.map(Length::as_inches),
+	z_position_inches: z_position.map(Length::as_inches),
+	x_position_mm: x_position.map(Length::as_mm),
+	z_position_mm: z_position.map(Length::as_mm),
+	alarms,
+    }
+}
+
+// Alarms about the run itself. An operator action step raises one while it waits, and another once it has waited
+// OPERATOR_WAIT_ALARM_SECONDS, so a run left unattended is noticed.
+fn run_alarms(run_status: Option<&ProcedureRunStatus>, now: DateTime<Utc>) -> Vec<String> {
+    let mut alarms = Vec::new();
+    if let Some(status) = run_status.filter(|s| s.operator_message.is_some()) {
+	alarms.push("waiting_for_operator".to_string());
+	let waited = status.current_procedure_step_start_time.map(|start| (now - start).num_seconds()).unwrap_or(0);
+	if waited >= OPERATOR_WAIT_ALARM_SECONDS {
+	    alarms.push("operator_wait_overdue".to_string());
+	}
+    }
+    alarms
+}
+
+// Watches the Pi and PES and publishes a status event whenever something changes.
+fn monitor_status(pi_mutex: SharedPi, pes: SharedProcedureExecutionState, broadcaster: SharedEventBroadcaster) {
+    let mut last_status : Option<StatusEvent> = None;
+    loop {
+	let status = read_status(&pi_mutex, &pes, &last_status);
+	if last_status.as_ref() != Some(&status) {
+	    broadcaster.publish("status", &status);
+	    last_status = Some(status);
+	}
+	thread::sleep(time::Duration::from_millis(100));
+    }
+}
+
+fn handle_event_client(stream: TcpStream, broadcaster: SharedEventBroadcaster) -> std::io::Result<()> {
+    // Read and discard the request. Every path gets the same event stream.
+    let mut reader = BufReader::new(stream.try_clone()?);
+    loop {
+	let mut line = String::new();
+	let bytes_read = reader.read_line(&mut line)?;
+	if bytes_read == 0 || line == "\r\n" || line == "\n" {
+	    break;
+	}
+    }
+
+    let mut stream = stream;
+    stream.write_all(b"HTTP/1.1 200 OK\r\n\
+                       Content-Type: text/event-stream\r\n\
+                       Cache-Control: no-cache\r\n\
+                       Connection: keep-alive\r\n\
+                       Access-Control-Allow-Origin: *\r\n\r\n")?;
+
+    let (receiver, latest_status) = broadcaster.subscribe();
+    if let Some(message) = latest_status {
+	stream.write_all(message.as_bytes())?;
+    }
+    stream.flush()?;
+
+    loop {
+	match receiver.recv_timeout(time::Duration::from_secs(15)) {
+	    Ok(message) => stream.write_all(message.as_bytes())?,
+	    // SSE comments keep proxies and the browser from timing out an idle connection
+	    Err(RecvTimeoutError::Timeout) => stream.write_all(b": keepalive\n\n")?,
+	    Err(RecvTimeoutError::Disconnected) => return Ok(()),
+	}
+	stream.flush()?;
+    }
+}
+
+// Starts the status monitor and the event stream listener on background threads.
+pub fn start_event_server(pi_mutex: SharedPi, pes: SharedProcedureExecutionState, broadcaster: SharedEventBroadcaster, port: u16) {
+    {
+	let broadcaster = broadcaster.clone();
+	thread::spawn(move || monitor_status(pi_mutex, pes, broadcaster));
+    }
+
+    thread::spawn(move || {
+	let listener = match TcpListener::bind(("0.0.0.0", port)) {
+	    Ok(listener) => listener,
+	    Err(e) => {
+		println!("Couldn't start the event server on port {}: {:?}", port, e);
+		return;
+	    }
+	};
+	for stream in listener.incoming() {
+	    match stream {
+		Ok(stream) => {
+		    let broadcaster = broadcaster.clone();
+		    thread::spawn(move || {
+			let ret = handle_event_client(stream, broadcaster);
+			println!("Event client disconnected: {:?}", ret);
+		    });
+		}
+		Err(e) => println!("Event server couldn't accept a connection: {:?}", e),
+	    }
+	}
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    fn waiting_at_step_4(since: DateTime<Utc>) -> ProcedureRunStatus {
+	ProcedureRunStatus {
+	    current_procedure_id: "hematoxylin-eosin".to_string(),
+	    current_procedure_name: "H&E".to_string(),
+	    current_procedure_step_number: 4,
+	    current_cycle_number: 1,
+	    current_group_path: Vec::new(),
+	    current_procedure_step_start_time: Some(since),
+	    current_step_elapsed_seconds: 0,
+	    current_step_seconds_remaining: 0,
+	    run_start_time: since - Duration::minutes(25),
+	    total_elapsed_seconds: 1500,
+	    estimated_seconds_remaining: 420,
+	    estimated_completion_time: since + Duration::minutes(7),
+	    activity: RunActivity::WaitingForOperator,
+	    operator_message: Some("Add the coverslips".to_string()),
+	    run_state: ProcedureExecutionStateEnum::Running,
+	}
+    }
+
+    #[test]
+    fn operator_waits_raise_alarms() {
+	let now = Utc::now();
+	assert!(run_alarms(None, now).is_empty());
+
+	let status = waiting_at_step_4(now - Duration::seconds(30));
+	assert_eq!(run_alarms(Some(&status), now), vec!["waiting_for_operator"]);
+	// still waiting while paused
+	let paused = ProcedureRunStatus { activity: RunActivity::Paused, ..status };
+	assert_eq!(run_alarms(Some(&paused), now), vec!["waiting_for_operator"]);
+
+	let overdue = waiting_at_step_4(now - Duration::seconds(OPERATOR_WAIT_ALARM_SECONDS));
+	assert_eq!(run_alarms(Some(&overdue), now), vec!["waiting_for_operator", "operator_wait_overdue"]);
+
+	let immersing = ProcedureRunStatus { activity: RunActivity::Immersing, operator_message: None, ..overdue };
+	assert!(run_alarms(Some(&immersing), now).is_empty());
+    }
+}
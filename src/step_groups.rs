@@ -0,0 +1,202 @@
+pub use crate::structs_and_consts::*;
+
+// Step groups repeat runs of steps within a cycle. A procedure's steps are stored flat, with each group naming the
+// range of steps it covers, so one cycle is run by expanding the groups into a sequence of steps.
+
+// One entry in the expanded sequence of steps for a cycle.
+#[derive(Debug, Clone)]
+pub struct SequencedStep {
+    pub step_index: usize, // zero-indexed into procedure_steps
+    pub group_path: Vec<GroupIteration>, // the groups the step is in, outermost first
+}
+
+// Finds the outermost group that starts at step_index, ends before end_index and isn't already being expanded.
+// expanding has an entry per group, set while that group is being expanded.
+// Groups with the same range nest inside each other in the order they're listed.
+fn outermost_group_at(groups: &[StepGroup], step_index: usize, end_index: usize, expanding: &[bool]) -> Option<usize> {
+    let step_number = step_index as i32 + 1;
+    groups.iter().enumerate()
+	.filter(|(i, group)| {
+	    !expanding[*i]
+		&& group.first_step_number == step_number
+		&& group.last_step_number >= group.first_step_number
+		&& (group.last_step_number as usize) <= end_index
+	})
+	.max_by_key(|(i, group)| (group.last_step_number, -(*i as i64)))
+	.map(|(i, _)| i)
+}
+
+fn expand_steps(groups: &[StepGroup], start_index: usize, end_index: usize,
+		expanding: &mut [bool], group_path: &mut Vec<GroupIteration>, sequence: &mut Vec<SequencedStep>) {
+    let mut step_index = start_index;
+    while step_index < end_index {
+	match outermost_group_at(groups, step_index, end_index, expanding) {
+	    Some(group_index) => {
+		let group = &groups[group_index];
+		let group_end_index = group.last_step_number as usize;
+		expanding[group_index] = true;
+		for iteration in 1..=group.repeat.max(0) {
+		    group_path.push(GroupIteration {
+			first_step_number: group.first_step_number,
+			last_step_number: group.last_step_number,
+			iteration,
+			repeat: group.repeat,
+		    });
+		    expand_steps(groups, step_index, group_end_index, expanding, group_path, sequence);
+		    group_path.pop();
+		}
+		expanding[group_index] = false;
+		step_index = group_end_index;
+	    }
+	    None => {
+		sequence.push(SequencedStep {
+		    step_index,
+		    group_path: group_path.clone(),
+		});
+		step_index += 1;
+	    }
+	}
+    }
+}
+
+// Counts the steps expand_steps would produce, without expanding them. Saturates rather than overflowing.
+fn count_steps(groups: &[StepGroup], start_index: usize, end_index: usize, expanding: &mut [bool]) -> u64 {
+    let mut count : u64 = 0;
+    let mut step_index = start_index;
+    while step_index < end_index {
+	match outermost_group_at(groups, step_index, end_index, expanding) {
+	    Some(group_index) => {
+		let group = &groups[group_index];
+		let group_end_index = group.last_step_number as usize;
+		expanding[group_index] = true;
+		let group_count = count_steps(groups, step_index, group_end_index, expanding);
+		expanding[group_index] = false;
+		count = count.saturating_add(group_count.saturating_mul(group.repeat.max(0) as u64));
+		step_index = group_end_index;
+	    }
+	    None => {
+		count = count.saturating_add(1);
+		step_index += 1;
+	    }
+	}
+    }
+    count
+}
+
+// How many steps a single cycle runs once the groups are expanded.
+pub fn step_sequence_length(groups: &[StepGroup], number_of_steps: usize) -> u64 {
+    count_steps(groups, 0, number_of_steps, &mut vec![false; groups.len()])
+}
+
+// The steps run in a single cycle of the procedure, in order, with each group's steps repeated.
+// Groups that are out of range or partially overlap another group are ignored here; validation reports them.
+// A sequence longer than MAX_SEQUENCE_LENGTH comes out empty rather than being expanded; validation won't save or run one.
+pub fn step_sequence(proc: &Procedure) -> Vec<SequencedStep> {
+    let mut sequence : Vec<SequencedStep> = Vec::new();
+    if step_sequence_length(&proc.step_groups, proc.procedure_steps.len()) > MAX_SEQUENCE_LENGTH {
+	return sequence;
+    }
+    expand_steps(&proc.step_groups, 0, proc.procedure_steps.len(), &mut vec![false; proc.step_groups.len()], &mut Vec::new(), &mut sequence);
+    sequence
+}
+
+fn contains(outer: &StepGroup, inner: &StepGroup) -> bool {
+    outer.first_step_number <= inner.first_step_number && inner.last_step_number <= outer.last_step_number
+}
+
+// Two groups partially overlap if they share steps but neither contains the other.
+pub fn groups_partially_overlap(a: &StepGroup, b: &StepGroup) -> bool {
+    let share_steps = a.first_step_number <= b.last_step_number && b.first_step_number <= a.last_step_number;
+    share_steps && !contains(a, b) && !contains(b, a)
+}
+
+// How many groups (including itself) the group at group_index is nested in. Groups with the same range count
+// as nested in the order they're listed, matching step_sequence.
+pub fn group_depth(groups: &[StepGroup], group_index: usize) -> i32 {
+    let group = &groups[group_index];
+    1 + groups.iter().enumerate()
+	.filter(|(i, other)| {
+	    let same_range = other.first_step_number == group.first_step_number && other.last_step_number == group.last_step_number;
+	    *i != group_index && contains(other, group) && (!same_range || *i < group_index)
+	})
+	.count() as i32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A procedure with one step in each of the given jars and the given (first, last, repeat) groups.
+    fn procedure(jars: &[i32], groups: &[(i32, i32, i32)]) -> Procedure {
+	let steps : Vec<serde_json::Value> = jars.iter()
+	    .map(|jar_number| serde_json::json!({"time_in_seconds": 10, "jar_number": jar_number}))
+	    .collect();
+	let groups : Vec<serde_json::Value> = groups.iter()
+	    .map(|&(first, last, repeat)| serde_json::json!({"first_step_number": first, "last_step_number": last, "repeat": repeat}))
+	    .collect();
+	serde_json::from_value(serde_json::json!({
+	    "_id": "grouped",
+	    "_rev": "1-a",
+	    "type": "procedure",
+	    "name": "Grouped",
+	    "jar_contents": [],
+	    "procedure_steps": steps,
+	    "step_groups": groups,
+	})).unwrap()
+    }
+
+    fn step_numbers(proc: &Procedure) -> Vec<usize> {
+	step_sequence(proc).iter().map(|step| step.step_index + 1).collect()
+    }
+
+    #[test]
+    fn procedures_without_groups_run_each_step_once() {
+	assert_eq!(step_numbers(&procedure(&[1, 2, 3], &[])), vec![1, 2, 3]);
+	assert!(step_sequence(&procedure(&[1, 2, 3], &[])).iter().all(|step| step.group_path.is_empty()));
+    }
+
+    #[test]
+    fn nested_groups_repeat_within_each_other() {
+	// steps 2 to 4 twice, and within each of those step 3 three times
+	let proc = procedure(&[1, 2, 3, 4, 5], &[(3, 3, 3), (2, 4, 2)]);
+	assert_eq!(step_numbers(&proc), vec![1, 2, 3, 3, 3, 4, 2, 3, 3, 3, 4, 5]);
+
+	let sequence = step_sequence(&proc);
+	let path : Vec<(i32, i32, i32)> = sequence[4].group_path.iter()
+	    .map(|iteration| (iteration.first_step_number, iteration.iteration, iteration.repeat))
+	    .collect();
+	// the third step 3 of the first time through steps 2 to 4
+	assert_eq!(path, vec![(2, 1, 2), (3, 3, 3)]);
+    }
+
+    #[test]
+    fn groups_with_the_same_range_nest_in_the_order_listed() {
+	let groups = [(1, 2, 2), (1, 2, 3)];
+	let proc = procedure(&[1, 2], &groups);
+	assert_eq!(step_sequence(&proc).len(), 2 * 3 * 2);
+	assert_eq!(step_sequence(&proc)[0].group_path[0].repeat, 2);
+	assert_eq!(group_depth(&proc.step_groups, 0), 1);
+	assert_eq!(group_depth(&proc.step_groups, 1), 2);
+    }
+
+    #[test]
+    fn invalid_groups_are_left_out_of_the_sequence() {
+	// steps 5 to 9 are out of range, and 2 to 3 only partly overlaps 1 to 2
+	let proc = procedure(&[1, 2, 3], &[(5, 9, 2), (1, 2, 2), (2, 3, 2)]);
+	assert_eq!(step_numbers(&proc), vec![1, 2, 1, 2, 3]);
+	assert!(groups_partially_overlap(&proc.step_groups[1], &proc.step_groups[2]));
+	assert!(!groups_partially_overlap(&proc.step_groups[1], &proc.step_groups[0]));
+    }
+
+    #[test]
+    fn sequence_length_is_counted_without_expanding() {
+	for groups in [vec![], vec![(3, 3, 3), (2, 4, 2)], vec![(1, 2, 2), (1, 2, 3)], vec![(5, 9, 2), (1, 2, 2), (2, 3, 2)]].iter() {
+	    let proc = procedure(&[1, 2, 3, 4, 5], groups);
+	    assert_eq!(step_sequence_length(&proc.step_groups, 5), step_sequence(&proc).len() as u64);
+	}
+	// 1000^4 steps would never finish expanding, but counts straight away
+	let proc = procedure(&[1, 2], &[(1, 2, 1000), (1, 2, 1000), (1, 2, 1000), (1, 2, 1000)]);
+	assert_eq!(step_sequence_length(&proc.step_groups, 2), 2 * 1000u64.pow(4));
+	assert!(step_sequence(&proc).is_empty());
+    }
+}
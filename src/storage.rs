@@ -0,0 +1,324 @@
+pub use crate::structs_and_consts::*;
+
+use chrono::{DateTime, Utc};
+use juniper::{FieldError, Object, Value};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+// Where procedures, settings and the rack are kept. The GraphQL layer and the runner only use these traits, so the
+// database behind them can be swapped out: CouchDBStore (couchdb.rs) talks to a CouchDB service, FileStore
+// (file_store.rs) keeps everything in a JSON file with no service needed, and MemoryStore (memory_store.rs) keeps
+// everything in memory for testing. Which one is used is picked by StorageBackend::from_env.
+// The stores are given to Rocket as managed state; see main().
+
+// Why a store couldn't do what it was asked. Converts into a GraphQL error whose extensions.code is one of
+// NOT_FOUND, CONFLICT, NOT_ALLOWED, STORAGE_UNAVAILABLE, INVALID_DOCUMENT or STORAGE_ERROR, so clients can tell a missing
+// document from a database outage without parsing the message. Conflicts can also carry extensions.current,
+// the _id, _rev and version of what's saved now.
+// StorageError deliberately doesn't implement Display: juniper turns anything Display into a FieldError with no code.
+#[derive(Debug, Clone, PartialEq)]
+pub enum StorageError {
+    NotFound(String), // the document doesn't exist
+    Conflict { message: String, current: Option<CurrentRevision> }, // the document was changed since it was loaded (a stale _rev)
+    NotAllowed(String), // the change isn't allowed in the document's current state, e.g. purging a procedure that has been run
+    Unavailable(String), // the database couldn't be reached or written to
+    InvalidDocument(String), // a stored document couldn't be parsed, or the database rejected one as malformed
+    Unexpected(String), // anything else the database reported
+}
+
+pub type StorageResult<T> = Result<T, StorageError>;
+
+// What's saved now, reported with a conflict so the client can tell how far behind its copy is.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CurrentRevision {
+    pub id: String,
+    pub rev: String,
+    pub version: Option<i32>, // for procedures
+}
+
+impl StorageError {
+    pub fn conflict(message: String) -> StorageError {
+	StorageError::Conflict { message, current: None }
+    }
+
+    pub fn code(&self) -> &'static str {
+	match self {
+	    StorageError::NotFound(_) => "NOT_FOUND",
+	    StorageError::Conflict { .. } => "CONFLICT",
+	    StorageError::NotAllowed(_) => "NOT_ALLOWED",
+	    StorageError::Unavailable(_) => "STORAGE_UNAVAILABLE",
+	    StorageError::InvalidDocument(_) => "INVALID_DOCUMENT",
+	    StorageError::Unexpected(_) => "STORAGE_ERROR",
+	}
+    }
+
+    pub fn message(&self) -> &str {
+	match self {
+	    StorageError::NotFound(message)
+		| StorageError::Conflict { message, .. }
+		| StorageError::NotAllowed(message)
+		| StorageError::Unavailable(message)
+		| StorageError::InvalidDocument(message)
+		| StorageError::Unexpected(message) => message,
+	}
+    }
+}
+
+impl From<StorageError> for FieldError {
+    fn from(e: StorageError) -> FieldError {
+	let mut extensions = Object::with_capacity(3);
+	extensions.add_field("code", Value::scalar(e.code().to_string()));
+	extensions.add_field("internal_error", Value::scalar(e.message().to_string()));
+	if let StorageError::Conflict { current: Some(current), .. } = &e {
+	    let mut obj = Object::with_capacity(3);
+	    obj.add_field("_id", Value::scalar(current.id.clone()));
+	    obj.add_field("_rev", Value::scalar(current.rev.clone()));
+	    obj.add_field("version", match current.version {
+		Some(version) => Value::scalar(version),
+		None => Value::null(),
+	    });
+	    extensions.add_field("current", Value::object(obj));
+	}
+	FieldError::new(e.message(), Value::object(extensions))
+    }
+}
+
+pub trait ProcedureStore: Send + Sync {
+    // Sets up the database if it isn't already: creates it, installs or updates its views and seeds the settings.
+    // Safe to run on every start.
+    fn configure(&self) -> StorageResult<DatabaseSetup>;
+
+    // Every procedure that could be read, archived ones included, plus the procedure docs that couldn't be.
+    fn procedure_list(&self) -> StorageResult<ProcedureList>;
+
+    // The procedures that could be read and aren't archived. The docs that couldn't be read are logged and left out.
+    fn procedures(&self) -> StorageResult<Vec<Procedure>> {
+	let list = self.procedure_list()?;
+	for skipped in list.skipped.iter() {
+	    println!("Skipped procedure {}: {}", skipped.id, skipped.reason);
+	}
+	Ok(list.procedures.into_iter().filter(|proc| proc.archived_at.is_none()).collect())
+    }
+
+    // The procedures in the trash, most recently archived first.
+    fn archived_procedures(&self) -> StorageResult<Vec<Procedure>> {
+	let mut archived : Vec<Procedure> = self.procedure_list()?.procedures.into_iter()
+	    .filter(|proc| proc.archived_at.is_some())
+	    .collect();
+	archived.sort_by(|a, b| b.archived_at.cmp(&a.archived_at));
+	Ok(archived)
+    }
+
+    // None if there's no procedure with the given _id.
+    fn find_procedure(&self, id: String) -> StorageResult<Option<Procedure>>;
+
+    fn procedure_by_id(&self, id: String) -> StorageResult<Procedure> {
+	match self.find_procedure(id.clone())? {
+	    Some(proc) => Ok(proc),
+	    None => Err(StorageError::NotFound(format!("No procedure with ID {} found.", id))),
+	}
+    }
+
+    // Saves the procedure as given. Most callers want save_procedure_version, which also keeps the version history.
+    fn save_procedure(&self, procedure: ProcedureInputObject) -> StorageResult<Procedure>;
+
+    // Archives the procedure as of archived_at, or restores it if archived_at is None. Doesn't change its version.
+    fn set_procedure_archived(&self, id: String, rev: String, archived_at: Option<DateTime<Utc>>) -> StorageResult<Procedure>;
+
+    // Moves the procedure to the trash. It can be restored with restore_procedure.
+    fn archive_procedure(&self, id: String, rev: String) -> StorageResult<Procedure> {
+	self.set_procedure_archived(id, rev, Some(Utc::now()))
+    }
+
+    fn restore_procedure(&self, id: String, rev: String) -> StorageResult<Procedure> {
+	self.set_procedure_archived(id, rev, None)
+    }
+
+    // Deletes an archived procedure for good. Procedures that have been run are kept archived instead,
+    // so the run history can still show what was run.
+    fn purge_procedure(&self, id: String, rev: String) -> StorageResult<()> {
+	let proc = self.procedure_by_id(id.clone())?;
+	if proc.archived_at.is_none() {
+	    return Err(StorageError::NotAllowed(format!("{} isn't in the trash. Delete it before purging it.", proc.name)));
+	}
+	let recorded_runs = proc.runs.unwrap_or(0) - proc.runs_before_run_history.unwrap_or(0);
+	if recorded_runs > 0 {
+	    return Err(StorageError::NotAllowed(format!("{} can't be purged because the run history refers to it {} times. It will stay in the trash.",
+							proc.name, recorded_runs)));
+	}
+	self.delete_procedure(id, rev)
+    }
+
+    // Removes the procedure doc for good, without any checks. Most callers want archive_procedure or purge_procedure.
+    fn delete_procedure(&self, id: String, rev: String) -> StorageResult<()>;
+
+    // Saved versions of a procedure, oldest first.
+    fn procedure_versions(&self, procedure_id: String) -> StorageResult<Vec<ProcedureVersion>>;
+
+    fn procedure_version(&self, procedure_id: String, version: i32) -> StorageResult<ProcedureVersion>;
+
+    // Stores a version unless it's already stored. Versions never change once stored.
+    fn create_procedure_version(&self, proc_version: &ProcedureVersion) -> StorageResult<()>;
+
+    // Saves the record of a finished run. Procedures' run counts are counted from these.
+    fn save_run_record(&self, run_record: &RunRecord) -> StorageResult<RunRecord>;
+
+    // Rewrites procedures that still store a substance on each step, returning the ones rewritten.
+    // Only CouchDB can hold those; the other stores reconcile procedures with their jar_contents as they're loaded.
+    fn migrate_step_substances(&self) -> StorageResult<Vec<Procedure>> {
+	Ok(Vec::new())
+    }
+}
+
+pub trait SettingsStore: Send + Sync {
+    fn settings(&self) -> StorageResult<Settings>;
+
+    // Saves the settings doc as given. See validate_settings for the checks to make first.
+    fn save_settings_doc(&self, settings: Settings) -> StorageResult<Settings>;
+}
+
+// The jars loaded on the instrument and when their reagents should be replaced.
+pub trait RackStore: Send + Sync {
+    fn rack(&self) -> StorageResult<Rack>;
+
+    // Saves the rack doc as given, usage counters included.
+    fn save_rack_doc(&self, rack: Rack) -> StorageResult<Rack>;
+
+    // Saves the rack. Usage counters aren't part of the input, so they're carried over from the saved rack
+    // unless the jar's substance or fill time changed, in which case the jar counts as newly filled.
+    fn save_rack(&self, rack_input_object: RackInputObject) -> StorageResult<Rack> {
+	let existing = self.rack()?;
+	let jars : Vec<RackJar> = rack_input_object.jars.into_iter().map(|jar| {
+	    let mut new_jar = RackJar {
+		jar_number: jar.jar_number,
+		substance: jar.substance,
+		filled_at: jar.filled_at,
+		lot_number: jar.lot_number,
+		volume_ml: jar.volume_ml,
+		runs_since_filled: 0,
+		immersion_seconds_since_filled: 0,
+		slides_since_filled: 0,
+	    };
+	    if let Some(old_jar) = existing.jar(new_jar.jar_number) {
+		if old_jar.substance == new_jar.substance && old_jar.filled_at == new_jar.filled_at {
+		    new_jar.runs_since_filled = old_jar.runs_since_filled;
+		    new_jar.immersion_seconds_since_filled = old_jar.immersion_seconds_since_filled;
+		    new_jar.slides_since_filled = old_jar.slides_since_filled;
+		}
+	    }
+	    new_jar
+	}).collect();
+
+	self.save_rack_doc(Rack {
+	    id: rack_input_object.id,
+	    rev: rack_input_object.rev,
+	    type_: rack_input_object.type_,
+	    jars,
+	})
+    }
+
+    fn reagent_thresholds(&self) -> StorageResult<ReagentThresholds>;
+
+    fn save_reagent_thresholds(&self, thresholds: ReagentThresholdsInputObject) -> StorageResult<ReagentThresholds>;
+}
+
+// Every document at once, for backups (see backup.rs).
+pub trait BackupStore: Send + Sync {
+    // Every document except CouchDB design docs, as it's stored.
+    fn all_docs(&self) -> StorageResult<Vec<serde_json::Value>>;
+
+    // Saves the documents, replacing any with the same _id. Documents that aren't in the list are left alone.
+    fn load_docs(&self, docs: Vec<serde_json::Value>) -> StorageResult<DocsLoaded>;
+}
+
+// What load_docs did.
+#[derive(Debug, Clone)]
+pub struct DocsLoaded {
+    pub created: i32,
+    pub overwritten: i32,
+    pub skipped: Vec<SkippedDocument>, // not loaded, with the reason
+}
+
+pub type SharedProcedureStore = Arc<dyn ProcedureStore>;
+pub type SharedSettingsStore = Arc<dyn SettingsStore>;
+pub type SharedRackStore = Arc<dyn RackStore>;
+pub type SharedBackupStore = Arc<dyn BackupStore>;
+
+pub const STORAGE_ENV_VAR: &str = "OPENSTAINER_STORAGE"; // couchdb (the default), file or memory
+pub const DATA_FILE_ENV_VAR: &str = "OPENSTAINER_DATA_FILE"; // where the file backend keeps its data
+pub const DEFAULT_DATA_FILE: &str = "openstainer-data.json";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StorageBackend {
+    CouchDB,
+    File(PathBuf),
+    Memory,
+}
+
+impl StorageBackend {
+    // Reads the backend to use from OPENSTAINER_STORAGE, and for the file backend, the file from OPENSTAINER_DATA_FILE.
+    pub fn from_env() -> Result<StorageBackend, String> {
+	let name = std::env::var(STORAGE_ENV_VAR).unwrap_or_else(|_| "couchdb".to_string());
+	match name.trim().to_lowercase().as_str() {
+	    "" | "couchdb" => Ok(StorageBackend::CouchDB),
+	    "file" => Ok(StorageBackend::File(data_file_from_env())),
+	    "memory" => Ok(StorageBackend::Memory),
+	    _ => Err(format!("Unknown {} {}; use couchdb, file or memory.", STORAGE_ENV_VAR, name)),
+	}
+    }
+}
+
+pub fn data_file_from_env() -> PathBuf {
+    PathBuf::from(std::env::var(DATA_FILE_ENV_VAR).unwrap_or_else(|_| DEFAULT_DATA_FILE.to_string()))
+}
+
+// The stores for a backend. They're all the same object, seen through each trait.
+pub struct Stores {
+    pub procedures: SharedProcedureStore,
+    pub settings: SharedSettingsStore,
+    pub racks: SharedRackStore,
+    pub backups: SharedBackupStore,
+}
+
+fn stores<S: ProcedureStore + SettingsStore + RackStore + BackupStore + 'static>(store: S) -> Stores {
+    let store = Arc::new(store);
+    Stores {
+	procedures: store.clone(),
+	settings: store.clone(),
+	racks: store.clone(),
+	backups: store,
+    }
+}
+
+pub fn open_stores(backend: &StorageBackend) -> Result<Stores, String> {
+    match backend {
+	StorageBackend::CouchDB => Ok(stores(crate::couchdb::CouchDBStore)),
+	StorageBackend::File(path) => Ok(stores(crate::file_store::FileStore::open(path.clone())?)),
+	StorageBackend::Memory => Ok(stores(crate::memory_store::MemoryStore::new())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn field_errors_carry_the_storage_error_code() {
+	let e : FieldError = StorageError::conflict("The procedure has been changed since it was loaded.".to_string()).into();
+	assert_eq!(e.message(), "The procedure has been changed since it was loaded.");
+	let extensions = e.extensions().as_object_value().unwrap();
+	assert_eq!(extensions.get_field_value("code").and_then(|code| code.as_scalar_value::<String>()), Some(&"CONFLICT".to_string()));
+	assert!(extensions.get_field_value("current").is_none());
+    }
+
+    #[test]
+    fn conflicts_report_what_is_saved_now() {
+	let e : FieldError = StorageError::Conflict {
+	    message: "Stale".to_string(),
+	    current: Some(CurrentRevision { id: "pap".to_string(), rev: "4-c".to_string(), version: Some(3) }),
+	}.into();
+	let current = e.extensions().as_object_value().unwrap().get_field_value("current").unwrap().as_object_value().unwrap();
+	assert_eq!(current.get_field_value("_rev").and_then(|rev| rev.as_scalar_value::<String>()), Some(&"4-c".to_string()));
+	assert_eq!(current.get_field_value("version").and_then(|version| version.as_scalar_value::<i32>()), Some(&3));
+    }
+}
@@ -1,19 +1,40 @@
 use serde::*;
 use atomic_enum::*;
 use std::sync::atomic::*;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex, RwLock};
 use std::fmt;
 use rocket::request::FromParam;
 use rocket::http::RawStr;
+use chrono::{DateTime, Utc};
+
+pub use crate::length::*;
+use crate::step_groups::SequencedStep;
 
-pub type Inch = f64;
 pub type PulseCount = u64;
 
-pub const LEFT_POSITION: Inch = 0.35; // This is the position the rack needs to be in on the x-axis to make it into the first staining jar.
-pub const UP_POSITION: Inch = 3.5; // This is the "up" position on the z-axis. This is important for getting the rack into the proper down position, since the limit switch is on the upper end of the z-axis.
-pub const JAR_SPACING: Inch = 1.9; // This is the distance between jars. Used to calculate the jar positioning.
+pub const LEFT_POSITION: Length = Length::inches(0.35); // This is the position the rack needs to be in on the x-axis to make it into the first staining jar.
+pub const UP_POSITION: Length = Length::inches(3.5); // This is the "up" position on the z-axis. This is important for getting the rack into the proper down position, since the limit switch is on the upper end of the z-axis.
+pub const DOWN_POSITION: Length = Length::inches(0.0); // The position on the z-axis with the slides immersed in a jar.
+pub const X_POSITION_LIMIT: Length = Length::inches(10.0); // The x-axis's travel.
+pub const Z_POSITION_LIMIT: Length = Length::inches(3.75); // The z-axis's travel.
+pub const JAR_SPACING: Length = Length::inches(1.9); // This is the distance between jars. Used to calculate the jar positioning.
+pub const NUMBER_OF_JARS: i32 = 6; // The number of staining jars in the rack.
+pub const MAX_STEP_GROUP_DEPTH: i32 = 3; // How deeply step groups can be nested inside each other.
+pub const MAX_STEP_GROUPS: usize = 100; // The most step groups a procedure can have.
+pub const MAX_STEP_SECONDS: i32 = 24 * 60 * 60; // The longest a single step can take.
+pub const MAX_REPEAT: i32 = 1000; // The most times a procedure can be repeated.
+pub const MAX_GROUP_REPEAT: i32 = 1000; // The most times a step group can be repeated.
+pub const MAX_SEQUENCE_LENGTH: u64 = 10_000; // The most steps one cycle can run once its groups are expanded.
+pub const OPERATOR_WAIT_ALARM_SECONDS: i64 = 10 * 60; // How long an operator action step can wait before status events raise an alarm.
+
+pub const PULSES_PER_REVOLUTION: u64 = 4000; // Both stepper drivers are set to this many pulses per revolution.
+pub const TRAVEL_DISTANCE_PER_TURN: Length = Length::inches(0.063); // Both lead screws travel this far per revolution.
+pub const MOTION_ACCELERATION: f64 = 64_000_000.0; // The default acceleration constant passed to generate_wait_times by move_steps.
+
+pub const COUCHDB_URL: &str = "http://localhost:5984/slide_stainer";
+pub const IMPORT_SIZE_LIMIT: u64 = 10 * 1024 * 1024; // Largest file accepted by the import endpoints, in bytes.
+pub const EVENT_SERVER_PORT: u16 = 8001; // Port on which status events are streamed to clients (see status_events.rs).
 
-pub const COUCHDB_URL: &'static str = "http://localhost:5984/slide_stainer";
 //pub const COUCHDB_URL: &'static str = "http://localhost:5984/slide_stainer_demo";
 
 pub struct Stepper {
@@ -23,9 +44,9 @@ pub struct Stepper {
     pub limit_switch_low: Option<gpio::sysfs::SysFsGpioInput>, // these are Options since currently each axis only has one limit switch
     pub limit_switch_high: Option<gpio::sysfs::SysFsGpioInput>,
     pub pos: Option<PulseCount>, // this is an Option because we don't have a position before the device is homed.
-    pub position_limit: Inch,
+    pub position_limit: Length,
     pub pulses_per_revolution: u64,
-    pub travel_distance_per_turn: Inch,
+    pub travel_distance_per_turn: Length,
 }
 
 #[atomic_enum]
@@ -44,72 +65,236 @@ pub enum ProcedureExecutionStateEnum {
 pub struct ProcedureExecutionState {
     pub atm: AtomicProcedureExecutionStateEnum,
     pub seconds_remaining: AtomicU64, // number of seconds remaining in the current step
+    pub active_run: Mutex<Option<ActiveRun>>, // None when no procedure is running
+    pub limit_switch_hit_unexpectedly: AtomicBool, // set when a move during a run hits a limit switch, cleared when the move succeeds
+    pub operator_action_confirmed: AtomicBool, // set from the UI to end an operator action step
+    pub motion_settings: SharedMotionSettings, // the same settings as the Pi's, readable while the Pi is locked
+    pub current_procedure: Mutex<Option<Procedure>>, // the procedure last run; kept here rather than on the Pi, which is locked while moving
+    pub restoring: AtomicBool, // set while a backup is restored, so a run can't start part way through (see while_restoring)
+}
+
+pub type SharedProcedureExecutionState = Arc<ProcedureExecutionState>;
+
+#[derive(juniper::GraphQLEnum, Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[graphql(description="What happens in a procedure step.")]
+pub enum StepType {
+    #[graphql(description="The slides are immersed in the jar for the step's time.")]
+    Immerse,
+    #[graphql(description="The rack is held up over the jar while the operator does something, e.g. replaces a reagent. The run continues when the operator presses the green button or confirms in the UI.")]
+    OperatorAction,
+}
+
+impl Default for StepType {
+    fn default() -> Self { StepType::Immerse }
 }
 
 #[derive(juniper::GraphQLObject, Debug, Serialize, Deserialize, Clone)]
 #[graphql(description="A single step in a staining procedure.")]
 pub struct ProcedureStep {
-    #[graphql(description="The substance contained in the jar.")]
+    #[graphql(description="The substance contained in the jar. This is taken from the procedure's jar_contents.")]
+    #[serde(default, skip_serializing)] // derived from jar_contents when loaded (see jar_contents.rs)
     pub substance: String,
     #[graphql(description="The time (in seconds) to immerse the slide in the staining jar.")]
     pub time_in_seconds: i32,
     #[graphql(description="The one-indexed jar number in which the slide is to be immersed.")]
     pub jar_number: i32,
+    #[serde(default)]
+    pub step_type: StepType,
+    #[graphql(description="What the operator needs to do in an operator action step, e.g. \"Replace jar 5 with fresh eosin\".")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub operator_message: Option<String>,
 }
 
 #[derive(juniper::GraphQLInputObject, Debug, Serialize, Deserialize, Clone)]
 #[graphql(description="A single step in a staining procedure.")]
 pub struct ProcedureStepInputObject {
-    #[graphql(description="The substance contained in the jar.")]
-    pub substance: String,
+    #[graphql(description="The substance contained in the jar. Ignored unless the jar's contents are blank, since jar_contents takes precedence.")]
+    #[serde(default, skip_serializing)] // derived from jar_contents when loaded (see jar_contents.rs)
+    pub substance: Option<String>,
     #[graphql(description="The time (in seconds) to immerse the slide in the staining jar.")]
     pub time_in_seconds: i32,
     #[graphql(description="The one-indexed jar number in which the slide is to be immersed.")]
     pub jar_number: i32,
+    #[graphql(description="Defaults to Immerse.")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub step_type: Option<StepType>,
+    #[graphql(description="What the operator needs to do in an operator action step, e.g. \"Replace jar 5 with fresh eosin\".")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub operator_message: Option<String>,
 }
 
 impl From<ProcedureStep> for ProcedureStepInputObject {
 
     fn from(proc: ProcedureStep) -> Self {
 	ProcedureStepInputObject {
-	    substance : proc.substance,
+	    substance : Some(proc.substance),
 	    time_in_seconds: proc.time_in_seconds,
 	    jar_number: proc.jar_number,
+	    step_type: Some(proc.step_type),
+	    operator_message: proc.operator_message,
 	}
     }
 }
 
-#[derive(juniper::GraphQLObject, Debug, Serialize, Deserialize, Clone)]
-#[graphql(description="A staining procedure")]
+impl From<ProcedureStepInputObject> for ProcedureStep {
+
+    fn from(step: ProcedureStepInputObject) -> Self {
+	ProcedureStep {
+	    substance : step.substance.unwrap_or_default(),
+	    time_in_seconds: step.time_in_seconds,
+	    jar_number: step.jar_number,
+	    step_type: step.step_type.unwrap_or_default(),
+	    operator_message: step.operator_message,
+	}
+    }
+}
+
+// A run of consecutive steps that is repeated within each cycle of the procedure. Groups can be nested,
+// but a group must either contain another group entirely or not overlap it at all (see step_groups.rs).
+#[derive(juniper::GraphQLObject, Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[graphql(description="A run of consecutive steps that is repeated within each cycle of the procedure.")]
+pub struct StepGroup {
+    #[graphql(description="One-indexed number of the first step in the group.")]
+    pub first_step_number: i32,
+    #[graphql(description="One-indexed number of the last step in the group.")]
+    pub last_step_number: i32,
+    #[graphql(description="Number of times the steps in the group are run each time the group is reached.")]
+    pub repeat: i32,
+}
+
+#[derive(juniper::GraphQLInputObject, Debug, Serialize, Deserialize, Clone)]
+#[graphql(description="A run of consecutive steps that is repeated within each cycle of the procedure.")]
+pub struct StepGroupInputObject {
+    #[graphql(description="One-indexed number of the first step in the group.")]
+    pub first_step_number: i32,
+    #[graphql(description="One-indexed number of the last step in the group.")]
+    pub last_step_number: i32,
+    #[graphql(description="Number of times the steps in the group are run each time the group is reached.")]
+    pub repeat: i32,
+}
+
+impl From<StepGroup> for StepGroupInputObject {
+
+    fn from(group: StepGroup) -> Self {
+	StepGroupInputObject {
+	    first_step_number: group.first_step_number,
+	    last_step_number: group.last_step_number,
+	    repeat: group.repeat,
+	}
+    }
+}
+
+impl From<StepGroupInputObject> for StepGroup {
+
+    fn from(group: StepGroupInputObject) -> Self {
+	StepGroup {
+	    first_step_number: group.first_step_number,
+	    last_step_number: group.last_step_number,
+	    repeat: group.repeat,
+	}
+    }
+}
+
+// Where a run is within one of the step groups it's currently in.
+#[derive(juniper::GraphQLObject, Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+#[graphql(description="Which repetition of a step group a run is on.")]
+pub struct GroupIteration {
+    pub first_step_number: i32,
+    pub last_step_number: i32,
+    #[graphql(description="One-indexed repetition of the group that is running.")]
+    pub iteration: i32,
+    #[graphql(description="Number of times the group is repeated.")]
+    pub repeat: i32,
+}
+
+// The GraphQL fields of Procedure are defined in graphql.rs since some of them are calculated.
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Procedure {
     #[serde(rename="_id")]
-    #[graphql(name="_id", description="The _id of the procedure.")]
     pub id: String,
     
-    #[graphql(name="_rev", description="The CouchDB _rev of the procedure.")]
     #[serde(rename="_rev")]
     pub rev: String,
     
-    #[graphql(name="type", description="The CouchDB type of the procedure. Will always be :procedure.")]
     #[serde(rename="type")]
     pub type_: String,
     
-    #[graphql(description="Name of the procedure.")]
     pub name: String,
     
-    #[graphql(description="List of contents of what substanc is in jar")]
     pub jar_contents: Vec<String>,
 
-    #[graphql(description="A list of steps in the staining procedure.")]
     pub procedure_steps: Vec<ProcedureStep>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub step_groups: Vec<StepGroup>,
     
-    #[graphql(description="Number of times to repeat a given procedure for a single run.")]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub repeat: Option<i32>,
 
-    #[graphql(description="Number of times this procedure has ever been run.")]
+    // Procedures used to count their own runs. That count is kept so that runs from before run docs existed still count.
+    #[serde(rename="runs", default, skip_serializing_if = "Option::is_none")]
+    pub runs_before_run_history: Option<i32>,
+
+    #[serde(skip)]
+    pub runs: Option<i32>, // runs_before_run_history plus the number of run docs for this procedure. Filled in when loaded.
+
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub runs: Option<i32>,
+    pub version: Option<i32>, // None for procedures saved before versioning, which count as version 1
+
+    // Deleting a procedure only archives it, so it can be restored and the run history that refers to it stays intact.
+    // Archived procedures are hidden from the procedure list. Not part of the input object; see set_procedure_archived.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub archived_at: Option<DateTime<Utc>>,
+}
+
+#[derive(juniper::GraphQLObject, Debug, Serialize, Deserialize, Clone)]
+#[graphql(description="A document that was left out of a list or a restore, and why.")]
+pub struct SkippedDocument {
+    #[serde(rename="_id")]
+    #[graphql(name="_id", description="The _id of the document.")]
+    pub id: String,
+
+    #[graphql(description="Why the document couldn't be read.")]
+    pub reason: String,
+}
+
+#[derive(juniper::GraphQLEnum, Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum DesignDocAction {
+    Created,
+    Updated,
+    Unchanged,
+}
+
+#[derive(juniper::GraphQLObject, Debug, Clone, Serialize, Deserialize)]
+#[graphql(description="What configureDatabase did with one design doc.")]
+pub struct DesignDocSetup {
+    #[serde(rename="_id")]
+    #[graphql(name="_id", description="The _id of the design doc.")]
+    pub id: String,
+
+    pub action: DesignDocAction,
+}
+
+#[derive(juniper::GraphQLObject, Debug, Clone, Serialize, Deserialize)]
+#[graphql(description="What configureDatabase did. Running it on a database that's already set up changes nothing.")]
+pub struct DatabaseSetup {
+    #[graphql(description="True if the database didn't exist and was created.")]
+    pub database_created: bool,
+
+    #[graphql(description="The design docs holding the views. Empty for storage backends that don't use views.")]
+    pub design_docs: Vec<DesignDocSetup>,
+
+    #[graphql(description="True if there was no settings doc and a default one was saved.")]
+    pub settings_created: bool,
+}
+
+// The procedures that could be read, and the procedure docs that couldn't. See procedure_list in storage.rs.
+// The GraphQL fields are defined in graphql.rs alongside Procedure's.
+#[derive(Debug, Clone)]
+pub struct ProcedureList {
+    pub procedures: Vec<Procedure>,
+    pub skipped: Vec<SkippedDocument>,
 }
 
 #[derive(juniper::GraphQLInputObject, Debug, Serialize, Deserialize, Clone)]
@@ -135,14 +320,22 @@ pub struct ProcedureInputObject {
 
     #[graphql(description="A list of steps in the staining procedure.")]
     pub procedure_steps: Vec<ProcedureStepInputObject>,
+
+    #[graphql(description="Runs of steps to repeat within each cycle. Leaving this out removes any groups.")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub step_groups: Option<Vec<StepGroupInputObject>>,
     
     #[graphql(description="Number of times to repeat a given procedure for a single run.")]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub repeat: Option<i32>,
 
-    #[graphql(description="Number of times this procedure has ever been run.")]
-    #[serde(skip_serializing_if = "Option::is_none")]
+    #[graphql(description="Number of times this procedure has ever been run. Ignored when saving, since it's counted from the run history.")]
+    #[serde(skip_serializing)]
     pub runs: Option<i32>,
+
+    #[graphql(description="Version number of the procedure. Set by the server when the procedure is saved, so it can be left out.")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<i32>,
 }
 
 impl From<Procedure> for ProcedureInputObject {
@@ -158,21 +351,92 @@ impl From<Procedure> for ProcedureInputObject {
 	    type_ : proc.type_,
 	    name : proc.name,
 	    jar_contents : proc.jar_contents.clone(),
-	    procedure_steps : procedure_steps,
+	    procedure_steps,
+	    step_groups : Some(proc.step_groups.into_iter().map(StepGroupInputObject::from).collect()),
 	    repeat : proc.repeat,
 	    runs : proc.runs,
+	    version : proc.version,
 	}
     }
 }
 
+#[derive(juniper::GraphQLEnum, Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[graphql(description="What the instrument is physically doing during a run.")]
+pub enum RunActivity {
+    #[graphql(description="Travelling to the next jar.")]
+    Moving,
+    #[graphql(description="Holding the slides in a jar for the step's time.")]
+    Immersing,
+    #[graphql(description="Raising the slides out of the last jar and letting them drain.")]
+    Draining,
+    #[graphql(description="Waiting for the operator to resume the run.")]
+    Paused,
+    #[graphql(description="Waiting for the operator to do what an operator action step asks and confirm it.")]
+    WaitingForOperator,
+}
+
+// Bookkeeping for the procedure being run, kept in the PES. ProcedureRunStatus is built from this when it's requested
+// so that elapsed times and estimates are current.
+#[derive(Debug, Clone)]
+pub struct ActiveRun {
+    pub procedure: Procedure,
+    pub run_start_time: DateTime<Utc>,
+    pub current_procedure_step_number: i32, // zero until the first step starts
+    pub current_cycle_number: i32, // zero until the first step starts
+    pub current_procedure_step_start_time: Option<DateTime<Utc>>, // None while moving to the step's jar
+    pub current_sequence_index: usize, // position in the cycle's step sequence (see step_groups.rs)
+    pub current_group_path: Vec<GroupIteration>,
+    pub activity: RunActivity,
+    pub operator_message: Option<String>, // Some while waiting for the operator
+    pub slide_count: Option<i32>,
+    pub motion_settings: MotionSettings, // as they were when the run started
+    pub step_sequence: Vec<SequencedStep>, // expanded once when the run starts
+}
+
 #[derive(juniper::GraphQLObject, Debug, Serialize, Deserialize, Clone)]
 pub struct ProcedureRunStatus {
+    #[graphql(description="CouchDB ID of the currently running procedure.")]
+    pub current_procedure_id: String,
+
+    #[graphql(description="Name of the currently running procedure.")]
+    pub current_procedure_name: String,
+
     #[graphql(description="One-indexed number of the current procedure step in the currently running procedure. Nil if no procedure is running.")]
     pub current_procedure_step_number : i32,
     
     #[graphql(description="The cycle number, one-indexed, of how many times the procedure has been repeated in a single run.")]
     pub current_cycle_number: i32,
 
+    #[graphql(description="The step groups the current step is in, outermost first, with which repetition of each is running.")]
+    pub current_group_path: Vec<GroupIteration>,
+
+    #[graphql(description="Start time of the current procedure step. Nil if the slide holder is currently en route to a staining jar.")]
+    pub current_procedure_step_start_time: Option<DateTime<Utc>>,
+
+    #[graphql(description="Seconds the slides have been immersed in the current step, not counting time paused.")]
+    pub current_step_elapsed_seconds: i32,
+
+    #[graphql(description="Seconds of immersion left in the current step.")]
+    pub current_step_seconds_remaining: i32,
+
+    #[graphql(description="Time the run was started.")]
+    pub run_start_time: DateTime<Utc>,
+
+    #[graphql(description="Seconds since the run was started, including time paused.")]
+    pub total_elapsed_seconds: i32,
+
+    #[graphql(description="Estimated seconds until the run completes, including remaining repeats and travel between jars.")]
+    pub estimated_seconds_remaining: i32,
+
+    #[graphql(description="Estimated time the run will complete if it isn't paused again.")]
+    pub estimated_completion_time: DateTime<Utc>,
+
+    #[graphql(description="What the instrument is physically doing.")]
+    pub activity: RunActivity,
+
+    #[graphql(description="What the operator needs to do. Nil unless the run is waiting for the operator.")]
+    pub operator_message: Option<String>,
+
     pub run_state: ProcedureExecutionStateEnum,
 }
 
@@ -183,32 +447,125 @@ pub struct Pi {
     pub green_button: gpio::sysfs::SysFsGpioInput,
     pub red_light: gpio::sysfs::SysFsGpioOutput,
     pub green_light: gpio::sysfs::SysFsGpioOutput,
-    pub current_procedure: Option<Procedure>,
-    pub run_status: Option<ProcedureRunStatus>,
+    pub motion_settings: SharedMotionSettings,
 }
 
-pub type SharedPi = Mutex<Pi>;
+// The Pi is shared through an Arc so that a procedure run can be handed off to its own thread.
+pub type SharedPi = Arc<Mutex<Pi>>;
 
-#[derive(juniper::GraphQLObject, Debug)]
-#[graphql(description="A axis of motion on the device.")]
+// An axis of motion on the device. Its GraphQL fields are in graphql.rs.
+#[derive(Debug)]
 pub struct Axis {
-    pub position_inches: String,
+    pub position: Option<Length>, // None until the axis is homed
+    pub display_unit: LengthUnit,
 }
 
-#[derive(juniper::GraphQLObject, Debug, Serialize, Deserialize)]
+// The instrument's settings. Missing fields, e.g. in a settings doc saved by an older version, take their defaults,
+// which are what the instrument did before they were settings.
+#[derive(juniper::GraphQLObject, Debug, Serialize, Deserialize, Clone)]
+#[graphql(description="The instrument's settings.")]
+#[serde(default)]
 pub struct Settings {
     #[serde(rename="_id")]
     #[graphql(name="_id", description="The _id of the settings doc.")]
     pub id: String,
     
     #[graphql(name="_rev", description="The CouchDB _rev of the settings doc.")]
-    #[serde(rename="_rev")]
+    #[serde(rename="_rev", skip_serializing_if = "String::is_empty")]
     pub rev: String,
 
     #[graphql(description="The developer flag.")]
     pub developer: bool,
+
+    #[graphql(description="The instrument's name, shown in the UI to tell instruments apart.")]
+    pub instrument_name: String,
+
+    #[graphql(description="The instrument's serial number. Blank if it hasn't been entered.")]
+    pub serial_number: String,
+
+    #[graphql(description="The IANA name of the time zone times are shown in, e.g. America/Chicago.")]
+    pub timezone: String,
+
+    #[graphql(description="The unit lengths are shown in.")]
+    pub length_unit: LengthUnit,
+
+    #[graphql(description="The x-axis acceleration constant (see generate_wait_times in motion.rs). Larger is faster.")]
+    pub x_acceleration: f64,
+
+    #[graphql(description="The z-axis acceleration constant (see generate_wait_times in motion.rs). Larger is faster.")]
+    pub z_acceleration: f64,
+
+    #[graphql(description="The fastest the x-axis moves, in inches per second. 0 leaves it limited only by the acceleration.")]
+    pub x_max_speed: f64,
+
+    #[graphql(description="The fastest the z-axis moves, in inches per second. 0 leaves it limited only by the acceleration.")]
+    pub z_max_speed: f64,
+
+    #[graphql(description="Height of the rack on the z-axis when it's raised out of the jars, in inches.")]
+    pub up_position: f64,
+
+    #[graphql(description="Height of the rack on the z-axis when the slides are immersed, in inches.")]
+    pub down_position: f64,
+
+    #[graphql(description="Seconds the rack is held up over the last jar at the end of a run, so the slides drip off.")]
+    pub drain_seconds: i32,
+
+    #[graphql(description="Seconds the rack is held up over a jar before moving to the next one, so less reagent is carried over.")]
+    pub drain_between_steps_seconds: i32,
+
+    #[graphql(description="Whether the red and green lights show the run's state. When off they stay dark.")]
+    pub status_lights: bool,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+	Settings {
+	    id: "settings".to_string(),
+	    rev: "".to_string(),
+	    developer: false,
+	    instrument_name: "OpenStainer".to_string(),
+	    serial_number: "".to_string(),
+	    timezone: "UTC".to_string(),
+	    length_unit: LengthUnit::Inches,
+	    x_acceleration: MOTION_ACCELERATION,
+	    z_acceleration: MOTION_ACCELERATION,
+	    x_max_speed: 0.0,
+	    z_max_speed: 0.0,
+	    up_position: UP_POSITION.as_inches(),
+	    down_position: DOWN_POSITION.as_inches(),
+	    drain_seconds: 0,
+	    drain_between_steps_seconds: 0,
+	    status_lights: true,
+	}
+    }
+}
+
+impl Settings {
+    // These settings with the fields given in the input changed. The _rev is the input's, so a save of the result
+    // is refused if the settings were saved by someone else since the input was read.
+    pub fn updated(self, input: SettingsInputObject) -> Settings {
+	Settings {
+	    id: self.id,
+	    rev: input.rev,
+	    developer: input.developer.unwrap_or(self.developer),
+	    instrument_name: input.instrument_name.unwrap_or(self.instrument_name),
+	    serial_number: input.serial_number.unwrap_or(self.serial_number),
+	    timezone: input.timezone.unwrap_or(self.timezone),
+	    length_unit: input.length_unit.unwrap_or(self.length_unit),
+	    x_acceleration: input.x_acceleration.unwrap_or(self.x_acceleration),
+	    z_acceleration: input.z_acceleration.unwrap_or(self.z_acceleration),
+	    x_max_speed: input.x_max_speed.unwrap_or(self.x_max_speed),
+	    z_max_speed: input.z_max_speed.unwrap_or(self.z_max_speed),
+	    up_position: input.up_position.unwrap_or(self.up_position),
+	    down_position: input.down_position.unwrap_or(self.down_position),
+	    drain_seconds: input.drain_seconds.unwrap_or(self.drain_seconds),
+	    drain_between_steps_seconds: input.drain_between_steps_seconds.unwrap_or(self.drain_between_steps_seconds),
+	    status_lights: input.status_lights.unwrap_or(self.status_lights),
+	}
+    }
 }
 
+// Fields that are left out aren't changed.
 #[derive(juniper::GraphQLInputObject, Debug, Serialize, Deserialize)]
 pub struct SettingsInputObject {
     #[serde(rename="_id")]
@@ -220,7 +577,21 @@ pub struct SettingsInputObject {
     pub rev: String,
 
     #[graphql(description="The developer flag.")]
-    pub developer: bool,
+    pub developer: Option<bool>,
+
+    pub instrument_name: Option<String>,
+    pub serial_number: Option<String>,
+    pub timezone: Option<String>,
+    pub length_unit: Option<LengthUnit>,
+    pub x_acceleration: Option<f64>,
+    pub z_acceleration: Option<f64>,
+    pub x_max_speed: Option<f64>,
+    pub z_max_speed: Option<f64>,
+    pub up_position: Option<f64>,
+    pub down_position: Option<f64>,
+    pub drain_seconds: Option<i32>,
+    pub drain_between_steps_seconds: Option<i32>,
+    pub status_lights: Option<bool>,
 }
 
 impl From<Settings> for SettingsInputObject {
@@ -228,9 +599,495 @@ impl From<Settings> for SettingsInputObject {
 	SettingsInputObject {
 	    id: settings.id,
 	    rev: settings.rev,
-	    developer: settings.developer,
+	    developer: Some(settings.developer),
+	    instrument_name: Some(settings.instrument_name),
+	    serial_number: Some(settings.serial_number),
+	    timezone: Some(settings.timezone),
+	    length_unit: Some(settings.length_unit),
+	    x_acceleration: Some(settings.x_acceleration),
+	    z_acceleration: Some(settings.z_acceleration),
+	    x_max_speed: Some(settings.x_max_speed),
+	    z_max_speed: Some(settings.z_max_speed),
+	    up_position: Some(settings.up_position),
+	    down_position: Some(settings.down_position),
+	    drain_seconds: Some(settings.drain_seconds),
+	    drain_between_steps_seconds: Some(settings.drain_between_steps_seconds),
+	    status_lights: Some(settings.status_lights),
+	}
+    }
+}
+
+// The settings the motion layer uses. Moves read them when they start, so a change takes effect from the next move;
+// a run keeps the ones it started with (see ActiveRun).
+#[derive(Debug, Clone, PartialEq)]
+pub struct MotionSettings {
+    pub x_acceleration: f64,
+    pub z_acceleration: f64,
+    pub x_max_speed: Length, // per second, 0 for no limit
+    pub z_max_speed: Length,
+    pub up_position: Length,
+    pub down_position: Length,
+    pub drain_seconds: i32,
+    pub drain_between_steps_seconds: i32,
+    pub status_lights: bool,
+}
+
+impl MotionSettings {
+    pub fn acceleration(&self, axis: &AxisDirection) -> f64 {
+	match axis {
+	    AxisDirection::X => self.x_acceleration,
+	    AxisDirection::Z => self.z_acceleration,
 	}
     }
+
+    pub fn max_speed(&self, axis: &AxisDirection) -> Length {
+	match axis {
+	    AxisDirection::X => self.x_max_speed,
+	    AxisDirection::Z => self.z_max_speed,
+	}
+    }
+}
+
+impl Default for MotionSettings {
+    fn default() -> Self {
+	MotionSettings::from(&Settings::default())
+    }
+}
+
+impl From<&Settings> for MotionSettings {
+    fn from(settings: &Settings) -> Self {
+	MotionSettings {
+	    x_acceleration: settings.x_acceleration,
+	    z_acceleration: settings.z_acceleration,
+	    x_max_speed: Length::inches(settings.x_max_speed),
+	    z_max_speed: Length::inches(settings.z_max_speed),
+	    up_position: Length::inches(settings.up_position),
+	    down_position: Length::inches(settings.down_position),
+	    drain_seconds: settings.drain_seconds,
+	    drain_between_steps_seconds: settings.drain_between_steps_seconds,
+	    status_lights: settings.status_lights,
+	}
+    }
+}
+
+// Shared by the Pi and the PES, since the Pi is locked for the duration of a move.
+pub type SharedMotionSettings = Arc<RwLock<MotionSettings>>;
+
+#[derive(juniper::GraphQLObject, Debug, Serialize, Deserialize, Clone)]
+#[graphql(description="A staining jar as currently loaded on the instrument.")]
+pub struct RackJar {
+    #[graphql(description="The one-indexed jar number.")]
+    pub jar_number: i32,
+
+    #[graphql(description="The substance currently in the jar. Blank if the jar is empty or its contents are unknown.")]
+    pub substance: String,
+
+    #[graphql(description="When the jar was last filled.")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub filled_at: Option<DateTime<Utc>>,
+
+    #[graphql(description="Lot number of the reagent in the jar.")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lot_number: Option<String>,
+
+    #[graphql(description="Volume of reagent in the jar, in millilitres.")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub volume_ml: Option<f64>,
+
+    #[graphql(description="Number of runs that have used this jar since it was filled.")]
+    #[serde(default)]
+    pub runs_since_filled: i32,
+
+    #[graphql(description="Total seconds slides have been immersed in this jar since it was filled.")]
+    #[serde(default)]
+    pub immersion_seconds_since_filled: i32,
+
+    #[graphql(description="Number of slides that have been through this jar since it was filled. Only counts runs where the slide count was given.")]
+    #[serde(default)]
+    pub slides_since_filled: i32,
+}
+
+impl RackJar {
+    pub fn reset_usage(&mut self) {
+	self.runs_since_filled = 0;
+	self.immersion_seconds_since_filled = 0;
+	self.slides_since_filled = 0;
+    }
+}
+
+// The usage counters are left out of the input object since they're only changed by runs and by refilling a jar.
+#[derive(juniper::GraphQLInputObject, Debug, Serialize, Deserialize, Clone)]
+#[graphql(description="A staining jar as currently loaded on the instrument.")]
+pub struct RackJarInputObject {
+    #[graphql(description="The one-indexed jar number.")]
+    pub jar_number: i32,
+
+    #[graphql(description="The substance currently in the jar. Blank if the jar is empty or its contents are unknown.")]
+    pub substance: String,
+
+    #[graphql(description="When the jar was last filled.")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub filled_at: Option<DateTime<Utc>>,
+
+    #[graphql(description="Lot number of the reagent in the jar.")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lot_number: Option<String>,
+
+    #[graphql(description="Volume of reagent in the jar, in millilitres.")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub volume_ml: Option<f64>,
+}
+
+impl From<RackJar> for RackJarInputObject {
+    fn from(jar: RackJar) -> Self {
+	RackJarInputObject {
+	    jar_number: jar.jar_number,
+	    substance: jar.substance,
+	    filled_at: jar.filled_at,
+	    lot_number: jar.lot_number,
+	    volume_ml: jar.volume_ml,
+	}
+    }
+}
+
+// The rack document describes what is physically loaded in the jars on the instrument, independent of any procedure.
+// There is a single rack document with the _id "rack".
+#[derive(juniper::GraphQLObject, Debug, Serialize, Deserialize, Clone)]
+#[graphql(description="The jars loaded on the instrument.")]
+pub struct Rack {
+    #[serde(rename="_id")]
+    #[graphql(name="_id", description="The _id of the rack doc. Will always be rack.")]
+    pub id: String,
+
+    #[graphql(name="_rev", description="The CouchDB _rev of the rack doc. Nil if the rack has never been saved.")]
+    #[serde(rename="_rev", skip_serializing_if = "Option::is_none")]
+    pub rev: Option<String>,
+
+    #[graphql(name="type", description="The CouchDB type of the rack doc. Will always be rack.")]
+    #[serde(rename="type")]
+    pub type_: String,
+
+    #[graphql(description="The jars in the rack, in jar number order.")]
+    pub jars: Vec<RackJar>,
+}
+
+#[derive(juniper::GraphQLInputObject, Debug, Serialize, Deserialize, Clone)]
+#[graphql(description="The jars loaded on the instrument.")]
+pub struct RackInputObject {
+    #[serde(rename="_id")]
+    #[graphql(name="_id", description="The _id of the rack doc. Will always be rack.")]
+    pub id: String,
+
+    #[graphql(name="_rev", description="The CouchDB _rev of the rack doc. Nil if the rack has never been saved.")]
+    #[serde(rename="_rev", skip_serializing_if = "Option::is_none")]
+    pub rev: Option<String>,
+
+    #[graphql(name="type", description="The CouchDB type of the rack doc. Will always be rack.")]
+    #[serde(rename="type")]
+    pub type_: String,
+
+    #[graphql(description="The jars in the rack, in jar number order.")]
+    pub jars: Vec<RackJarInputObject>,
+}
+
+impl From<Rack> for RackInputObject {
+    fn from(rack: Rack) -> Self {
+	RackInputObject {
+	    id: rack.id,
+	    rev: rack.rev,
+	    type_: rack.type_,
+	    jars: rack.jars.into_iter().map(RackJarInputObject::from).collect(),
+	}
+    }
+}
+
+impl Rack {
+    // The rack used before one has been saved: every jar's contents are unknown.
+    pub fn empty() -> Rack {
+	Rack {
+	    id: "rack".to_string(),
+	    rev: None,
+	    type_: "rack".to_string(),
+	    jars: (1..=NUMBER_OF_JARS).map(|jar_number| RackJar {
+		jar_number,
+		substance: "".to_string(),
+		filled_at: None,
+		lot_number: None,
+		volume_ml: None,
+		runs_since_filled: 0,
+		immersion_seconds_since_filled: 0,
+		slides_since_filled: 0,
+	    }).collect(),
+	}
+    }
+
+    pub fn jar(&self, jar_number: i32) -> Option<&RackJar> {
+	self.jars.iter().find(|jar| jar.jar_number == jar_number)
+    }
+
+    pub fn jar_mut(&mut self, jar_number: i32) -> Option<&mut RackJar> {
+	self.jars.iter_mut().find(|jar| jar.jar_number == jar_number)
+    }
+}
+
+#[derive(juniper::GraphQLObject, Debug, Serialize, Deserialize, Clone)]
+#[graphql(description="Limits after which a reagent should be replaced. A limit that is nil is not checked.")]
+pub struct ReagentThreshold {
+    #[graphql(description="The substance the limits apply to. Matched against jar contents without regard to case.")]
+    pub substance: String,
+
+    #[graphql(description="Maximum number of runs before the reagent should be replaced.")]
+    pub max_runs: Option<i32>,
+
+    #[graphql(description="Maximum total minutes of immersion before the reagent should be replaced.")]
+    pub max_immersion_minutes: Option<i32>,
+
+    #[graphql(description="Maximum number of slides before the reagent should be replaced.")]
+    pub max_slides: Option<i32>,
+
+    #[graphql(description="Maximum number of days after filling before the reagent should be replaced.")]
+    pub max_days: Option<i32>,
+}
+
+#[derive(juniper::GraphQLInputObject, Debug, Serialize, Deserialize, Clone)]
+#[graphql(description="Limits after which a reagent should be replaced. A limit that is nil is not checked.")]
+pub struct ReagentThresholdInputObject {
+    #[graphql(description="The substance the limits apply to. Matched against jar contents without regard to case.")]
+    pub substance: String,
+
+    #[graphql(description="Maximum number of runs before the reagent should be replaced.")]
+    pub max_runs: Option<i32>,
+
+    #[graphql(description="Maximum total minutes of immersion before the reagent should be replaced.")]
+    pub max_immersion_minutes: Option<i32>,
+
+    #[graphql(description="Maximum number of slides before the reagent should be replaced.")]
+    pub max_slides: Option<i32>,
+
+    #[graphql(description="Maximum number of days after filling before the reagent should be replaced.")]
+    pub max_days: Option<i32>,
+}
+
+impl From<ReagentThresholdInputObject> for ReagentThreshold {
+    fn from(threshold: ReagentThresholdInputObject) -> Self {
+	ReagentThreshold {
+	    substance: threshold.substance,
+	    max_runs: threshold.max_runs,
+	    max_immersion_minutes: threshold.max_immersion_minutes,
+	    max_slides: threshold.max_slides,
+	    max_days: threshold.max_days,
+	}
+    }
+}
+
+// There is a single reagent thresholds document with the _id "reagent_thresholds".
+#[derive(juniper::GraphQLObject, Debug, Serialize, Deserialize, Clone)]
+#[graphql(description="When each reagent should be replaced.")]
+pub struct ReagentThresholds {
+    #[serde(rename="_id")]
+    #[graphql(name="_id", description="The _id of the reagent thresholds doc. Will always be reagent_thresholds.")]
+    pub id: String,
+
+    #[graphql(name="_rev", description="The CouchDB _rev of the reagent thresholds doc. Nil if it has never been saved.")]
+    #[serde(rename="_rev", skip_serializing_if = "Option::is_none")]
+    pub rev: Option<String>,
+
+    #[graphql(name="type", description="The CouchDB type of the doc. Will always be reagent_thresholds.")]
+    #[serde(rename="type")]
+    pub type_: String,
+
+    pub thresholds: Vec<ReagentThreshold>,
+}
+
+#[derive(juniper::GraphQLInputObject, Debug, Serialize, Deserialize, Clone)]
+#[graphql(description="When each reagent should be replaced.")]
+pub struct ReagentThresholdsInputObject {
+    #[serde(rename="_id")]
+    #[graphql(name="_id", description="The _id of the reagent thresholds doc. Will always be reagent_thresholds.")]
+    pub id: String,
+
+    #[graphql(name="_rev", description="The CouchDB _rev of the reagent thresholds doc. Nil if it has never been saved.")]
+    #[serde(rename="_rev", skip_serializing_if = "Option::is_none")]
+    pub rev: Option<String>,
+
+    #[graphql(name="type", description="The CouchDB type of the doc. Will always be reagent_thresholds.")]
+    #[serde(rename="type")]
+    pub type_: String,
+
+    pub thresholds: Vec<ReagentThresholdInputObject>,
+}
+
+impl ReagentThresholds {
+    pub fn empty() -> ReagentThresholds {
+	ReagentThresholds {
+	    id: "reagent_thresholds".to_string(),
+	    rev: None,
+	    type_: "reagent_thresholds".to_string(),
+	    thresholds: Vec::new(),
+	}
+    }
+
+    pub fn for_substance(&self, substance: &str) -> Option<&ReagentThreshold> {
+	self.thresholds.iter().find(|t| t.substance.trim().eq_ignore_ascii_case(substance.trim()))
+    }
+}
+
+#[derive(juniper::GraphQLObject, Debug, Serialize, Deserialize, Clone)]
+#[graphql(description="A jar whose reagent is due for replacement.")]
+pub struct ReagentAlert {
+    pub jar_number: i32,
+    pub substance: String,
+    #[graphql(description="Which limits have been reached, e.g. \"12 of 10 runs\".")]
+    pub reasons: Vec<String>,
+}
+
+#[derive(juniper::GraphQLObject, Debug, Serialize, Deserialize, Clone)]
+#[graphql(description="How long a run spent in one jar.")]
+pub struct JarUsage {
+    pub jar_number: i32,
+    pub substance: String,
+    pub immersion_seconds: i32,
+}
+
+#[derive(juniper::GraphQLObject, Debug, Serialize, Deserialize, Clone)]
+#[graphql(description="How long a run waited on an operator action step.")]
+pub struct OperatorActionRecord {
+    #[graphql(description="One-indexed number of the operator action step.")]
+    pub step_number: i32,
+    pub message: String,
+    #[graphql(description="Seconds from the rack arriving over the jar to the operator confirming.")]
+    pub wait_seconds: i32,
+    #[graphql(description="False if the run was stopped before the operator confirmed.")]
+    pub confirmed: bool,
+}
+
+// A record of a single run of a procedure, saved when the run ends.
+#[derive(juniper::GraphQLObject, Debug, Serialize, Deserialize, Clone)]
+#[graphql(description="A record of a run of a procedure.")]
+pub struct RunRecord {
+    #[serde(rename="_id", skip_serializing_if = "Option::is_none")]
+    #[graphql(name="_id", description="The _id of the run doc. Nil until it has been saved.")]
+    pub id: Option<String>,
+
+    #[serde(rename="_rev", skip_serializing_if = "Option::is_none")]
+    #[graphql(name="_rev", description="The CouchDB _rev of the run doc. Nil until it has been saved.")]
+    pub rev: Option<String>,
+
+    #[graphql(name="type", description="The CouchDB type of the doc. Will always be run.")]
+    #[serde(rename="type")]
+    pub type_: String,
+
+    pub procedure_id: String,
+    pub procedure_name: String,
+
+    #[graphql(description="The version of the procedure that was run. See procedureVersion.")]
+    #[serde(default)]
+    pub procedure_version: Option<i32>,
+
+    pub start_time: DateTime<Utc>,
+    pub end_time: DateTime<Utc>,
+
+    #[graphql(description="Completed if the run finished, Stopped if the operator stopped it.")]
+    pub final_state: ProcedureExecutionStateEnum,
+
+    #[graphql(description="Number of slides in the rack, if given when the run was started.")]
+    pub slide_count: Option<i32>,
+
+    #[graphql(description="Time spent in each jar, in the order the jars were first used.")]
+    pub jar_usage: Vec<JarUsage>,
+
+    #[graphql(description="Each operator action step reached during the run, in order.")]
+    #[serde(default)]
+    pub operator_actions: Vec<OperatorActionRecord>,
+}
+
+// An immutable snapshot of a procedure as it was saved. One is stored for every version of every procedure,
+// with an _id of "<procedure _id>:v<version>", and is never changed after it's written.
+#[derive(juniper::GraphQLObject, Debug, Serialize, Deserialize, Clone)]
+#[graphql(description="A saved version of a procedure. Versions never change once saved.")]
+pub struct ProcedureVersion {
+    #[serde(rename="_id")]
+    #[graphql(name="_id", description="The _id of the version doc.")]
+    pub id: String,
+
+    #[serde(rename="_rev", skip_serializing_if = "Option::is_none")]
+    #[graphql(name="_rev", description="The CouchDB _rev of the version doc.")]
+    pub rev: Option<String>,
+
+    #[graphql(name="type", description="The CouchDB type of the doc. Will always be procedure_version.")]
+    #[serde(rename="type")]
+    pub type_: String,
+
+    #[graphql(description="The _id of the procedure this is a version of.")]
+    pub procedure_id: String,
+    pub version: i32,
+    pub saved_at: DateTime<Utc>,
+    pub name: String,
+    pub jar_contents: Vec<String>,
+    pub procedure_steps: Vec<ProcedureStep>,
+    #[serde(default)]
+    pub step_groups: Vec<StepGroup>,
+    pub repeat: Option<i32>,
+}
+
+#[derive(juniper::GraphQLObject, Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[graphql(description="A single difference between two versions of a procedure.")]
+pub struct ProcedureChange {
+    #[graphql(description="The field that changed, e.g. name, repeat, jar_contents, time_in_seconds or procedure_steps.")]
+    pub field: String,
+
+    #[graphql(description="One-indexed step number, if the change is to a step.")]
+    pub step_number: Option<i32>,
+
+    #[graphql(description="One-indexed jar number, if the change is to the jar contents.")]
+    pub jar_number: Option<i32>,
+
+    #[graphql(description="The value in the older version. Nil if the step or jar was added.")]
+    pub old_value: Option<String>,
+
+    #[graphql(description="The value in the newer version. Nil if the step or jar was removed.")]
+    pub new_value: Option<String>,
+}
+
+#[derive(juniper::GraphQLObject, Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[graphql(description="A field that both the saved procedure and an edit changed, in different ways.")]
+pub struct ProcedureMergeConflict {
+    pub field: String,
+    pub step_number: Option<i32>,
+    pub jar_number: Option<i32>,
+
+    #[graphql(description="The value in the version the edit started from.")]
+    pub base_value: Option<String>,
+
+    #[graphql(description="The value saved now.")]
+    pub their_value: Option<String>,
+
+    #[graphql(description="The value in the edit.")]
+    pub my_value: Option<String>,
+}
+
+#[derive(juniper::GraphQLObject, Debug, Serialize, Deserialize, Clone)]
+#[graphql(description="How an edit and the saved procedure have each changed since the version the edit started from.")]
+pub struct ProcedureThreeWayDiff {
+    pub procedure_id: String,
+
+    #[graphql(description="The version the edit started from.")]
+    pub base_version: i32,
+
+    #[graphql(description="The version saved now.")]
+    pub current_version: i32,
+
+    #[graphql(description="The _rev saved now. Save the edit with this _rev to overwrite the saved procedure.")]
+    pub current_rev: String,
+
+    #[graphql(description="Changes from the base version to what's saved now.")]
+    pub their_changes: Vec<ProcedureChange>,
+
+    #[graphql(description="Changes from the base version to the edit.")]
+    pub my_changes: Vec<ProcedureChange>,
+
+    #[graphql(description="Fields both sides changed in different ways.")]
+    pub conflicts: Vec<ProcedureMergeConflict>,
 }
 
 #[derive(Debug, PartialEq, Eq)]
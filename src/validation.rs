@@ -0,0 +1,542 @@
+pub use crate::structs_and_consts::*;
+pub use crate::couchdb::*;
+pub use crate::step_groups::*;
+
+use juniper::{FieldError, FieldResult, Object, Value};
+use serde::*;
+
+// Checks a procedure for problems before it's saved or run.
+// Errors make a procedure impossible (or unsafe) to run and block saving; warnings are shown to the user but don't block anything.
+
+#[derive(juniper::GraphQLEnum, Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ValidationSeverity {
+    Error,
+    Warning,
+}
+
+#[derive(juniper::GraphQLObject, Debug, Clone, Serialize, Deserialize)]
+#[graphql(description="A problem found when validating a procedure.")]
+pub struct ValidationIssue {
+    #[graphql(description="Errors block saving and running the procedure. Warnings don't.")]
+    pub severity: ValidationSeverity,
+
+    #[graphql(description="One-indexed number of the step the issue applies to. Nil if it applies to the whole procedure.")]
+    pub step_number: Option<i32>,
+
+    #[graphql(description="The field the issue applies to, e.g. time_in_seconds.")]
+    pub field: String,
+
+    #[graphql(description="Machine-readable identifier of the kind of issue, e.g. time_not_positive.")]
+    pub code: String,
+
+    #[graphql(description="Human-readable description of the issue.")]
+    pub message: String,
+}
+
+#[derive(juniper::GraphQLObject, Debug, Clone, Serialize, Deserialize)]
+#[graphql(description="The result of validating a procedure.")]
+pub struct ProcedureValidation {
+    #[graphql(description="True if there are no errors. There may still be warnings.")]
+    pub valid: bool,
+
+    pub issues: Vec<ValidationIssue>,
+}
+
+impl ProcedureValidation {
+    pub fn errors(&self) -> Vec<&ValidationIssue> {
+	self.issues.iter().filter(|issue| issue.severity == ValidationSeverity::Error).collect()
+    }
+}
+
+fn issue(severity: ValidationSeverity, step_number: Option<i32>, field: &str, code: &str, message: String) -> ValidationIssue {
+    ValidationIssue {
+	severity,
+	step_number,
+	field: field.to_string(),
+	code: code.to_string(),
+	message,
+    }
+}
+
+pub fn validate_procedure(proc: &ProcedureInputObject) -> ProcedureValidation {
+    let mut issues : Vec<ValidationIssue> = Vec::new();
+
+    if proc.name.trim().is_empty() {
+	issues.push(issue(ValidationSeverity::Error, None, "name", "name_missing",
+			  "The procedure needs a name.".to_string()));
+    }
+    if proc.type_ != "procedure" {
+	issues.push(issue(ValidationSeverity::Error, None, "type", "wrong_type",
+			  format!("The type of a procedure must be \"procedure\", not \"{}\".", proc.type_)));
+    }
+    if let Some(repeat) = proc.repeat {
+	if repeat < 1 {
+	    issues.push(issue(ValidationSeverity::Error, None, "repeat", "repeat_not_positive",
+			      format!("The procedure must be repeated at least once, not {} times.", repeat)));
+	}
+	if repeat > MAX_REPEAT {
+	    issues.push(issue(ValidationSeverity::Error, None, "repeat", "repeat_too_large",
+			      format!("The procedure can be repeated at most {} times, not {} times.", MAX_REPEAT, repeat)));
+	}
+    }
+    if proc.jar_contents.len() > NUMBER_OF_JARS as usize {
+	issues.push(issue(ValidationSeverity::Error, None, "jar_contents", "too_many_jars",
+			  format!("The rack only has {} jars, but contents are listed for {}.", NUMBER_OF_JARS, proc.jar_contents.len())));
+    }
+    if proc.procedure_steps.is_empty() {
+	issues.push(issue(ValidationSeverity::Error, None, "procedure_steps", "no_steps",
+			  "The procedure has no steps.".to_string()));
+    }
+
+    for (index, step) in proc.procedure_steps.iter().enumerate() {
+	let step_number = Some(index as i32 + 1);
+	let step_type = step.step_type.unwrap_or_default();
+	if step_type == StepType::OperatorAction {
+	    if step.operator_message.as_ref().map(|message| message.trim().is_empty()).unwrap_or(true) {
+		issues.push(issue(ValidationSeverity::Error, step_number, "operator_message", "operator_message_missing",
+				  format!("Step {} is an operator action, so it needs a message telling the operator what to do.", index + 1)));
+	    }
+	    if step.jar_number < 1 || step.jar_number > NUMBER_OF_JARS {
+		issues.push(issue(ValidationSeverity::Error, step_number, "jar_number", "jar_out_of_range",
+				  format!("Step {} holds the rack over jar {}, but the jars are numbered 1 to {}.", index + 1, step.jar_number, NUMBER_OF_JARS)));
+	    }
+	    continue; // the slides don't go into the jar, so its time and contents don't matter
+	}
+	if step.time_in_seconds <= 0 {
+	    issues.push(issue(ValidationSeverity::Error, step_number, "time_in_seconds", "time_not_positive",
+			      format!("Step {} must take more than 0 seconds.", index + 1)));
+	}
+	if step.time_in_seconds > MAX_STEP_SECONDS {
+	    issues.push(issue(ValidationSeverity::Error, step_number, "time_in_seconds", "time_too_long",
+			      format!("Step {} takes {} seconds, but a step can take at most {} seconds ({} hours).",
+				      index + 1, step.time_in_seconds, MAX_STEP_SECONDS, MAX_STEP_SECONDS / 3600)));
+	}
+	if step.jar_number < 1 || step.jar_number > NUMBER_OF_JARS {
+	    issues.push(issue(ValidationSeverity::Error, step_number, "jar_number", "jar_out_of_range",
+			      format!("Step {} uses jar {}, but the jars are numbered 1 to {}.", index + 1, step.jar_number, NUMBER_OF_JARS)));
+	    continue;
+	}
+	match proc.jar_contents.get((step.jar_number - 1) as usize).map(|contents| contents.trim()) {
+	    None | Some("") => {
+		issues.push(issue(ValidationSeverity::Warning, step_number, "jar_number", "jar_empty",
+				  format!("Step {} uses jar {}, but no contents are listed for that jar.", index + 1, step.jar_number)));
+	    }
+	    Some(contents) => {
+		if let Some(substance) = &step.substance {
+		    if contents != substance.trim() {
+			issues.push(issue(ValidationSeverity::Warning, step_number, "substance", "substance_mismatch",
+					  format!("Step {} says jar {} contains {}, but the jar contents say {}. The jar contents will be used.",
+						  index + 1, step.jar_number, substance, contents)));
+		    }
+		}
+	    }
+	}
+    }
+
+    let step_groups : Vec<StepGroup> = proc.step_groups.clone().unwrap_or_default().into_iter().map(StepGroup::from).collect();
+    validate_step_groups(&step_groups, proc.procedure_steps.len() as i32, &mut issues);
+
+    ProcedureValidation {
+	valid: !issues.iter().any(|issue| issue.severity == ValidationSeverity::Error),
+	issues,
+    }
+}
+
+fn validate_step_groups(groups: &[StepGroup], number_of_steps: i32, issues: &mut Vec<ValidationIssue>) {
+    // checked first, since the checks below compare every pair of groups
+    if groups.len() > MAX_STEP_GROUPS {
+	issues.push(issue(ValidationSeverity::Error, None, "step_groups", "too_many_step_groups",
+			  format!("A procedure can have at most {} step groups, not {}.", MAX_STEP_GROUPS, groups.len())));
+	return;
+    }
+    let mut well_formed = true; // every group is in range and no two groups overlap
+    for (index, group) in groups.iter().enumerate() {
+	let step_number = Some(group.first_step_number);
+	if group.repeat < 1 {
+	    issues.push(issue(ValidationSeverity::Error, step_number, "step_groups", "group_repeat_not_positive",
+			      format!("The group of steps {} to {} must be repeated at least once, not {} times.",
+				      group.first_step_number, group.last_step_number, group.repeat)));
+	}
+	if group.repeat > MAX_GROUP_REPEAT {
+	    issues.push(issue(ValidationSeverity::Error, step_number, "step_groups", "group_repeat_too_large",
+			      format!("The group of steps {} to {} can be repeated at most {} times, not {} times.",
+				      group.first_step_number, group.last_step_number, MAX_GROUP_REPEAT, group.repeat)));
+	}
+	if group.first_step_number < 1 || group.last_step_number > number_of_steps || group.first_step_number > group.last_step_number {
+	    issues.push(issue(ValidationSeverity::Error, step_number, "step_groups", "group_out_of_range",
+			      format!("A group can't cover steps {} to {}; the procedure has steps 1 to {}.",
+				      group.first_step_number, group.last_step_number, number_of_steps)));
+	    well_formed = false;
+	    continue;
+	}
+	// each overlapping pair is only reported once
+	for other in groups.iter().skip(index + 1) {
+	    if groups_partially_overlap(group, other) {
+		issues.push(issue(ValidationSeverity::Error, step_number, "step_groups", "groups_overlap",
+				  format!("The groups of steps {} to {} and {} to {} overlap. One group has to be entirely inside the other.",
+					  group.first_step_number, group.last_step_number, other.first_step_number, other.last_step_number)));
+		well_formed = false;
+	    }
+	}
+    }
+    // nesting depth and sequence length only mean something once the groups nest properly
+    if !well_formed {
+	return;
+    }
+    for (index, group) in groups.iter().enumerate() {
+	let depth = group_depth(groups, index);
+	if depth > MAX_STEP_GROUP_DEPTH {
+	    issues.push(issue(ValidationSeverity::Error, Some(group.first_step_number), "step_groups", "groups_nested_too_deeply",
+			      format!("The group of steps {} to {} is nested {} deep, but groups can only be nested {} deep.",
+				      group.first_step_number, group.last_step_number, depth, MAX_STEP_GROUP_DEPTH)));
+	    well_formed = false;
+	}
+    }
+    if !well_formed {
+	return;
+    }
+    // counted rather than expanded, since nested repeats multiply
+    let sequence_length = step_sequence_length(groups, number_of_steps.max(0) as usize);
+    if sequence_length > MAX_SEQUENCE_LENGTH {
+	issues.push(issue(ValidationSeverity::Error, None, "step_groups", "sequence_too_long",
+			  format!("With its groups repeated, each cycle would run {} steps, but a cycle can run at most {} steps.",
+				  sequence_length, MAX_SEQUENCE_LENGTH)));
+    }
+}
+
+// Compares the jars a procedure uses with what is loaded on the instrument.
+// A jar holding a different substance is an error; a jar whose contents are unknown is a warning.
+pub fn check_rack(proc: &Procedure, rack: &Rack) -> ProcedureValidation {
+    let mut issues : Vec<ValidationIssue> = Vec::new();
+    let mut checked_jars : Vec<i32> = Vec::new();
+
+    for (index, step) in proc.procedure_steps.iter().enumerate() {
+	if step.step_type != StepType::Immerse || checked_jars.contains(&step.jar_number) {
+	    continue;
+	}
+	checked_jars.push(step.jar_number);
+
+	let step_number = Some(index as i32 + 1);
+	let expected = step.substance.trim();
+	let loaded = rack.jar(step.jar_number).map(|jar| jar.substance.trim()).unwrap_or("");
+	if loaded.is_empty() {
+	    issues.push(issue(ValidationSeverity::Warning, step_number, "jar_number", "rack_jar_unknown",
+			      format!("The procedure expects {} in jar {}, but the rack doesn't say what jar {} contains.",
+				      expected, step.jar_number, step.jar_number)));
+	}
+	else if !loaded.eq_ignore_ascii_case(expected) {
+	    issues.push(issue(ValidationSeverity::Error, step_number, "jar_number", "rack_mismatch",
+			      format!("The procedure expects {} in jar {}, but the rack has {} loaded in it.",
+				      expected, step.jar_number, loaded)));
+	}
+    }
+
+    ProcedureValidation {
+	valid: !issues.iter().any(|issue| issue.severity == ValidationSeverity::Error),
+	issues,
+    }
+}
+
+// Warns about jars the procedure uses whose reagent is due for replacement. These never block a run.
+pub fn check_reagent_alerts(proc: &Procedure, alerts: &[ReagentAlert]) -> Vec<ValidationIssue> {
+    alerts.iter()
+	.filter(|alert| proc.procedure_steps.iter().any(|step| step.step_type == StepType::Immerse && step.jar_number == alert.jar_number))
+	.map(|alert| issue(ValidationSeverity::Warning, None, "jar_number", "reagent_due",
+			   format!("The {} in jar {} is due for replacement ({}).", alert.substance, alert.jar_number, alert.reasons.join(", "))))
+	.collect()
+}
+
+// Checks that a rack lists each jar at most once and only lists jars that exist.
+pub fn validate_rack(rack: &RackInputObject) -> FieldResult<()> {
+    let mut seen : Vec<i32> = Vec::new();
+    for jar in rack.jars.iter() {
+	if jar.jar_number < 1 || jar.jar_number > NUMBER_OF_JARS {
+	    return juniper_err(format!("Jar {} doesn't exist; the jars are numbered 1 to {}.", jar.jar_number, NUMBER_OF_JARS));
+	}
+	if seen.contains(&jar.jar_number) {
+	    return juniper_err(format!("Jar {} is listed more than once.", jar.jar_number));
+	}
+	seen.push(jar.jar_number);
+    }
+    Ok(())
+}
+
+// Checks settings are within what the instrument can safely do.
+pub fn validate_settings(settings: &Settings) -> FieldResult<()> {
+    if settings.instrument_name.trim().is_empty() || settings.instrument_name.chars().count() > 64 {
+	return juniper_err("The instrument name should be 1 to 64 characters.".to_string());
+    }
+    if settings.serial_number.chars().count() > 64 {
+	return juniper_err("The serial number should be at most 64 characters.".to_string());
+    }
+    // e.g. UTC or America/Argentina/Buenos_Aires; the UI checks the name is one it knows
+    if settings.timezone.is_empty() || settings.timezone.len() > 64
+	|| !settings.timezone.chars().all(|c| c.is_ascii_alphanumeric() || "/_+-".contains(c)) {
+	return juniper_err(format!("{:?} isn't a time zone name, e.g. America/Chicago.", settings.timezone));
+    }
+    // much faster than the default and the steppers stall or skip steps
+    let max_acceleration = MOTION_ACCELERATION * 4.0;
+    for (axis, acceleration) in [("x", settings.x_acceleration), ("z", settings.z_acceleration)].iter() {
+	if !(*acceleration > 0.0 && *acceleration <= max_acceleration) {
+	    return juniper_err(format!("The {}-axis acceleration should be more than 0 and at most {}.", axis, max_acceleration));
+	}
+    }
+    for (axis, speed) in [("x", settings.x_max_speed), ("z", settings.z_max_speed)].iter() {
+	if !(*speed >= 0.0 && speed.is_finite()) {
+	    return juniper_err(format!("The {}-axis speed limit can't be negative; use 0 for no limit.", axis));
+	}
+    }
+    if !(settings.down_position >= 0.0 && settings.down_position < settings.up_position && settings.up_position <= Z_POSITION_LIMIT.as_inches()) {
+	return juniper_err(format!("The down position should be below the up position, and both from 0 to {} inches.", Z_POSITION_LIMIT.as_inches()));
+    }
+    if settings.drain_seconds < 0 || settings.drain_seconds > 600 || settings.drain_between_steps_seconds < 0 || settings.drain_between_steps_seconds > 600 {
+	return juniper_err("Drain times should be from 0 to 600 seconds.".to_string());
+    }
+    Ok(())
+}
+
+// Builds an error carrying the validation errors in its extensions so the UI can point at the offending steps.
+pub fn validation_err<T>(validation: &ProcedureValidation) -> FieldResult<T> {
+    let errors = validation.errors();
+    let messages : Vec<String> = errors.iter().map(|issue| issue.message.clone()).collect();
+    let message = format!("The procedure is invalid: {}", messages.join(" "));
+
+    let issues : Vec<Value> = errors.iter().map(|issue| {
+	let mut obj = Object::with_capacity(4);
+	obj.add_field("step_number", match issue.step_number {
+	    Some(n) => Value::scalar(n),
+	    None => Value::null(),
+	});
+	obj.add_field("field", Value::scalar(issue.field.clone()));
+	obj.add_field("code", Value::scalar(issue.code.clone()));
+	obj.add_field("message", Value::scalar(issue.message.clone()));
+	Value::object(obj)
+    }).collect();
+
+    let mut extensions = Object::with_capacity(2);
+    extensions.add_field("internal_error", Value::scalar(message.clone()));
+    extensions.add_field("validation_errors", Value::list(issues));
+    Err(FieldError::new(message, Value::object(extensions)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn procedure(value: serde_json::Value) -> ProcedureInputObject {
+	serde_json::from_value(value).expect("Test procedure should parse")
+    }
+
+    fn h_and_e() -> serde_json::Value {
+	json!({
+	    "type": "procedure",
+	    "name": "H&E",
+	    "jar_contents": ["Hematoxylin", "Water", "Eosin"],
+	    "procedure_steps": [
+		{"substance": "Hematoxylin", "time_in_seconds": 150, "jar_number": 1},
+		{"substance": "Water", "time_in_seconds": 20, "jar_number": 2},
+		{"substance": "Eosin", "time_in_seconds": 90, "jar_number": 3},
+	    ],
+	})
+    }
+
+    fn codes(validation: &ProcedureValidation) -> Vec<&str> {
+	validation.issues.iter().map(|issue| issue.code.as_str()).collect()
+    }
+
+    #[test]
+    fn valid_procedures_have_no_issues() {
+	let validation = validate_procedure(&procedure(h_and_e()));
+	assert!(validation.valid);
+	assert!(validation.issues.is_empty(), "{:?}", validation.issues);
+    }
+
+    #[test]
+    fn procedure_fields_are_checked() {
+	let mut value = h_and_e();
+	value["name"] = json!(" ");
+	value["type"] = json!("settings");
+	value["repeat"] = json!(0);
+	let validation = validate_procedure(&procedure(value));
+	assert!(!validation.valid);
+	assert_eq!(codes(&validation), vec!["name_missing", "wrong_type", "repeat_not_positive"]);
+	assert!(validation.issues.iter().all(|issue| issue.step_number.is_none()));
+    }
+
+    #[test]
+    fn step_problems_point_at_the_step() {
+	let mut value = h_and_e();
+	value["procedure_steps"][0]["time_in_seconds"] = json!(0);
+	value["procedure_steps"][1]["jar_number"] = json!(NUMBER_OF_JARS + 1);
+	value["procedure_steps"][2]["substance"] = json!("Eosin Y");
+	let validation = validate_procedure(&procedure(value));
+	assert_eq!(codes(&validation), vec!["time_not_positive", "jar_out_of_range", "substance_mismatch"]);
+	let steps : Vec<Option<i32>> = validation.issues.iter().map(|issue| issue.step_number).collect();
+	assert_eq!(steps, vec![Some(1), Some(2), Some(3)]);
+    }
+
+    #[test]
+    fn warnings_do_not_make_a_procedure_invalid() {
+	let mut value = h_and_e();
+	value["jar_contents"] = json!(["Hematoxylin", "", "Eosin"]);
+	let validation = validate_procedure(&procedure(value));
+	assert_eq!(codes(&validation), vec!["jar_empty"]);
+	assert!(validation.valid);
+	assert!(validation.errors().is_empty());
+    }
+
+    #[test]
+    fn step_times_and_repeats_have_upper_limits() {
+	let mut value = h_and_e();
+	value["procedure_steps"][0]["time_in_seconds"] = json!(MAX_STEP_SECONDS);
+	value["repeat"] = json!(MAX_REPEAT);
+	assert!(validate_procedure(&procedure(value.clone())).valid);
+
+	value["procedure_steps"][2]["time_in_seconds"] = json!(MAX_STEP_SECONDS + 1);
+	value["repeat"] = json!(MAX_REPEAT + 1);
+	let validation = validate_procedure(&procedure(value));
+	assert_eq!(codes(&validation), vec!["repeat_too_large", "time_too_long"]);
+	assert_eq!(validation.issues[1].step_number, Some(3));
+    }
+
+    fn loaded_rack(substances: &[&str]) -> Rack {
+	let mut rack = Rack::empty();
+	for (jar, substance) in rack.jars.iter_mut().zip(substances.iter()) {
+	    jar.substance = substance.to_string();
+	}
+	rack
+    }
+
+    fn saved_procedure() -> Procedure {
+	let mut value = h_and_e();
+	value["_id"] = json!("h-and-e");
+	value["_rev"] = json!("1-a");
+	let mut proc : Procedure = serde_json::from_value(value).unwrap();
+	crate::jar_contents::reconcile_procedure(&mut proc);
+	proc
+    }
+
+    #[test]
+    fn the_loaded_rack_has_to_match_the_procedure() {
+	let proc = saved_procedure();
+	assert!(check_rack(&proc, &loaded_rack(&["hematoxylin", "Water", "Eosin"])).issues.is_empty());
+
+	let validation = check_rack(&proc, &loaded_rack(&["Hematoxylin", "Xylene"]));
+	assert!(!validation.valid);
+	assert_eq!(codes(&validation), vec!["rack_mismatch", "rack_jar_unknown"]);
+	assert_eq!(validation.issues[0].step_number, Some(2));
+    }
+
+    #[test]
+    fn racks_list_each_real_jar_once() {
+	let jar = |jar_number: i32| RackJarInputObject::from(RackJar { jar_number, ..Rack::empty().jars[0].clone() });
+	let mut rack = RackInputObject::from(Rack::empty());
+	assert!(validate_rack(&rack).is_ok());
+	rack.jars.push(jar(1));
+	assert!(validate_rack(&rack).is_err());
+	rack.jars = vec![jar(NUMBER_OF_JARS + 1)];
+	assert!(validate_rack(&rack).is_err());
+    }
+
+    fn grouped(groups: serde_json::Value) -> ProcedureInputObject {
+	let mut value = h_and_e();
+	value["step_groups"] = groups;
+	procedure(value)
+    }
+
+    #[test]
+    fn step_groups_are_checked() {
+	let validation = validate_procedure(&grouped(json!([
+	    {"first_step_number": 1, "last_step_number": 2, "repeat": 0},
+	    {"first_step_number": 3, "last_step_number": 4, "repeat": 2},
+	    {"first_step_number": 2, "last_step_number": 3, "repeat": 2},
+	])));
+	assert_eq!(codes(&validation), vec!["group_repeat_not_positive", "groups_overlap", "group_out_of_range"]);
+	assert_eq!(validation.issues[2].step_number, Some(3));
+    }
+
+    #[test]
+    fn group_repeats_and_sequence_length_are_capped() {
+	let validation = validate_procedure(&grouped(json!([{"first_step_number": 2, "last_step_number": 2, "repeat": MAX_GROUP_REPEAT + 1}])));
+	assert_eq!(codes(&validation), vec!["group_repeat_too_large"]);
+
+	// each group is within its cap, but together they make too many steps
+	let validation = validate_procedure(&grouped(json!([
+	    {"first_step_number": 1, "last_step_number": 3, "repeat": 100},
+	    {"first_step_number": 2, "last_step_number": 3, "repeat": 100},
+	])));
+	assert_eq!(codes(&validation), vec!["sequence_too_long"]);
+	assert!(!validation.valid);
+    }
+
+    #[test]
+    fn the_number_of_groups_is_checked_first() {
+	let group = json!({"first_step_number": 1, "last_step_number": 9, "repeat": 2});
+	let groups : Vec<serde_json::Value> = (0..=MAX_STEP_GROUPS).map(|_| group.clone()).collect();
+	let validation = validate_procedure(&grouped(json!(groups)));
+	assert_eq!(codes(&validation), vec!["too_many_step_groups"]);
+    }
+
+    #[test]
+    fn nesting_is_only_checked_once_the_groups_are_well_formed() {
+	// deep enough and long enough to fail both, but the overlap has to be fixed first
+	let mut groups : Vec<serde_json::Value> = (0..=MAX_STEP_GROUP_DEPTH)
+	    .map(|_| json!({"first_step_number": 1, "last_step_number": 2, "repeat": MAX_GROUP_REPEAT}))
+	    .collect();
+	groups.push(json!({"first_step_number": 2, "last_step_number": 3, "repeat": 2}));
+	let validation = validate_procedure(&grouped(json!(groups)));
+	assert!(codes(&validation).iter().all(|code| *code == "groups_overlap"), "{:?}", codes(&validation));
+
+	groups.pop();
+	let validation = validate_procedure(&grouped(json!(groups)));
+	assert_eq!(codes(&validation), vec!["groups_nested_too_deeply"]);
+    }
+
+    #[test]
+    fn step_groups_can_only_nest_so_deep() {
+	let group = json!({"first_step_number": 1, "last_step_number": 3, "repeat": 2});
+	let nested : Vec<serde_json::Value> = (0..MAX_STEP_GROUP_DEPTH).map(|_| group.clone()).collect();
+	assert!(validate_procedure(&grouped(json!(nested))).valid);
+
+	let too_deep : Vec<serde_json::Value> = (0..=MAX_STEP_GROUP_DEPTH).map(|_| group.clone()).collect();
+	let validation = validate_procedure(&grouped(json!(too_deep)));
+	assert_eq!(codes(&validation), vec!["groups_nested_too_deeply"]);
+    }
+
+    #[test]
+    fn operator_steps_need_a_message_but_no_time() {
+	let mut value = h_and_e();
+	value["procedure_steps"][1] = json!({"step_type": "OperatorAction", "time_in_seconds": 0, "jar_number": 5});
+	value["procedure_steps"][2]["step_type"] = json!("OperatorAction");
+	value["procedure_steps"][2]["operator_message"] = json!("Replace jar 3 with fresh eosin");
+	let validation = validate_procedure(&procedure(value));
+	assert_eq!(codes(&validation), vec!["operator_message_missing"]);
+	assert_eq!(validation.issues[0].step_number, Some(2));
+    }
+
+    #[test]
+    fn default_settings_are_valid() {
+	assert!(validate_settings(&Settings::default()).is_ok());
+	let tuned = Settings::default().updated(serde_json::from_value(json!({
+	    "_id": "settings", "_rev": "2-a",
+	    "instrument_name": "Stainer 2", "timezone": "America/Argentina/Buenos_Aires", "x_max_speed": 0.0, "drain_seconds": 600,
+	})).unwrap());
+	assert!(validate_settings(&tuned).is_ok());
+    }
+
+    #[test]
+    fn unsafe_settings_are_refused() {
+	let message = |changes: serde_json::Value| {
+	    let mut value = serde_json::to_value(Settings::default()).unwrap();
+	    value.as_object_mut().unwrap().extend(changes.as_object().unwrap().clone());
+	    validate_settings(&serde_json::from_value(value).unwrap()).unwrap_err().message().to_string()
+	};
+	assert!(message(json!({"instrument_name": " "})).contains("instrument name"));
+	assert!(message(json!({"timezone": "Chicago; rm -rf"})).contains("time zone"));
+	assert!(message(json!({"z_acceleration": MOTION_ACCELERATION * 5.0})).contains("z-axis acceleration"));
+	assert!(message(json!({"x_max_speed": -1.0})).contains("x-axis speed"));
+	assert!(message(json!({"down_position": 3.0, "up_position": 2.0})).contains("down position"));
+	assert!(message(json!({"drain_between_steps_seconds": 601})).contains("Drain times"));
+    }
+}